@@ -0,0 +1,29 @@
+use crate::commands::devices::format_size;
+use crate::image;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub size: u64,
+    pub size_human: String,
+}
+
+#[tauri::command]
+pub async fn list_archive_entries(path: String) -> Result<Vec<ArchiveEntry>, String> {
+    tokio::task::spawn_blocking(move || {
+        let entries = image::list_archive_entries(&path)
+            .map_err(|e| format!("Failed to read archive: {}", e))?;
+
+        Ok(entries
+            .into_iter()
+            .map(|entry| ArchiveEntry {
+                name: entry.name,
+                size: entry.size,
+                size_human: format_size(entry.size),
+            })
+            .collect())
+    })
+    .await
+    .map_err(|e| format!("Task failed: {}", e))?
+}
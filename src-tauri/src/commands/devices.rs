@@ -1,7 +1,14 @@
-use crate::platform;
+use crate::commands::write;
+use crate::inspect::{self, partitions::PartitionTable};
+use crate::platform::{self, DeviceEvent};
 use serde::Serialize;
+use std::sync::Mutex;
+use tauri::{AppHandle, Emitter};
 
-#[derive(Debug, Clone, Serialize)]
+/// Whether the device watcher runs, once `watch_devices` has tried to start it
+static WATCHING: Mutex<Option<bool>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
 pub struct BlockDevice {
     pub path: String,
     pub name: String,
@@ -9,6 +16,31 @@ pub struct BlockDevice {
     pub size_human: String,
     pub removable: bool,
     pub mount_points: Vec<String>,
+    /// Tells apart drives of the same make and model
+    pub serial: Option<String>,
+    /// USB vendor and product IDs, as four hex digits
+    pub vendor_id: Option<String>,
+    pub product_id: Option<String>,
+    /// "usb", "mmc", "ata", "nvme" or "virtio"
+    pub transport: Option<String>,
+    /// A /dev/disk/by-id path, which stays the same across replugging
+    pub by_id_path: Option<String>,
+    pub logical_sector_size: Option<u32>,
+    pub physical_sector_size: Option<u32>,
+    /// Left empty where the platform doesn't report them
+    pub partitions: Vec<DevicePartition>,
+}
+
+/// A partition of a `BlockDevice`, as the operating system sees it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DevicePartition {
+    pub path: String,
+    pub number: u32,
+    pub size: u64,
+    pub size_human: String,
+    pub fs_type: Option<String>,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
 }
 
 pub fn format_size(bytes: u64) -> String {
@@ -36,3 +68,66 @@ pub async fn list_devices() -> Result<Vec<BlockDevice>, String> {
         platform::list_removable_devices().await;
     result.map_err(|e| e.to_string())
 }
+
+/// Starts emitting `device-added`, `device-removed` and `device-changed`
+/// events, each carrying a `BlockDevice`. Returns `false` where the platform
+/// can't watch for devices, so `list_devices` has to be polled. Should the
+/// watcher fail later, `device-watch-stopped` carries the error and polling
+/// has to take over.
+#[tauri::command]
+pub async fn watch_devices(app: AppHandle) -> Result<bool, String> {
+    let mut watching = WATCHING.lock().map_err(|e| e.to_string())?;
+    if let Some(supported) = *watching {
+        return Ok(supported);
+    }
+
+    let supported = platform::watch_devices(Box::new(move |event| {
+        let (name, device) = match event {
+            DeviceEvent::Added(device) => ("device-added", device),
+            DeviceEvent::Removed(device) => ("device-removed", device),
+            DeviceEvent::Changed(device) => ("device-changed", device),
+            DeviceEvent::Stopped(error) => {
+                // Let the next call start a new watcher
+                if let Ok(mut watching) = WATCHING.lock() {
+                    *watching = None;
+                }
+                let _ = app.emit("device-watch-stopped", &error);
+                return;
+            }
+        };
+        let _ = app.emit(name, &device);
+    }))
+    .map_err(|e| format!("Failed to watch devices: {}", e))?;
+    *watching = Some(supported);
+    Ok(supported)
+}
+
+/// Reads the partition table of a removable device, or `None` when it has
+/// neither an MBR nor a GPT.
+#[tauri::command]
+pub async fn read_partition_table(device_path: String) -> Result<Option<PartitionTable>, String> {
+    let device = write::require_removable_device(&device_path).await?;
+
+    let file = platform::open_device_for_read(&device_path)
+        .await
+        .map_err(|e| format!("Failed to open device: {}", e))?
+        .into_std()
+        .await;
+
+    tokio::task::spawn_blocking(move || {
+        let mut disk = inspect::DeviceReader::new(file);
+        let mut table = inspect::partitions::read_table(&mut disk, Some(device.size))
+            .map_err(|e| format!("Failed to read partition table: {}", e))?;
+        // An image written to a smaller device loses its end
+        if let Some(table) = table.as_mut().filter(|table| table.required_size > device.size) {
+            table.errors.push(format!(
+                "The partitions end {} past the end of the device, which only holds {}.",
+                format_size(table.required_size - device.size),
+                format_size(device.size)
+            ));
+        }
+        Ok(table)
+    })
+    .await
+    .map_err(|e| format!("Task failed: {}", e))?
+}
@@ -0,0 +1,218 @@
+use crate::commands::devices::format_size;
+use crate::image;
+use crate::inspect::{
+    self,
+    fat::Fat,
+    fs::{lookup, read_file_at, FileSystem, Node},
+    iso9660::Iso9660,
+    udf::Udf,
+    ImageReader,
+};
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+
+/// Files are copied out in chunks of this size
+const CHUNK_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageFile {
+    pub name: String,
+    /// Full path inside the image, as `list_image_files` and
+    /// `extract_image_file` take it
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub size_human: String,
+}
+
+/// A filesystem of the image and the name of the top-level directory it
+/// shows up as; `None` when it is the image's only one and is the top level.
+type Volume = (Option<String>, Box<dyn FileSystem>);
+
+/// Lists a directory inside an image without mounting it. Optical media show
+/// their UDF or ISO 9660 (Joliet or Rock Ridge names) file system; disk
+/// images a `partitionN` directory for each FAT partition.
+#[tauri::command]
+pub async fn list_image_files(
+    path: String,
+    dir: String,
+    entry: Option<String>,
+) -> Result<Vec<ImageFile>, String> {
+    tokio::task::spawn_blocking(move || {
+        let mut disk = open_disk(&path, entry.as_deref())?;
+        let mut volumes = open_volumes(&mut disk)?;
+        let dir_path = normalize(&dir);
+
+        if dir_path.is_empty() && volumes[0].0.is_some() {
+            return Ok(volumes
+                .iter()
+                .filter_map(|(name, _)| name.clone())
+                .map(|name| ImageFile {
+                    path: name.clone(),
+                    name,
+                    is_dir: true,
+                    size: 0,
+                    size_human: format_size(0),
+                })
+                .collect());
+        }
+
+        let (volume, node) = find(&mut disk, &mut volumes, &dir_path)?;
+        if !node.is_dir {
+            return Err(format!("Not a directory: {}", dir));
+        }
+        let mut nodes = volume
+            .read_dir(&mut disk, &node)
+            .map_err(|e| format!("Failed to read {}: {}", dir, e))?;
+        nodes.sort_by(|a, b| {
+            b.is_dir
+                .cmp(&a.is_dir)
+                .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+        });
+
+        Ok(nodes
+            .into_iter()
+            .map(|node| ImageFile {
+                path: if dir_path.is_empty() {
+                    node.name.clone()
+                } else {
+                    format!("{}/{}", dir_path, node.name)
+                },
+                size_human: format_size(node.size),
+                name: node.name,
+                is_dir: node.is_dir,
+                size: node.size,
+            })
+            .collect())
+    })
+    .await
+    .map_err(|e| format!("Task failed: {}", e))?
+}
+
+/// Copies a file out of an image to `dest`, returning its size.
+#[tauri::command]
+pub async fn extract_image_file(
+    path: String,
+    inner_path: String,
+    dest: String,
+    entry: Option<String>,
+) -> Result<u64, String> {
+    tokio::task::spawn_blocking(move || {
+        let mut disk = open_disk(&path, entry.as_deref())?;
+        let mut volumes = open_volumes(&mut disk)?;
+        let (_, node) = find(&mut disk, &mut volumes, &normalize(&inner_path))?;
+        if node.is_dir {
+            return Err(format!("{} is a directory", inner_path));
+        }
+
+        let mut out =
+            File::create(&dest).map_err(|e| format!("Failed to create {}: {}", dest, e))?;
+        let mut buffer = vec![0u8; CHUNK_LEN];
+        let mut offset = 0u64;
+        while offset < node.size {
+            let n = read_file_at(&mut disk, &node, offset, &mut buffer)
+                .map_err(|e| format!("Failed to read {}: {}", inner_path, e))?;
+            out.write_all(&buffer[..n])
+                .map_err(|e| format!("Failed to write {}: {}", dest, e))?;
+            offset += n as u64;
+        }
+        out.sync_all()
+            .map_err(|e| format!("Failed to write {}: {}", dest, e))?;
+        Ok(node.size)
+    })
+    .await
+    .map_err(|e| format!("Task failed: {}", e))?
+}
+
+fn open_disk(path: &str, entry: Option<&str>) -> Result<ImageReader, String> {
+    let source =
+        image::open_image(path, entry).map_err(|e| format!("Failed to open file: {}", e))?;
+    Ok(ImageReader::new(path, entry, source))
+}
+
+/// Opens the file systems `list_image_files` shows. Windows media are UDF
+/// with a near-empty ISO 9660 bridge, so UDF comes first.
+fn open_volumes(disk: &mut ImageReader) -> Result<Vec<Volume>, String> {
+    let failed = |e: std::io::Error| format!("Failed to read image: {}", e);
+    // A UDF/ISO 9660 bridge image is still readable through its ISO 9660
+    // tree when the UDF side is damaged
+    let mut udf_error = None;
+    if inspect::udf::detect(disk).map_err(failed)?.is_some() {
+        match Udf::open(disk) {
+            Ok(volume) => return Ok(vec![(None, Box::new(volume))]),
+            Err(e) => udf_error = Some(e),
+        }
+    }
+    match Iso9660::open(disk) {
+        Ok(Some(volume)) => return Ok(vec![(None, Box::new(volume))]),
+        Ok(None) => {}
+        Err(e) => return Err(failed(udf_error.unwrap_or(e))),
+    }
+    if let Some(e) = udf_error {
+        return Err(failed(e));
+    }
+
+    // Reaching the backup GPT of a compressed image would decode all of it
+    let disk_size = disk
+        .source()
+        .size
+        .filter(|size| size.exact && disk.source().can_seek())
+        .map(|size| size.bytes);
+    let partitions = inspect::partitions::read_table(disk, disk_size)
+        .map_err(failed)?
+        .map(|table| table.partitions)
+        .unwrap_or_default();
+    let mut volumes: Vec<Volume> = Vec::new();
+    for partition in partitions {
+        if !partition
+            .filesystem
+            .as_ref()
+            .is_some_and(|fs| fs.fs_type.starts_with("FAT"))
+        {
+            continue;
+        }
+        let volume = Fat::open(disk, partition.start)
+            .map_err(|e| format!("Failed to read partition {}: {}", partition.index, e))?;
+        volumes.push((
+            Some(format!("partition{}", partition.index)),
+            Box::new(volume),
+        ));
+    }
+    if volumes.is_empty() {
+        return Err("The image has no ISO 9660, UDF or FAT file system to read".to_string());
+    }
+    Ok(volumes)
+}
+
+/// Finds the node at a normalized path, and the volume it is on.
+fn find<'a>(
+    disk: &mut ImageReader,
+    volumes: &'a mut [Volume],
+    path: &str,
+) -> Result<(&'a mut dyn FileSystem, Node), String> {
+    let (volume, inner) = if volumes[0].0.is_none() {
+        (&mut volumes[0].1, path)
+    } else {
+        let (top, inner) = path.split_once('/').unwrap_or((path, ""));
+        let volume = volumes
+            .iter_mut()
+            .find(|(name, _)| name.as_deref() == Some(top))
+            .map(|(_, volume)| volume)
+            .ok_or_else(|| format!("No such file or directory: {}", path))?;
+        (volume, inner)
+    };
+    let volume = volume.as_mut();
+    let node = lookup(volume, disk, inner)
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?
+        .ok_or_else(|| format!("No such file or directory: {}", path))?;
+    Ok((volume, node))
+}
+
+/// Drops leading, trailing and doubled slashes, and accepts backslashes.
+fn normalize(path: &str) -> String {
+    path.split(['/', '\\'])
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
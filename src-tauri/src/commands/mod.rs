@@ -1,3 +1,5 @@
+pub mod archive;
 pub mod devices;
+pub mod files;
 pub mod verify;
 pub mod write;
@@ -1,10 +1,27 @@
 use crate::commands::devices::format_size;
+use crate::commands::write::{self, ProgressReporter};
+use crate::image::{self, ImageError};
+use crate::inspect::{
+    self,
+    arch::Platforms,
+    efi::EfiBootloader,
+    fat::Fat,
+    fs::FileSystem,
+    iso9660::{Iso9660, IsoInfo},
+    isomd5::Md5Check,
+    os::OsInfo,
+    partitions::PartitionTable,
+    sums::SumsCheck,
+    udf::{Udf, UdfInfo},
+    ReadAt,
+};
+use crate::platform;
 use md5::{Digest, Md5};
 use serde::Serialize;
 use sha2::Sha256;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::Read;
 use std::path::Path;
+use tauri::AppHandle;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct FileInfo {
@@ -12,12 +29,40 @@ pub struct FileInfo {
     pub name: String,
     pub size: u64,
     pub size_human: String,
+    /// Number of files a split image is spread over, 1 otherwise
+    pub parts: usize,
+    /// Decoded size for compressed and archived images, same as `size` otherwise
+    pub image_size: Option<u64>,
+    pub image_size_human: Option<String>,
+    /// False when `image_size` is only a lower bound
+    pub image_size_exact: bool,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct ImageValidation {
     pub is_valid: bool,
     pub format: String,
+    pub compression: Option<String>,
+    pub entry: Option<String>,
+    pub disk_format: Option<String>,
+    pub image_size: Option<u64>,
+    pub image_size_exact: bool,
+    /// Block map found next to the image
+    pub bmap: Option<String>,
+    pub mapped_size: Option<u64>,
+    pub mapped_size_human: Option<String>,
+    /// Volume details of ISO 9660 images
+    pub iso: Option<IsoInfo>,
+    /// Set when the image carries a UDF volume
+    pub udf: Option<UdfInfo>,
+    /// MBR or GPT partitions, also found in hybrid ISOs
+    pub partitions: Option<PartitionTable>,
+    /// Operating system named by the release files in the image
+    pub os: Option<OsInfo>,
+    /// UEFI bootloaders for removable media, with their Secure Boot signatures
+    pub bootloaders: Vec<EfiBootloader>,
+    /// CPU architectures and boards the image boots on
+    pub platforms: Platforms,
     pub errors: Vec<String>,
     pub warnings: Vec<String>,
 }
@@ -29,20 +74,30 @@ pub async fn get_file_info(path: String) -> Result<FileInfo, String> {
     tokio::task::spawn_blocking(move || {
         let path_ref = Path::new(&path_clone);
 
-        let metadata = std::fs::metadata(&path_clone)
+        // A split image counts as the size of all its parts
+        let file = image::open_file(&path_clone)
             .map_err(|e| format!("Failed to read file: {}", e))?;
 
-        let size = metadata.len();
+        let size = file.size();
         let name = path_ref
             .file_name()
             .map(|n| n.to_string_lossy().to_string())
             .unwrap_or_else(|| path_clone.clone());
 
+        // Archives holding several images have no single image size
+        let image_size = image::open_image(&path_clone, None)
+            .ok()
+            .and_then(|source| source.size);
+
         Ok(FileInfo {
             path: path_clone,
             name,
             size,
             size_human: format_size(size),
+            parts: file.part_count(),
+            image_size: image_size.map(|s| s.bytes),
+            image_size_human: image_size.map(|s| format_size(s.bytes)),
+            image_size_exact: image_size.map(|s| s.exact).unwrap_or(false),
         })
     })
     .await
@@ -53,7 +108,7 @@ pub async fn get_file_info(path: String) -> Result<FileInfo, String> {
 pub async fn calculate_checksum(path: String, algorithm: String) -> Result<String, String> {
     // Run CPU-intensive hashing in a blocking thread to avoid blocking the async runtime
     tokio::task::spawn_blocking(move || {
-        let mut file = File::open(&path)
+        let mut file = image::open_file(&path)
             .map_err(|e| format!("Failed to open file: {}", e))?;
 
         const BUFFER_SIZE: usize = 8 * 1024 * 1024; // 8 MB buffer for better throughput
@@ -95,29 +150,194 @@ pub async fn calculate_checksum(path: String, algorithm: String) -> Result<Strin
     .map_err(|e| format!("Task failed: {}", e))?
 }
 
+/// Checks an image, or the drive it was written to, against the MD5 that
+/// implantisomd5 embedded in it, as checkisomd5 does. Only the part of the
+/// drive the ISO takes up is read. Returns `None` when the image carries no
+/// such checksum.
+#[tauri::command]
+pub async fn check_iso_md5(
+    path: String,
+    entry: Option<String>,
+    device_path: Option<String>,
+    app: AppHandle,
+) -> Result<Option<Md5Check>, String> {
+    let device = match &device_path {
+        Some(device_path) => {
+            write::require_removable_device(device_path).await?;
+            let file = platform::open_device_for_read(device_path)
+                .await
+                .map_err(|e| format!("Failed to open device: {}", e))?
+                .into_std()
+                .await;
+            Some(file)
+        }
+        None => None,
+    };
+
+    tokio::task::spawn_blocking(move || {
+        // The checksum is taken from the image even when checking the drive
+        let source = image::open_image(&path, entry.as_deref())
+            .map_err(|e| format!("Failed to open file: {}", e))?;
+        let mut disk = inspect::ImageReader::new(&path, entry.as_deref(), source);
+        let implanted = match inspect::isomd5::read(&mut disk)
+            .map_err(|e| format!("Failed to read image: {}", e))?
+        {
+            Some(implanted) => implanted,
+            None => return Ok(None),
+        };
+
+        let mut data: Box<dyn Read> = match device {
+            Some(file) => Box::new(file),
+            None => Box::new(
+                image::open_image(&path, entry.as_deref())
+                    .map_err(|e| format!("Failed to open file: {}", e))?,
+            ),
+        };
+        let mut progress = ProgressReporter::new(&app, "checking-md5", implanted.length);
+        let mut reported = 0;
+        inspect::isomd5::check(data.as_mut(), &implanted, |done| {
+            progress.advance(done - reported);
+            reported = done;
+        })
+        .map(Some)
+        .map_err(|e| format!("Failed to check the embedded MD5: {}", e))
+    })
+    .await
+    .map_err(|e| format!("Task failed: {}", e))?
+}
+
+/// Hashes every file the checksum list on an Ubuntu or Debian disc
+/// (sha256sum.txt or md5sum.txt) names, in the image or on the drive it was
+/// written to, and reports the files that are missing or damaged. Returns
+/// `None` when there is no checksum list.
+#[tauri::command]
+pub async fn verify_image_files(
+    path: String,
+    entry: Option<String>,
+    device_path: Option<String>,
+    app: AppHandle,
+) -> Result<Option<SumsCheck>, String> {
+    let device = match &device_path {
+        Some(device_path) => {
+            write::require_removable_device(device_path).await?;
+            let file = platform::open_device_for_read(device_path)
+                .await
+                .map_err(|e| format!("Failed to open device: {}", e))?
+                .into_std()
+                .await;
+            Some(file)
+        }
+        None => None,
+    };
+
+    tokio::task::spawn_blocking(move || {
+        let mut disk: Box<dyn ReadAt> = match device {
+            Some(file) => Box::new(inspect::DeviceReader::new(file)),
+            None => {
+                let source = image::open_image(&path, entry.as_deref())
+                    .map_err(|e| format!("Failed to open file: {}", e))?;
+                Box::new(inspect::ImageReader::new(&path, entry.as_deref(), source))
+            }
+        };
+        let mut volume = match Iso9660::open(disk.as_mut())
+            .map_err(|e| format!("Failed to read the ISO 9660 file system: {}", e))?
+        {
+            Some(volume) => volume,
+            None => return Err("No ISO 9660 file system found".to_string()),
+        };
+
+        let mut progress: Option<ProgressReporter> = None;
+        let mut reported = 0;
+        inspect::sums::check(&mut volume, disk.as_mut(), |done, total| {
+            progress
+                .get_or_insert_with(|| ProgressReporter::new(&app, "checking-files", total))
+                .advance(done - reported);
+            reported = done;
+        })
+        .map_err(|e| format!("Failed to check the files: {}", e))
+    })
+    .await
+    .map_err(|e| format!("Task failed: {}", e))?
+}
+
 #[tauri::command]
-pub async fn validate_image(path: String, device_size: Option<u64>) -> Result<ImageValidation, String> {
+pub async fn validate_image(
+    path: String,
+    device_size: Option<u64>,
+    entry: Option<String>,
+) -> Result<ImageValidation, String> {
     tokio::task::spawn_blocking(move || {
         let mut errors: Vec<String> = Vec::new();
         let mut warnings: Vec<String> = Vec::new();
         let mut format = String::from("Unknown");
 
-        let mut file = File::open(&path)
-            .map_err(|e| format!("Failed to open file: {}", e))?;
-
-        let metadata = file.metadata()
-            .map_err(|e| format!("Failed to read file metadata: {}", e))?;
+        // Inspect the decoded image rather than any compression wrapper
+        let mut source = match image::open_image(&path, entry.as_deref()) {
+            Ok(source) => source,
+            // Damaged or unsupported containers are a validation result, not a failure
+            Err(e @ (ImageError::Corrupt(_) | ImageError::Unsupported(_))) => {
+                return Ok(ImageValidation {
+                    is_valid: false,
+                    format,
+                    compression: None,
+                    entry,
+                    disk_format: None,
+                    image_size: None,
+                    image_size_exact: false,
+                    bmap: None,
+                    mapped_size: None,
+                    mapped_size_human: None,
+                    iso: None,
+                    udf: None,
+                    partitions: None,
+                    os: None,
+                    bootloaders: Vec::new(),
+                    platforms: Platforms::default(),
+                    errors: vec![e.to_string()],
+                    warnings,
+                });
+            }
+            Err(e) => return Err(format!("Failed to open file: {}", e)),
+        };
+        warnings.append(&mut source.warnings);
+        let container = source.container.as_deref().unwrap_or("compressed");
+        match source.truncated {
+            Some(true) => errors.push(format!(
+                "The {} file appears truncated: its compressed stream stops before the end. Download the image again.",
+                container
+            )),
+            // gzip's end is only found by decompressing all of it
+            None => warnings.push(format!(
+                "Whether the {} file is complete was not checked: that takes decompressing all of it. A truncated download will fail part way through writing.",
+                container
+            )),
+            Some(false) => {}
+        }
+        let mut disk = inspect::ImageReader::new(&path, entry.as_deref(), source);
 
-        let file_size = metadata.len();
+        // Enough to cover the ISO 9660 Primary Volume Descriptor
+        let mut header = vec![0u8; 32768 + 2048];
+        let header_len = disk
+            .read_at(0, &mut header)
+            .map_err(|e| format!("Failed to read image: {}", e))?;
+        header.truncate(header_len);
+        let source = disk.source();
 
-        // Check if file size exceeds device capacity
+        // Check if image size exceeds device capacity
         if let Some(dev_size) = device_size {
-            if file_size > dev_size {
-                errors.push(format!(
-                    "Image size ({}) exceeds device capacity ({})",
-                    format_size(file_size),
-                    format_size(dev_size)
-                ));
+            match source.size {
+                Some(image_size) if image_size.bytes > dev_size => {
+                    errors.push(format!(
+                        "Image size ({}{}) exceeds device capacity ({})",
+                        if image_size.exact { "" } else { "at least " },
+                        format_size(image_size.bytes),
+                        format_size(dev_size)
+                    ));
+                }
+                Some(_) => {}
+                None => warnings.push(String::from(
+                    "Uncompressed image size is unknown. Device capacity will be checked while writing.",
+                )),
             }
         }
 
@@ -125,57 +345,208 @@ pub async fn validate_image(path: String, device_size: Option<u64>) -> Result<Im
         // Primary Volume Descriptor is at sector 16 (offset 32768)
         // Bytes 0: type (0x01 for primary)
         // Bytes 1-5: "CD001"
-        let mut iso_header = [0u8; 6];
-        if file.seek(SeekFrom::Start(32768)).is_ok() {
-            if file.read_exact(&mut iso_header).is_ok() {
-                if &iso_header[1..6] == b"CD001" {
-                    format = String::from("ISO 9660");
+        let mut iso = None;
+        if header.len() >= 32768 + 6 && &header[32769..32774] == b"CD001" {
+            format = String::from("ISO 9660");
+            match inspect::iso9660::read_info(&mut disk) {
+                Ok(Some(mut info)) => {
+                    warnings.append(&mut info.warnings);
+                    iso = Some(info);
                 }
+                Ok(None) => {}
+                Err(e) => warnings.push(format!("Could not read the ISO 9660 volume details: {}", e)),
             }
         }
 
-        // If not ISO 9660, check for raw disk image signatures
-        if format == "Unknown" {
-            // Check for MBR boot signature at offset 510-511 (0x55, 0xAA)
-            file.seek(SeekFrom::Start(510))
-                .map_err(|e| format!("Failed to seek: {}", e))?;
-            let mut mbr_sig = [0u8; 2];
-            if file.read_exact(&mut mbr_sig).is_ok() {
-                if mbr_sig == [0x55, 0xAA] {
-                    format = String::from("Disk Image (MBR)");
+        // Windows media are UDF with a minimal ISO 9660 bridge; other UDF
+        // images may have no ISO 9660 volume at all
+        let mut udf = None;
+        let mut udf_volume = None;
+        match inspect::udf::detect(&mut disk) {
+            Ok(Some(revision)) => {
+                let mut info = UdfInfo {
+                    revision,
+                    volume_label: None,
+                };
+                match inspect::udf::Udf::open(&mut disk) {
+                    Ok(mut volume) => {
+                        info.volume_label = Some(volume.volume_label.clone());
+                        match inspect::os::is_windows_installer(&mut volume, &mut disk) {
+                            Ok(true) => format = String::from("Windows Installer (UDF)"),
+                            Ok(false) if format == "Unknown" => format = String::from("UDF"),
+                            Ok(false) => {}
+                            Err(e) => warnings.push(format!("Could not read the UDF file system: {}", e)),
+                        }
+                        udf_volume = Some(volume);
+                    }
+                    Err(e) => warnings.push(format!("Could not read the UDF file system: {}", e)),
                 }
+                udf = Some(info);
             }
+            Ok(None) => {}
+            Err(e) => warnings.push(format!("Could not look for a UDF volume: {}", e)),
         }
 
-        // Check for GPT
-        if format == "Unknown" || format == "Disk Image (MBR)" {
-            // GPT header is at LBA 1 (offset 512)
-            // Signature is "EFI PART" at offset 512
-            file.seek(SeekFrom::Start(512))
-                .map_err(|e| format!("Failed to seek: {}", e))?;
-            let mut gpt_sig = [0u8; 8];
-            if file.read_exact(&mut gpt_sig).is_ok() {
-                if &gpt_sig == b"EFI PART" {
-                    format = String::from("Disk Image (GPT)");
+        if format == "Windows Installer (UDF)" {
+            warnings.push(String::from(
+                "This is Windows installation media. Written to a USB drive as-is it will not boot: Windows ISOs only boot from DVD. Use a tool that builds a Windows installer drive, such as Microsoft's Media Creation Tool.",
+            ));
+        } else if let Some(info) = iso.as_ref().filter(|info| !info.is_hybrid()) {
+            // Without a partition table in the system area, firmware won't
+            // boot the ISO from a USB drive
+            warnings.push(String::from(if info.boot_entries.is_empty() {
+                "This ISO has no boot record and is not a hybrid image. Written to a USB drive it will not boot."
+            } else {
+                "This ISO only boots from CD/DVD: it has no isohybrid MBR or GPT, so written to a USB drive as-is it will not boot."
+            }));
+        }
+
+        // Raw disk images, and the MBR or GPT of a hybrid ISO. The backup GPT
+        // can only be checked when the decoded size is known exactly, and is
+        // left alone where reaching it means decompressing the whole image
+        let mut partitions = None;
+        let disk_size = disk
+            .source()
+            .size
+            .filter(|size| size.exact)
+            .map(|size| size.bytes);
+        let table_size = disk_size.filter(|_| disk.source().can_seek());
+        match inspect::partitions::read_table(&mut disk, table_size) {
+            Ok(Some(mut table)) => {
+                errors.append(&mut table.errors);
+                warnings.append(&mut table.warnings);
+                if format == "Unknown" {
+                    format = format!("Disk Image ({})", table.scheme);
                 }
+                partitions = Some(table);
+            }
+            // A superfloppy: one FAT file system filling the whole image
+            Ok(None)
+                if format == "Unknown"
+                    && header.len() >= 512
+                    && inspect::probe::is_fat(&header[..512]) =>
+            {
+                format = String::from("FAT (no partition table)");
+            }
+            Ok(None) => {}
+            Err(e) => warnings.push(format!("Could not read the partition table: {}", e)),
+        }
+
+        // Partial downloads pass every check above that only reads the
+        // start, so make sure the image holds all its structures describe
+        if let Some(size) = disk_size {
+            let iso_end = iso.as_ref().map(|info| {
+                ("ISO 9660 volume", info.volume_space_size as u64 * info.logical_block_size as u64)
+            });
+            let table_end = partitions.as_ref().map(|table| ("partition table", table.required_size));
+            if let Some((what, end)) = iso_end
+                .into_iter()
+                .chain(table_end)
+                .filter(|&(_, end)| end > size)
+                .max_by_key(|&(_, end)| end)
+            {
+                errors.push(format!(
+                    "The image appears truncated by {}: its {} needs {}, but the image is only {}. Download it again.",
+                    format_size(end - size),
+                    what,
+                    format_size(end),
+                    format_size(size)
+                ));
+            }
+        }
+
+        let mut volumes = open_volumes(
+            &mut disk,
+            udf_volume,
+            iso.as_ref(),
+            partitions.as_ref(),
+            &mut warnings,
+        );
+        let os = identify_os(&mut disk, &mut volumes)
+            .unwrap_or_else(|e| {
+                warnings.push(format!("Could not identify the operating system: {}", e));
+                None
+            });
+
+        let bootloaders = inspect::efi::find_bootloaders(&mut disk, &mut volumes)
+            .unwrap_or_else(|e| {
+                warnings.push(format!("Could not read the UEFI bootloaders: {}", e));
+                Vec::new()
+            });
+        for bootloader in &bootloaders {
+            if !bootloader.signed {
+                warnings.push(format!(
+                    "The UEFI bootloader {} is unsigned, so it will not start while Secure Boot is on. Turn Secure Boot off in the firmware settings to boot this image.",
+                    bootloader.path
+                ));
+            } else if !bootloader.microsoft_signed() {
+                warnings.push(format!(
+                    "The UEFI bootloader {} is signed by {} rather than Microsoft. With Secure Boot on it only starts if that key has been enrolled in the firmware.",
+                    bootloader.path,
+                    bootloader.signer.as_deref().unwrap_or("an unknown key")
+                ));
             }
         }
 
+        let platforms = inspect::arch::detect(
+            &mut disk,
+            &mut volumes,
+            iso.as_ref()
+                .map(|info| info.boot_entries.as_slice())
+                .unwrap_or_default(),
+            partitions.as_ref(),
+            &bootloaders,
+            os.as_ref(),
+        )
+        .unwrap_or_else(|e| {
+            warnings.push(format!("Could not look for a Raspberry Pi boot partition: {}", e));
+            Platforms::default()
+        });
+        if !platforms.raspberry_pi.is_empty() {
+            warnings.push(format!(
+                "This is a Raspberry Pi image for the {}. It will not boot on a PC.",
+                platforms.raspberry_pi.join(", ")
+            ));
+        } else if !platforms.architectures.is_empty() && !platforms.boots_on_pc() {
+            warnings.push(format!(
+                "This image is built for {} machines only. It will not boot on an ordinary x86 PC.",
+                platforms.architectures.join(", ")
+            ));
+        }
+
         // Warn if format is unknown
         if format == "Unknown" {
             warnings.push(String::from("Could not detect image format. File may not be a valid disk image."));
         }
 
         // Check minimum file size (at least 512 bytes for a boot sector)
-        if file_size < 512 {
+        if header_len < 512 {
             errors.push(String::from("File is too small to be a valid disk image."));
         }
 
         let is_valid = errors.is_empty();
+        let source = disk.source();
 
         Ok(ImageValidation {
             is_valid,
             format,
+            compression: source.container.clone(),
+            entry: source.entry.clone(),
+            disk_format: source.disk_format.clone(),
+            image_size: source.size.map(|s| s.bytes),
+            image_size_exact: source.size.map(|s| s.exact).unwrap_or(false),
+            bmap: source
+                .bmap_path
+                .as_ref()
+                .map(|p| p.to_string_lossy().to_string()),
+            mapped_size: source.mapped_bytes,
+            mapped_size_human: source.mapped_bytes.map(format_size),
+            iso,
+            udf,
+            partitions,
+            os,
+            bootloaders,
+            platforms,
             errors,
             warnings,
         })
@@ -183,3 +554,71 @@ pub async fn validate_image(path: String, device_size: Option<u64>) -> Result<Im
     .await
     .map_err(|e| format!("Task failed: {}", e))?
 }
+
+/// Opens the filesystems release files and bootloaders are looked for in:
+/// the UDF volume Windows media keep them in, the ISO 9660 volume of other
+/// optical media, FAT partitions, then the FAT images El Torito boots UEFI
+/// from.
+fn open_volumes(
+    disk: &mut inspect::ImageReader,
+    udf_volume: Option<Udf>,
+    iso: Option<&IsoInfo>,
+    partitions: Option<&PartitionTable>,
+    warnings: &mut Vec<String>,
+) -> Vec<Box<dyn FileSystem>> {
+    let mut volumes: Vec<Box<dyn FileSystem>> = Vec::new();
+    if let Some(volume) = udf_volume {
+        volumes.push(Box::new(volume));
+    }
+    if iso.is_some() {
+        match Iso9660::open(disk) {
+            Ok(Some(volume)) => volumes.push(Box::new(volume)),
+            Ok(None) => {}
+            Err(e) => warnings.push(format!("Could not read the ISO 9660 file system: {}", e)),
+        }
+    }
+
+    let fat_partitions = partitions
+        .map(|table| table.partitions.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .filter(|partition| {
+            partition
+                .filesystem
+                .as_ref()
+                .is_some_and(|fs| fs.fs_type.starts_with("FAT"))
+        })
+        .map(|partition| partition.start);
+    // A hybrid ISO's EFI partition is usually its El Torito image as well
+    let uefi_images = iso
+        .map(|info| info.boot_entries.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .filter(|entry| entry.platform == "UEFI")
+        .map(|entry| entry.load_rba as u64 * inspect::iso9660::SECTOR);
+    let mut opened = Vec::new();
+    for start in fat_partitions.chain(uefi_images) {
+        if opened.contains(&start) {
+            continue;
+        }
+        opened.push(start);
+        match Fat::open(disk, start) {
+            Ok(volume) => volumes.push(Box::new(volume)),
+            Err(e) => warnings.push(format!("Could not read the FAT file system at offset {}: {}", start, e)),
+        }
+    }
+    volumes
+}
+
+/// Returns the OS named by the first volume with release files.
+fn identify_os(
+    disk: &mut inspect::ImageReader,
+    volumes: &mut [Box<dyn FileSystem>],
+) -> std::io::Result<Option<OsInfo>> {
+    for volume in volumes {
+        if let Some(os) = inspect::os::identify(volume.as_mut(), disk)? {
+            return Ok(Some(os));
+        }
+    }
+    Ok(None)
+}
@@ -1,10 +1,12 @@
 use crate::commands::devices::{format_size, BlockDevice};
+use crate::image::{self, ImageSource};
+use crate::inspect;
 use crate::platform;
 use serde::Serialize;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::time::Instant;
 use tauri::{AppHandle, Emitter};
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct WriteProgressEvent {
@@ -17,58 +19,158 @@ pub struct WriteProgressEvent {
 
 const BLOCK_SIZE: usize = 4 * 1024 * 1024; // 4 MB blocks
 
+/// Whether the image is an ISO without an isohybrid MBR or GPT, which
+/// firmware will not boot from a USB drive.
+fn non_hybrid_iso(path: &str, entry: Option<&str>) -> Result<bool, String> {
+    let source =
+        image::open_image(path, entry).map_err(|e| format!("Failed to open image: {}", e))?;
+    let mut disk = inspect::ImageReader::new(path, entry, source);
+    // Disk images, and ISOs too damaged to tell, are checked elsewhere
+    Ok(matches!(inspect::iso9660::read_info(&mut disk), Ok(Some(info)) if !info.is_hybrid()))
+}
+
+#[tauri::command]
+pub async fn is_non_hybrid_iso(path: String, entry: Option<String>) -> Result<bool, String> {
+    tokio::task::spawn_blocking(move || non_hybrid_iso(&path, entry.as_deref()))
+        .await
+        .map_err(|e| format!("Task failed: {}", e))?
+}
+
 #[tauri::command]
 pub async fn write_iso_to_device(
     iso_path: String,
     device_path: String,
     verify: bool,
+    entry: Option<String>,
+    allow_non_hybrid: bool,
     app: AppHandle,
 ) -> Result<(), String> {
     let device = require_removable_device(&device_path).await?;
 
+    // Writing an image that cannot boot from USB takes the user's say-so
+    if !allow_non_hybrid && is_non_hybrid_iso(iso_path.clone(), entry.clone()).await? {
+        return Err(
+            "This ISO is not a hybrid image, so it will not boot from a USB drive. Confirm writing it anyway to continue."
+                .to_string(),
+        );
+    }
+
     // Unmount the device first
     platform::unmount_device(&device_path)
         .await
         .map_err(|e| format!("Failed to unmount device: {}", e))?;
 
-    // Get file size
     let metadata = tokio::fs::metadata(&iso_path)
         .await
         .map_err(|e| format!("Failed to read ISO file: {}", e))?;
     if !metadata.is_file() {
         return Err("Selected image is not a file".to_string());
     }
-    let total_bytes = metadata.len();
-    if total_bytes == 0 {
+
+    // Open source image, decompressing on the fly if needed
+    let source = image::open_image(&iso_path, entry.as_deref())
+        .map_err(|e| format!("Failed to open image: {}", e))?;
+    if source.file_size == 0 {
         return Err("Selected image is empty".to_string());
     }
-    if device.size > 0 && total_bytes > device.size {
+
+    // Use the decoded size where the format records it; bzip2 only reveals
+    // it once fully decoded, and gzip only gives a lower bound
+    let image_size = source.size.map(|s| s.bytes).unwrap_or(0);
+    if device.size > 0 && image_size > device.size {
         return Err(format!(
             "Image size ({}) exceeds device capacity ({})",
-            format_size(total_bytes),
+            format_size(image_size),
             format_size(device.size)
         ));
     }
-
-    // Open source file
-    let mut source = File::open(&iso_path)
-        .await
-        .map_err(|e| format!("Failed to open ISO file: {}", e))?;
+    // Holes in block-mapped and sparse images are skipped, not written
+    let total_bytes = source.mapped_bytes.unwrap_or(image_size);
 
     // Open device for writing
-    let mut device = platform::open_device_for_write(&device_path)
+    let target = platform::open_device_for_write(&device_path)
         .await
-        .map_err(|e| format!("Failed to open device: {}", e))?;
+        .map_err(|e| format!("Failed to open device: {}", e))?
+        .into_std()
+        .await;
 
     // Write phase
+    let write_app = app.clone();
+    let bytes_written = tokio::task::spawn_blocking(move || {
+        write_image(source, target, total_bytes, &write_app)
+    })
+    .await
+    .map_err(|e| format!("Task failed: {}", e))??;
+
+    // Verification phase
+    if verify {
+        // Reopen source and device for verification
+        let source = image::open_image(&iso_path, entry.as_deref())
+            .map_err(|e| format!("Failed to open ISO for verification: {}", e))?;
+
+        let target = platform::open_device_for_read(&device_path)
+            .await
+            .map_err(|e| format!("Failed to open device for verification: {}", e))?
+            .into_std()
+            .await;
+
+        tokio::task::spawn_blocking(move || verify_image(source, target, bytes_written, &app))
+            .await
+            .map_err(|e| format!("Task failed: {}", e))??;
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn eject_device(device_path: String) -> Result<(), String> {
+    let _ = require_removable_device(&device_path).await?;
+    platform::eject_device(&device_path)
+        .await
+        .map_err(|e| format!("Failed to eject device: {}", e))
+}
+
+pub(crate) async fn require_removable_device(device_path: &str) -> Result<BlockDevice, String> {
+    let devices = platform::list_removable_devices()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    devices
+        .into_iter()
+        .find(|device| device.path == device_path)
+        .ok_or_else(|| format!("Device not found or not removable: {}", device_path))
+}
+
+/// Streams the decoded image onto the device and returns the number of bytes
+/// written. Holes in the image are skipped over on the device.
+fn write_image(
+    mut source: ImageSource,
+    mut device: File,
+    total_bytes: u64,
+    app: &AppHandle,
+) -> Result<u64, String> {
     let mut buffer = vec![0u8; BLOCK_SIZE];
-    let mut bytes_written: u64 = 0;
-    let start_time = Instant::now();
+    let mut progress = ProgressReporter::new(app, "writing", total_bytes);
+    // Without a mapped size up front, skipped holes count towards progress
+    let count_holes = source.mapped_bytes.is_none();
 
     loop {
+        let hole = source
+            .skip_hole()
+            .map_err(|e| format!("Failed to read ISO: {}", e))?;
+        if hole > 0 {
+            device
+                .seek(SeekFrom::Current(hole as i64))
+                .map_err(|e| format!("Failed to seek on device: {}", e))?;
+            if count_holes {
+                progress.advance(hole);
+            }
+            continue;
+        }
+
+        // Decoders hand out small chunks, so fill a whole block before writing
         let bytes_read = source
-            .read(&mut buffer)
-            .await
+            .read_data(&mut buffer)
             .map_err(|e| format!("Failed to read ISO: {}", e))?;
 
         if bytes_read == 0 {
@@ -77,125 +179,108 @@ pub async fn write_iso_to_device(
 
         device
             .write_all(&buffer[..bytes_read])
-            .await
             .map_err(|e| format!("Failed to write to device: {}", e))?;
 
-        bytes_written += bytes_read as u64;
-
-        // Calculate progress
-        let elapsed = start_time.elapsed().as_secs_f64();
-        let speed_bps = if elapsed > 0.0 {
-            (bytes_written as f64 / elapsed) as u64
-        } else {
-            0
-        };
-        let remaining_bytes = total_bytes.saturating_sub(bytes_written);
-        let eta_seconds = if speed_bps > 0 {
-            remaining_bytes / speed_bps
-        } else {
-            0
-        };
-
-        let progress = WriteProgressEvent {
-            phase: "writing".to_string(),
-            bytes_written,
-            total_bytes,
-            speed_bps,
-            eta_seconds,
-        };
-
-        let _ = app.emit("write-progress", &progress);
+        progress.advance(bytes_read as u64);
     }
 
     // Sync to ensure all data is written
     device
         .sync_all()
-        .await
         .map_err(|e| format!("Failed to sync device: {}", e))?;
 
-    drop(device);
-
-    // Verification phase
-    if verify {
-        // Reopen source and device for verification
-        let mut source = File::open(&iso_path)
-            .await
-            .map_err(|e| format!("Failed to open ISO for verification: {}", e))?;
-
-        let mut device = platform::open_device_for_read(&device_path)
-            .await
-            .map_err(|e| format!("Failed to open device for verification: {}", e))?;
-
-        let mut source_buffer = vec![0u8; BLOCK_SIZE];
-        let mut device_buffer = vec![0u8; BLOCK_SIZE];
-        let mut bytes_verified: u64 = 0;
-        let verify_start = Instant::now();
+    Ok(progress.bytes_done)
+}
 
-        loop {
-            let source_read = source
-                .read(&mut source_buffer)
-                .await
-                .map_err(|e| format!("Failed to read ISO during verification: {}", e))?;
+/// Reads the device back and compares it against a fresh decode of the
+/// image, leaving out any holes.
+fn verify_image(
+    mut source: ImageSource,
+    mut device: File,
+    total_bytes: u64,
+    app: &AppHandle,
+) -> Result<(), String> {
+    let mut source_buffer = vec![0u8; BLOCK_SIZE];
+    let mut device_buffer = vec![0u8; BLOCK_SIZE];
+    let mut progress = ProgressReporter::new(app, "verifying", total_bytes);
+    let count_holes = source.mapped_bytes.is_none();
 
-            if source_read == 0 {
-                break;
+    loop {
+        let hole = source
+            .skip_hole()
+            .map_err(|e| format!("Failed to read ISO during verification: {}", e))?;
+        if hole > 0 {
+            device
+                .seek(SeekFrom::Current(hole as i64))
+                .map_err(|e| format!("Failed to seek on device during verification: {}", e))?;
+            if count_holes {
+                progress.advance(hole);
             }
+            continue;
+        }
 
-            device
-                .read_exact(&mut device_buffer[..source_read])
-                .await
-                .map_err(|e| format!("Failed to read device during verification: {}", e))?;
+        let source_read = source
+            .read_data(&mut source_buffer)
+            .map_err(|e| format!("Failed to read ISO during verification: {}", e))?;
 
-            if source_buffer[..source_read] != device_buffer[..source_read] {
-                return Err("Verification failed: data mismatch detected".to_string());
-            }
+        if source_read == 0 {
+            break;
+        }
 
-            bytes_verified += source_read as u64;
-
-            // Calculate progress
-            let elapsed = verify_start.elapsed().as_secs_f64();
-            let speed_bps = if elapsed > 0.0 {
-                (bytes_verified as f64 / elapsed) as u64
-            } else {
-                0
-            };
-            let remaining_bytes = total_bytes.saturating_sub(bytes_verified);
-            let eta_seconds = if speed_bps > 0 {
-                remaining_bytes / speed_bps
-            } else {
-                0
-            };
-
-            let progress = WriteProgressEvent {
-                phase: "verifying".to_string(),
-                bytes_written: bytes_verified,
-                total_bytes,
-                speed_bps,
-                eta_seconds,
-            };
-
-            let _ = app.emit("write-progress", &progress);
+        device
+            .read_exact(&mut device_buffer[..source_read])
+            .map_err(|e| format!("Failed to read device during verification: {}", e))?;
+
+        if source_buffer[..source_read] != device_buffer[..source_read] {
+            return Err("Verification failed: data mismatch detected".to_string());
         }
+
+        progress.advance(source_read as u64);
     }
 
     Ok(())
 }
 
-#[tauri::command]
-pub async fn eject_device(device_path: String) -> Result<(), String> {
-    let _ = require_removable_device(&device_path).await?;
-    platform::eject_device(&device_path)
-        .await
-        .map_err(|e| format!("Failed to eject device: {}", e))
+pub(crate) struct ProgressReporter<'a> {
+    app: &'a AppHandle,
+    phase: &'static str,
+    total_bytes: u64,
+    bytes_done: u64,
+    start_time: Instant,
 }
 
-async fn require_removable_device(device_path: &str) -> Result<BlockDevice, String> {
-    let devices = platform::list_removable_devices()
-        .await
-        .map_err(|e| e.to_string())?;
+impl<'a> ProgressReporter<'a> {
+    pub(crate) fn new(app: &'a AppHandle, phase: &'static str, total_bytes: u64) -> Self {
+        Self {
+            app,
+            phase,
+            total_bytes,
+            bytes_done: 0,
+            start_time: Instant::now(),
+        }
+    }
 
-    devices
-        .into_iter()
-        .find(|device| device.path == device_path)
-        .ok_or_else(|| format!("Device not found or not removable: {}", device_path))
+    pub(crate) fn advance(&mut self, bytes: u64) {
+        self.bytes_done += bytes;
+
+        // Calculate progress
+        let elapsed = self.start_time.elapsed().as_secs_f64();
+        let speed_bps = if elapsed > 0.0 {
+            (self.bytes_done as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let remaining_bytes = self.total_bytes.saturating_sub(self.bytes_done);
+        let eta_seconds = remaining_bytes.checked_div(speed_bps).unwrap_or(0);
+
+        let progress = WriteProgressEvent {
+            phase: self.phase.to_string(),
+            bytes_written: self.bytes_done,
+            total_bytes: self.total_bytes,
+            speed_bps,
+            eta_seconds,
+        };
+
+        let _ = self.app.emit("write-progress", &progress);
+    }
 }
@@ -0,0 +1,297 @@
+use super::{ImageError, ImageRead};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Block map produced by bmaptool: which blocks of the image hold data.
+#[derive(Debug, Clone)]
+pub struct Bmap {
+    pub image_size: u64,
+    pub block_size: u64,
+    ranges: Vec<BmapRange>,
+}
+
+#[derive(Debug, Clone)]
+struct BmapRange {
+    first_block: u64,
+    last_block: u64,
+    /// Only SHA-256 range checksums are checked
+    sha256: Option<String>,
+}
+
+impl Bmap {
+    pub fn mapped_bytes(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|r| self.range_end(r) - self.range_start(r))
+            .sum()
+    }
+
+    fn range_start(&self, range: &BmapRange) -> u64 {
+        (range.first_block * self.block_size).min(self.image_size)
+    }
+
+    fn range_end(&self, range: &BmapRange) -> u64 {
+        ((range.last_block + 1) * self.block_size).min(self.image_size)
+    }
+}
+
+/// Looks for `<image>.bmap`, and for compressed images also the name with
+/// the compression suffix dropped (`foo.img.xz` -> `foo.img.bmap`).
+pub fn find(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    let mut candidates = vec![PathBuf::from(format!("{}.bmap", path.display()))];
+    if let (Some(stem), Some(parent)) = (path.file_stem(), path.parent()) {
+        candidates.push(parent.join(format!("{}.bmap", stem.to_string_lossy())));
+    }
+    candidates.into_iter().find(|candidate| candidate.is_file())
+}
+
+pub fn load(path: &Path) -> Result<Bmap, ImageError> {
+    let text = std::fs::read_to_string(path)?;
+    parse(&text)
+}
+
+fn parse(text: &str) -> Result<Bmap, ImageError> {
+    let mut reader = Reader::from_str(text);
+    reader.config_mut().trim_text(true);
+
+    let mut image_size = None;
+    let mut block_size = None;
+    let mut checksum_type = String::from("sha1");
+    let mut file_checksum = None;
+    let mut ranges = Vec::new();
+
+    let mut element = Vec::new();
+    let mut range_checksum = None;
+    loop {
+        match reader.read_event().map_err(|e| corrupt(&e.to_string()))? {
+            Event::Start(start) => {
+                element = start.local_name().as_ref().to_vec();
+                if element == b"Range" {
+                    // bmap 1.4+ uses "chksum"; the "sha1" attribute of older
+                    // versions is not checked
+                    range_checksum = start
+                        .try_get_attribute("chksum")
+                        .ok()
+                        .flatten()
+                        .and_then(|a| a.unescape_value().ok())
+                        .map(|v| v.trim().to_lowercase());
+                }
+            }
+            Event::Text(value) => {
+                let value = value.unescape().map_err(|e| corrupt(&e.to_string()))?;
+                let value = value.trim();
+                match element.as_slice() {
+                    b"ImageSize" => image_size = Some(parse_number(value)?),
+                    b"BlockSize" => block_size = Some(parse_number(value)?),
+                    b"ChecksumType" => checksum_type = value.to_lowercase(),
+                    b"BmapFileChecksum" | b"BmapFileSHA1" => {
+                        file_checksum = Some(value.to_string())
+                    }
+                    b"Range" => {
+                        let (first, last) = match value.split_once('-') {
+                            Some((first, last)) => (parse_number(first)?, parse_number(last)?),
+                            None => (parse_number(value)?, parse_number(value)?),
+                        };
+                        if last < first {
+                            return Err(corrupt("range ends before it starts"));
+                        }
+                        ranges.push(BmapRange {
+                            first_block: first,
+                            last_block: last,
+                            sha256: range_checksum.take(),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(_) => element.clear(),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    let image_size = image_size.ok_or_else(|| corrupt("missing ImageSize"))?;
+    let block_size = block_size
+        .filter(|&size| size > 0)
+        .ok_or_else(|| corrupt("missing BlockSize"))?;
+
+    if checksum_type == "sha256" {
+        if let Some(expected) = file_checksum {
+            // The file checksum is taken with its own value zeroed out
+            let zeroed = text.replacen(&expected, &"0".repeat(expected.len()), 1);
+            let actual = format!("{:x}", Sha256::digest(zeroed.as_bytes()));
+            if !actual.eq_ignore_ascii_case(&expected) {
+                return Err(corrupt("checksum of the bmap file itself does not match"));
+            }
+        }
+    } else {
+        for range in &mut ranges {
+            range.sha256 = None;
+        }
+    }
+
+    ranges.sort_by_key(|r| r.first_block);
+    if ranges
+        .windows(2)
+        .any(|pair| pair[1].first_block <= pair[0].last_block)
+    {
+        return Err(corrupt("overlapping block ranges"));
+    }
+
+    Ok(Bmap {
+        image_size,
+        block_size,
+        ranges,
+    })
+}
+
+fn parse_number(value: &str) -> Result<u64, ImageError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| corrupt(&format!("invalid number \"{}\"", value.trim())))
+}
+
+fn corrupt(message: &str) -> ImageError {
+    ImageError::Corrupt(format!("bmap file: {}", message))
+}
+
+/// Presents only the mapped ranges of the image as data; everything else is
+/// a hole. Each range's SHA-256 is checked as soon as it has been read.
+pub struct BmapReader {
+    inner: Box<dyn ImageRead>,
+    bmap: Bmap,
+    pos: u64,
+    range: usize,
+    hasher: Sha256,
+    /// The current range was entered part way by a seek, so its checksum
+    /// cannot be checked
+    partial: bool,
+}
+
+impl BmapReader {
+    pub fn new(inner: Box<dyn ImageRead>, bmap: Bmap) -> Self {
+        Self {
+            inner,
+            bmap,
+            pos: 0,
+            range: 0,
+            hasher: Sha256::new(),
+            partial: false,
+        }
+    }
+
+    /// End of the region the current position is in, and whether it is mapped.
+    fn region(&self) -> (u64, bool) {
+        match self.bmap.ranges.get(self.range) {
+            Some(range) if self.pos >= self.bmap.range_start(range) => {
+                (self.bmap.range_end(range), true)
+            }
+            Some(range) => (self.bmap.range_start(range), false),
+            None => (self.bmap.image_size, false),
+        }
+    }
+
+    fn finish_range(&mut self) -> io::Result<()> {
+        let range = &self.bmap.ranges[self.range];
+        let digest = format!("{:x}", std::mem::take(&mut self.hasher).finalize());
+        let partial = std::mem::take(&mut self.partial);
+        if let Some(expected) = range.sha256.as_ref().filter(|_| !partial) {
+            if *expected != digest {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "bmap checksum mismatch for blocks {}-{}",
+                        range.first_block, range.last_block
+                    ),
+                ));
+            }
+        }
+        self.range += 1;
+        Ok(())
+    }
+}
+
+impl Read for BmapReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.bmap.image_size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let (end, mapped) = self.region();
+        let len = buf.len().min((end - self.pos) as usize);
+        let n = self.inner.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "image is shorter than its bmap file describes",
+            ));
+        }
+
+        if mapped {
+            self.hasher.update(&buf[..n]);
+        } else {
+            buf[..n].fill(0);
+        }
+        self.pos += n as u64;
+        if mapped && self.pos == end {
+            self.finish_range()?;
+        }
+        Ok(n)
+    }
+}
+
+impl ImageRead for BmapReader {
+    fn hole_ahead(&self) -> u64 {
+        match self.region() {
+            (end, false) => end.saturating_sub(self.pos),
+            (_, true) => 0,
+        }
+    }
+
+    fn skip_hole(&mut self) -> io::Result<u64> {
+        let len = self.hole_ahead();
+        if len == 0 {
+            return Ok(0);
+        }
+
+        // A short image shows up at the next read after a seek
+        if !self.inner.seek_to(self.pos + len)? {
+            let skipped = io::copy(&mut (&mut self.inner).take(len), &mut io::sink())?;
+            if skipped < len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "image is shorter than its bmap file describes",
+                ));
+            }
+        }
+        self.pos += len;
+        Ok(len)
+    }
+
+    fn seek_to(&mut self, pos: u64) -> io::Result<bool> {
+        if !self.inner.seek_to(pos)? {
+            return Ok(false);
+        }
+        self.pos = pos;
+        self.range = self
+            .bmap
+            .ranges
+            .partition_point(|range| self.bmap.range_end(range) <= pos);
+        self.hasher = Sha256::new();
+        self.partial = self
+            .bmap
+            .ranges
+            .get(self.range)
+            .is_some_and(|range| pos > self.bmap.range_start(range));
+        Ok(true)
+    }
+
+    fn can_seek(&self) -> bool {
+        self.inner.can_seek()
+    }
+}
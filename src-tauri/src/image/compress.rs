@@ -0,0 +1,485 @@
+use super::bytes::{le_u32, le_u64};
+use super::ImageSize;
+use bzip2::read::MultiBzDecoder;
+use flate2::bufread::MultiGzDecoder;
+use lzma_rust2::XzReader;
+use ruzstd::decoding::errors::{FrameDecoderError, ReadFrameHeaderError};
+use ruzstd::decoding::{BlockDecodingStrategy, FrameDecoder};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+
+const XZ_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+const ZSTD_SKIPPABLE_MASK: u32 = 0xFFFF_FFF0;
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+/// bzip2 ends a stream with these 48 bits and a CRC, padded to a whole byte
+const BZIP2_END_MAGIC: u64 = 0x1772_4538_5090;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Bzip2,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Bzip2 => "bzip2",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn decoder<R: BufRead + Send + 'static>(self, inner: R) -> Box<dyn Read + Send> {
+        match self {
+            Compression::Bzip2 => Box::new(Truncated::new(MultiBzDecoder::new(inner), self)),
+            Compression::Gzip => Box::new(Truncated::new(MultiGzDecoder::new(inner), self)),
+            Compression::Xz => Box::new(Truncated::new(XzReader::new(inner, true), self)),
+            Compression::Zstd => Box::new(Truncated::new(ZstdDecoder::new(inner), self)),
+        }
+    }
+
+    /// Reads the decoded size from the compressed file's own metadata,
+    /// without decompressing it. bzip2 does not record one.
+    pub fn recorded_size<F: Read + Seek>(self, file: &mut F) -> io::Result<Option<ImageSize>> {
+        match self {
+            Compression::Bzip2 => Ok(None),
+            Compression::Gzip => gzip_size(file),
+            Compression::Xz => xz_size(file),
+            Compression::Zstd => zstd_size(file),
+        }
+    }
+
+    /// Checks that the file ends where a compressed stream does, without
+    /// decompressing it. `None` for gzip, whose end is only found by
+    /// decoding all of it.
+    pub fn ends_cleanly<F: Read + Seek>(self, file: &mut F) -> io::Result<Option<bool>> {
+        match self {
+            Compression::Bzip2 => bzip2_ends_cleanly(file).map(Some),
+            Compression::Gzip => Ok(None),
+            Compression::Xz => xz_ends_cleanly(file).map(Some),
+            Compression::Zstd => Ok(Some(walk_zstd(file)?.complete)),
+        }
+    }
+}
+
+/// Identifies a compressed stream from its leading bytes.
+pub fn detect(magic: &[u8]) -> Option<Compression> {
+    // "BZh" followed by the block size digit '1'-'9'
+    if magic.len() >= 4 && &magic[..3] == b"BZh" && (b'1'..=b'9').contains(&magic[3]) {
+        return Some(Compression::Bzip2);
+    }
+    // gzip magic followed by the deflate method byte
+    if magic.len() >= 3 && magic[..3] == [0x1F, 0x8B, 0x08] {
+        return Some(Compression::Gzip);
+    }
+    if magic.len() >= 6 && magic[..6] == XZ_MAGIC {
+        return Some(Compression::Xz);
+    }
+    if magic.len() >= 4 && le_u32(magic, 0) == ZSTD_MAGIC {
+        return Some(Compression::Zstd);
+    }
+    None
+}
+
+/// gzip only keeps the size modulo 4 GiB (ISIZE, in the trailer of the last
+/// member), so this is a lower bound: the smallest size consistent with
+/// ISIZE that deflate could have compressed into this many bytes.
+fn gzip_size<F: Read + Seek>(file: &mut F) -> io::Result<Option<ImageSize>> {
+    let compressed = file.seek(SeekFrom::End(0))?;
+    if compressed < 18 {
+        return Ok(None);
+    }
+
+    let mut trailer = [0u8; 4];
+    file.seek(SeekFrom::End(-4))?;
+    file.read_exact(&mut trailer)?;
+    let isize = le_u32(&trailer, 0) as u64;
+
+    // Stored deflate blocks add 5 bytes per 64 KB, plus the gzip framing
+    let floor = compressed.saturating_sub(compressed / 8192 + 64);
+    if isize >= floor {
+        return Ok(Some(ImageSize {
+            bytes: isize,
+            exact: false,
+        }));
+    }
+    // Below 4 GiB a too-small ISIZE means a truncated file or several
+    // concatenated members, not wrap-around
+    if compressed < 1 << 32 {
+        return Ok(None);
+    }
+
+    let wraps = (floor - isize).div_ceil(1 << 32);
+    Ok(Some(ImageSize {
+        bytes: isize + (wraps << 32),
+        exact: false,
+    }))
+}
+
+/// Sums the uncompressed sizes in the index of every xz stream, walking
+/// backwards from the end of the file through any concatenated streams.
+fn xz_size<F: Read + Seek>(file: &mut F) -> io::Result<Option<ImageSize>> {
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut total = 0u64;
+
+    loop {
+        // Stream padding is a multiple of four null bytes
+        while end >= 4 {
+            let mut word = [0u8; 4];
+            file.seek(SeekFrom::Start(end - 4))?;
+            file.read_exact(&mut word)?;
+            if word != [0u8; 4] {
+                break;
+            }
+            end -= 4;
+        }
+        if end < 24 {
+            return Ok(None);
+        }
+
+        let mut footer = [0u8; 12];
+        file.seek(SeekFrom::Start(end - 12))?;
+        file.read_exact(&mut footer)?;
+        if &footer[10..12] != b"YZ" {
+            return Ok(None);
+        }
+
+        let index_size = (le_u32(&footer, 4) as u64 + 1) * 4;
+        let Some(index_start) = (end - 12).checked_sub(index_size) else {
+            return Ok(None);
+        };
+        let mut index = vec![0u8; index_size as usize];
+        file.seek(SeekFrom::Start(index_start))?;
+        file.read_exact(&mut index)?;
+        if index[0] != 0x00 {
+            return Ok(None);
+        }
+
+        let mut pos = 1;
+        let Some(records) = read_varint(&index, &mut pos) else {
+            return Ok(None);
+        };
+        let mut blocks_size = 0u64;
+        for _ in 0..records {
+            let (Some(unpadded), Some(uncompressed)) =
+                (read_varint(&index, &mut pos), read_varint(&index, &mut pos))
+            else {
+                return Ok(None);
+            };
+            blocks_size += (unpadded + 3) & !3;
+            total += uncompressed;
+        }
+
+        let Some(stream_start) = index_start.checked_sub(blocks_size + 12) else {
+            return Ok(None);
+        };
+        let mut header = [0u8; 6];
+        file.seek(SeekFrom::Start(stream_start))?;
+        file.read_exact(&mut header)?;
+        if header != XZ_MAGIC {
+            return Ok(None);
+        }
+
+        if stream_start == 0 {
+            return Ok(Some(ImageSize {
+                bytes: total,
+                exact: true,
+            }));
+        }
+        end = stream_start;
+    }
+}
+
+/// Looks for the end-of-stream marker in the last bytes of the file, at
+/// each of the eight bit offsets the padding allows.
+fn bzip2_ends_cleanly<F: Read + Seek>(file: &mut F) -> io::Result<bool> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < 14 {
+        return Ok(false);
+    }
+    // 48-bit marker, 32-bit CRC, up to 7 bits of padding
+    let mut tail = [0u8; 11];
+    file.seek(SeekFrom::End(-11))?;
+    file.read_exact(&mut tail)?;
+    let bits = tail.iter().fold(0u128, |bits, &b| bits << 8 | b as u128);
+    Ok((0..8).any(|padding| (bits >> (32 + padding)) as u64 & 0xFFFF_FFFF_FFFF == BZIP2_END_MAGIC))
+}
+
+/// Checks for the footer of an xz stream after any stream padding.
+fn xz_ends_cleanly<F: Read + Seek>(file: &mut F) -> io::Result<bool> {
+    let mut end = file.seek(SeekFrom::End(0))?;
+    while end >= 4 {
+        let mut word = [0u8; 4];
+        file.seek(SeekFrom::Start(end - 4))?;
+        file.read_exact(&mut word)?;
+        if word != [0u8; 4] {
+            break;
+        }
+        end -= 4;
+    }
+    if end < 24 {
+        return Ok(false);
+    }
+    let mut magic = [0u8; 2];
+    file.seek(SeekFrom::Start(end - 2))?;
+    file.read_exact(&mut magic)?;
+    Ok(&magic == b"YZ")
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for i in 0..9 {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7F) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Adds up the frame content size of every zstd frame. Returns `None` if
+/// any frame leaves its size out.
+fn zstd_size<F: Read + Seek>(file: &mut F) -> io::Result<Option<ImageSize>> {
+    let frames = walk_zstd(file)?;
+    if !frames.complete {
+        return Ok(None);
+    }
+    Ok(frames
+        .content_size
+        .map(|bytes| ImageSize { bytes, exact: true }))
+}
+
+/// What walking the frames of a zstd file found.
+struct ZstdFrames {
+    /// Total of the frame content sizes, if every frame records one
+    content_size: Option<u64>,
+    /// The last frame ends exactly at the end of the file
+    complete: bool,
+}
+
+/// Walks the frames of a zstd file block by block, which only touches the
+/// 3-byte block headers.
+fn walk_zstd<F: Read + Seek>(file: &mut F) -> io::Result<ZstdFrames> {
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file);
+    let mut frames = ZstdFrames {
+        content_size: Some(0),
+        complete: false,
+    };
+    match walk_zstd_frames(&mut reader, &mut frames) {
+        // Skipping a block that is cut short seeks past the end
+        Ok(true) => frames.complete = reader.stream_position()? == len,
+        Ok(false) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+        Err(e) => return Err(e),
+    }
+    Ok(frames)
+}
+
+/// Returns false on anything that is not a frame.
+fn walk_zstd_frames<R: Read + Seek>(
+    reader: &mut BufReader<R>,
+    frames: &mut ZstdFrames,
+) -> io::Result<bool> {
+    loop {
+        if reader.fill_buf()?.is_empty() {
+            return Ok(true);
+        }
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let magic = le_u32(&magic, 0);
+
+        if magic & ZSTD_SKIPPABLE_MASK == ZSTD_SKIPPABLE_MAGIC {
+            let mut length = [0u8; 4];
+            reader.read_exact(&mut length)?;
+            reader.seek_relative(le_u32(&length, 0) as i64)?;
+            continue;
+        }
+        if magic != ZSTD_MAGIC {
+            return Ok(false);
+        }
+
+        let mut descriptor = [0u8; 1];
+        reader.read_exact(&mut descriptor)?;
+        let descriptor = descriptor[0];
+        let single_segment = descriptor & 0x20 != 0;
+        let has_checksum = descriptor & 0x04 != 0;
+        let dict_id_len = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+        let size_len = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => 0,
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+
+        let window_len = if single_segment { 0 } else { 1 };
+        reader.seek_relative(window_len + dict_id_len)?;
+
+        if size_len == 0 {
+            frames.content_size = None;
+        } else {
+            let mut size = [0u8; 8];
+            reader.read_exact(&mut size[..size_len])?;
+            let mut content_size = le_u64(&size, 0);
+            if size_len == 2 {
+                content_size += 256;
+            }
+            frames.content_size = frames.content_size.map(|total| total + content_size);
+        }
+
+        loop {
+            let mut block = [0u8; 3];
+            reader.read_exact(&mut block)?;
+            let header = block[0] as u32 | (block[1] as u32) << 8 | (block[2] as u32) << 16;
+            let last = header & 1 != 0;
+            let block_type = (header >> 1) & 0x03;
+            let block_size = header >> 3;
+            // RLE blocks store a single byte regardless of their size
+            let stored = if block_type == 1 { 1 } else { block_size };
+            reader.seek_relative(stored as i64)?;
+            if last {
+                break;
+            }
+        }
+        if has_checksum {
+            reader.seek_relative(4)?;
+        }
+    }
+}
+
+/// Decodes every frame of a zstd stream in turn, skipping skippable frames
+/// and checking each frame's content checksum when it has one.
+struct ZstdDecoder<R> {
+    source: R,
+    decoder: FrameDecoder,
+    in_frame: bool,
+}
+
+impl<R: BufRead> ZstdDecoder<R> {
+    fn new(source: R) -> Self {
+        Self {
+            source,
+            decoder: FrameDecoder::new(),
+            in_frame: false,
+        }
+    }
+
+    fn start_frame(&mut self) -> io::Result<bool> {
+        loop {
+            if self.source.fill_buf()?.is_empty() {
+                return Ok(false);
+            }
+            match self.decoder.reset(&mut self.source) {
+                Ok(()) => return Ok(true),
+                Err(FrameDecoderError::ReadFrameHeaderError(ReadFrameHeaderError::SkipFrame {
+                    length,
+                    ..
+                })) => {
+                    io::copy(&mut (&mut self.source).take(length as u64), &mut io::sink())?;
+                }
+                Err(e) => return Err(zstd_error(e)),
+            }
+        }
+    }
+
+    fn finish_frame(&self) -> io::Result<()> {
+        if let (Some(stored), Some(calculated)) = (
+            self.decoder.get_checksum_from_data(),
+            self.decoder.get_calculated_checksum(),
+        ) {
+            if stored != calculated {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "zstd frame checksum mismatch",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ZstdDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.in_frame {
+                if self.decoder.can_collect() > 0 {
+                    return self.decoder.read(buf);
+                }
+                if self.decoder.is_finished() {
+                    self.finish_frame()?;
+                    self.in_frame = false;
+                    continue;
+                }
+                if self.source.fill_buf()?.is_empty() {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                if let Err(e) = self.decoder.decode_blocks(
+                    &mut self.source,
+                    BlockDecodingStrategy::UptoBytes(buf.len()),
+                ) {
+                    // Running out of input mid-block is truncation, not corruption
+                    if self.source.fill_buf()?.is_empty() {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    return Err(zstd_error(e));
+                }
+            } else if self.start_frame()? {
+                self.in_frame = true;
+            } else {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+fn zstd_error(e: FrameDecoderError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Turns the decoder's bare "unexpected EOF" into an error that says the
+/// archive itself is cut short, and how far decoding got.
+struct Truncated<R> {
+    inner: R,
+    compression: Compression,
+    bytes_out: u64,
+}
+
+impl<R> Truncated<R> {
+    fn new(inner: R, compression: Compression) -> Self {
+        Self {
+            inner,
+            compression,
+            bytes_out: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Truncated<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.bytes_out += n as u64;
+                Ok(n)
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "{} archive is truncated: stream ended after {} bytes of image data",
+                    self.compression.name(),
+                    self.bytes_out
+                ),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+}
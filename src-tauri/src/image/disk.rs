@@ -0,0 +1,78 @@
+use super::ImageRead;
+use std::io::{self, Read};
+
+/// A virtual disk kept in a random-access container format, read by offset.
+pub trait VirtualDisk: Send {
+    /// Size of the disk as the guest sees it.
+    fn size(&self) -> u64;
+
+    /// Reads at `pos` into `buf`. May return fewer bytes than asked for
+    /// (typically stopping at a cluster boundary), but at least one while
+    /// `pos` is below `size`.
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Damage found while opening that a redundant copy made up for.
+    fn warnings(&self) -> &[String] {
+        &[]
+    }
+}
+
+/// Presents a virtual disk as the raw image stream, front to back.
+pub struct DiskReader<D> {
+    disk: D,
+    pos: u64,
+}
+
+impl<D: VirtualDisk> DiskReader<D> {
+    pub fn new(disk: D) -> Self {
+        Self { disk, pos: 0 }
+    }
+}
+
+impl<D: VirtualDisk> Read for DiskReader<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.disk.size().saturating_sub(self.pos);
+        let len = buf.len().min(left.min(usize::MAX as u64) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        let n = self.disk.read_at(self.pos, &mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<D: VirtualDisk> ImageRead for DiskReader<D> {
+    fn seek_to(&mut self, pos: u64) -> io::Result<bool> {
+        self.pos = pos;
+        Ok(true)
+    }
+
+    fn can_seek(&self) -> bool {
+        true
+    }
+}
+
+/// Reads exactly `buf.len()` bytes at `offset`, reporting a short file as
+/// corruption of the named format rather than a bare EOF.
+pub fn read_exact_at<F: io::Read + io::Seek>(
+    file: &mut F,
+    offset: u64,
+    buf: &mut [u8],
+    format: &str,
+) -> io::Result<()> {
+    file.seek(io::SeekFrom::Start(offset))?;
+    file.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} image is truncated: data at offset {} is missing",
+                format, offset
+            ),
+        ),
+        _ => e,
+    })
+}
@@ -0,0 +1,345 @@
+use super::bytes::{be_u32, be_u64};
+use super::disk::{read_exact_at, VirtualDisk};
+use super::ImageError;
+use bzip2::read::BzDecoder;
+use flate2::read::ZlibDecoder;
+use std::fs::File;
+use std::io::{self, Read, Seek};
+
+const KOLY_MAGIC: &[u8] = b"koly";
+const KOLY_LEN: u64 = 512;
+const MISH_MAGIC: &[u8] = b"mish";
+const MISH_HEADER_LEN: usize = 204;
+const CHUNK_LEN: usize = 40;
+const SECTOR: u64 = 512;
+/// Encrypted images start with this instead of chunk data
+const ENCRYPTED_MAGIC: &[u8] = b"encrcdsa";
+
+const CHUNK_ZERO: u32 = 0x0000_0000;
+const CHUNK_RAW: u32 = 0x0000_0001;
+const CHUNK_IGNORE: u32 = 0x0000_0002;
+const CHUNK_ADC: u32 = 0x8000_0004;
+const CHUNK_ZLIB: u32 = 0x8000_0005;
+const CHUNK_BZIP2: u32 = 0x8000_0006;
+const CHUNK_LZFSE: u32 = 0x8000_0007;
+const CHUNK_COMMENT: u32 = 0x7FFF_FFFE;
+const CHUNK_TERMINATOR: u32 = 0xFFFF_FFFF;
+
+/// Decompressed chunks are capped well above what hdiutil produces
+const MAX_CHUNK_LEN: u64 = 64 * 1024 * 1024;
+
+/// UDIF images are recognised by the koly trailer in their last 512 bytes.
+pub fn has_trailer<F: Read + Seek>(file: &mut F, file_size: u64) -> io::Result<bool> {
+    if file_size < KOLY_LEN {
+        return Ok(false);
+    }
+    let mut magic = [0u8; 4];
+    read_exact_at(file, file_size - KOLY_LEN, &mut magic, "DMG")?;
+    Ok(magic == KOLY_MAGIC)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    kind: u32,
+    /// Position and length in the disk, in bytes
+    start: u64,
+    len: u64,
+    /// Position and length of the stored data in the file
+    offset: u64,
+    stored_len: u64,
+}
+
+/// An Apple UDIF disk image, read through the chunk tables of its blkx
+/// resources.
+pub struct Dmg {
+    file: File,
+    size: u64,
+    /// Sorted by `start`, without overlaps
+    chunks: Vec<Chunk>,
+    compressed: bool,
+    /// Most recently decompressed chunk, by its index in `chunks`
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl Dmg {
+    pub fn open(mut file: File) -> Result<Self, ImageError> {
+        let file_size = file.metadata()?.len();
+        let koly_offset = file_size
+            .checked_sub(KOLY_LEN)
+            .ok_or_else(|| corrupt("koly trailer is missing"))?;
+        let mut koly = [0u8; KOLY_LEN as usize];
+        read_exact_at(&mut file, koly_offset, &mut koly, "DMG")?;
+        if !koly.starts_with(KOLY_MAGIC) {
+            return Err(corrupt("koly trailer is missing"));
+        }
+
+        let mut magic = [0u8; 8];
+        read_exact_at(&mut file, 0, &mut magic, "DMG")?;
+        if magic == ENCRYPTED_MAGIC {
+            return Err(ImageError::Unsupported("encrypted DMG images".to_string()));
+        }
+
+        let data_fork_offset = be_u64(&koly, 24);
+        let xml_offset = be_u64(&koly, 216);
+        let xml_len = be_u64(&koly, 224);
+        let size = be_u64(&koly, 492)
+            .checked_mul(SECTOR)
+            .ok_or_else(|| corrupt("disk size is out of range"))?;
+        if xml_len == 0 {
+            return Err(ImageError::Unsupported(
+                "DMG images without an XML property list".to_string(),
+            ));
+        }
+        if xml_offset.saturating_add(xml_len) > file_size || xml_len > 64 * 1024 * 1024 {
+            return Err(corrupt("property list lies beyond the end of the file"));
+        }
+
+        let mut xml = vec![0u8; xml_len as usize];
+        read_exact_at(&mut file, xml_offset, &mut xml, "DMG")?;
+        let mut chunks = parse_blkx(&xml, data_fork_offset)?;
+
+        chunks.sort_by_key(|c| c.start);
+        for chunk in &chunks {
+            if chunk.start.saturating_add(chunk.len) > size {
+                return Err(corrupt("chunk tables reach past the end of the disk"));
+            }
+            if chunk.offset.saturating_add(chunk.stored_len) > file_size {
+                return Err(corrupt("chunk data lies beyond the end of the file"));
+            }
+        }
+        if chunks
+            .windows(2)
+            .any(|pair| pair[1].start < pair[0].start + pair[0].len)
+        {
+            return Err(corrupt("chunk tables overlap"));
+        }
+        let compressed = chunks
+            .iter()
+            .any(|c| c.kind & 0x8000_0000 != 0 && c.kind != CHUNK_TERMINATOR);
+
+        Ok(Self {
+            file,
+            size,
+            chunks,
+            compressed,
+            cache: None,
+        })
+    }
+
+    pub fn format_name(&self) -> &'static str {
+        if self.compressed {
+            "DMG (compressed)"
+        } else {
+            "DMG"
+        }
+    }
+
+    fn load_chunk(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self.cache.as_ref().map(|(cached, _)| *cached) != Some(index) {
+            let chunk = self.chunks[index];
+            let mut stored = vec![0u8; chunk.stored_len as usize];
+            read_exact_at(&mut self.file, chunk.offset, &mut stored, "DMG")?;
+
+            let mut data = Vec::with_capacity(chunk.len as usize);
+            let result = match chunk.kind {
+                CHUNK_ZLIB => ZlibDecoder::new(&stored[..])
+                    .take(chunk.len)
+                    .read_to_end(&mut data)
+                    .map(|_| ()),
+                CHUNK_BZIP2 => BzDecoder::new(&stored[..])
+                    .take(chunk.len)
+                    .read_to_end(&mut data)
+                    .map(|_| ()),
+                CHUNK_LZFSE => lzfse_rust::decode_bytes(&stored, &mut data)
+                    .map(|_| ())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+                CHUNK_ADC => adc_decode(&stored, &mut data, chunk.len as usize),
+                _ => unreachable!("only compressed chunks are loaded"),
+            };
+            result.map_err(|e| {
+                invalid(&format!(
+                    "chunk at disk offset {} is damaged: {}",
+                    chunk.start, e
+                ))
+            })?;
+            if (data.len() as u64) < chunk.len {
+                return Err(invalid(&format!(
+                    "chunk at disk offset {} decompressed to {} bytes, expected {}",
+                    chunk.start,
+                    data.len(),
+                    chunk.len
+                )));
+            }
+            self.cache = Some((index, data));
+        }
+        Ok(&self.cache.as_ref().unwrap().1)
+    }
+}
+
+/// Collects the chunks of every blkx resource in the property list.
+fn parse_blkx(xml: &[u8], data_fork_offset: u64) -> Result<Vec<Chunk>, ImageError> {
+    let plist = plist::Value::from_reader_xml(xml)
+        .map_err(|e| corrupt(&format!("property list is damaged: {}", e)))?;
+    let blkx = plist
+        .as_dictionary()
+        .and_then(|d| d.get("resource-fork"))
+        .and_then(|v| v.as_dictionary())
+        .and_then(|d| d.get("blkx"))
+        .and_then(|v| v.as_array())
+        .ok_or_else(|| corrupt("property list has no blkx resources"))?;
+
+    let mut chunks = Vec::new();
+    for resource in blkx {
+        let data = resource
+            .as_dictionary()
+            .and_then(|d| d.get("Data"))
+            .and_then(|v| v.as_data())
+            .ok_or_else(|| corrupt("blkx resource without data"))?;
+        parse_mish(data, data_fork_offset, &mut chunks)?;
+    }
+    Ok(chunks)
+}
+
+fn parse_mish(
+    data: &[u8],
+    data_fork_offset: u64,
+    chunks: &mut Vec<Chunk>,
+) -> Result<(), ImageError> {
+    if data.len() < MISH_HEADER_LEN || !data.starts_with(MISH_MAGIC) {
+        return Err(corrupt("blkx table header is damaged"));
+    }
+    let first_sector = be_u64(data, 8);
+    let data_offset = be_u64(data, 24);
+    let count = be_u32(data, 200) as usize;
+    if data.len() < MISH_HEADER_LEN + count * CHUNK_LEN {
+        return Err(corrupt("blkx table is truncated"));
+    }
+
+    for i in 0..count {
+        let entry = &data[MISH_HEADER_LEN + i * CHUNK_LEN..];
+        let kind = be_u32(entry, 0);
+        let start = first_sector
+            .checked_add(be_u64(entry, 8))
+            .and_then(|sector| sector.checked_mul(SECTOR));
+        let len = be_u64(entry, 16).checked_mul(SECTOR);
+        let offset = data_fork_offset
+            .checked_add(data_offset)
+            .and_then(|offset| offset.checked_add(be_u64(entry, 24)));
+        let (Some(start), Some(len), Some(offset)) = (start, len, offset) else {
+            return Err(corrupt("chunk is out of range"));
+        };
+        let chunk = Chunk {
+            kind,
+            start,
+            len,
+            offset,
+            stored_len: be_u64(entry, 32),
+        };
+        match kind {
+            CHUNK_TERMINATOR => break,
+            CHUNK_COMMENT => continue,
+            CHUNK_ZERO | CHUNK_IGNORE => {}
+            CHUNK_RAW => {
+                if chunk.stored_len < chunk.len {
+                    return Err(corrupt("raw chunk is shorter than the sectors it covers"));
+                }
+            }
+            CHUNK_ADC | CHUNK_ZLIB | CHUNK_BZIP2 | CHUNK_LZFSE => {
+                if chunk.len > MAX_CHUNK_LEN {
+                    return Err(corrupt("chunk is too large"));
+                }
+            }
+            other => {
+                return Err(ImageError::Unsupported(format!(
+                    "DMG chunk type {:#010x}",
+                    other
+                )))
+            }
+        }
+        if chunk.len > 0 {
+            chunks.push(chunk);
+        }
+    }
+    Ok(())
+}
+
+/// Apple Data Compression: literal runs and back-references into the output.
+fn adc_decode(input: &[u8], output: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+    let damaged = || io::Error::new(io::ErrorKind::InvalidData, "invalid ADC data");
+    let mut pos = 0;
+    while pos < input.len() && output.len() < limit {
+        let byte = input[pos];
+        if byte & 0x80 != 0 {
+            let len = (byte & 0x7F) as usize + 1;
+            let literal = input.get(pos + 1..pos + 1 + len).ok_or_else(damaged)?;
+            output.extend_from_slice(literal);
+            pos += 1 + len;
+            continue;
+        }
+
+        let (len, distance) = if byte & 0x40 != 0 {
+            let high = *input.get(pos + 1).ok_or_else(damaged)? as usize;
+            let low = *input.get(pos + 2).ok_or_else(damaged)? as usize;
+            pos += 3;
+            ((byte & 0x3F) as usize + 4, (high << 8 | low) + 1)
+        } else {
+            let low = *input.get(pos + 1).ok_or_else(damaged)? as usize;
+            pos += 2;
+            (
+                ((byte & 0x3C) >> 2) as usize + 3,
+                (((byte & 0x03) as usize) << 8 | low) + 1,
+            )
+        };
+        let from = output.len().checked_sub(distance).ok_or_else(damaged)?;
+        // Copies may overlap their own output
+        for i in 0..len {
+            output.push(output[from + i]);
+        }
+    }
+    output.truncate(limit);
+    Ok(())
+}
+
+impl VirtualDisk for Dmg {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let index = self.chunks.partition_point(|c| c.start + c.len <= pos);
+        let chunk = match self.chunks.get(index) {
+            Some(chunk) if chunk.start <= pos => *chunk,
+            next => {
+                // Sectors no chunk covers read as zeros
+                let gap_end = next.map(|c| c.start).unwrap_or(self.size);
+                let len = buf.len().min((gap_end - pos) as usize);
+                buf[..len].fill(0);
+                return Ok(len);
+            }
+        };
+
+        let in_chunk = pos - chunk.start;
+        let len = buf.len().min((chunk.len - in_chunk) as usize);
+        let buf = &mut buf[..len];
+        match chunk.kind {
+            CHUNK_ZERO | CHUNK_IGNORE => buf.fill(0),
+            CHUNK_RAW => read_exact_at(&mut self.file, chunk.offset + in_chunk, buf, "DMG")?,
+            _ => {
+                let data = self.load_chunk(index)?;
+                buf.copy_from_slice(&data[in_chunk as usize..in_chunk as usize + len]);
+            }
+        }
+        Ok(len)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("DMG image: {}", message),
+    )
+}
+
+fn corrupt(message: &str) -> ImageError {
+    ImageError::Corrupt(format!("DMG image: {}", message))
+}
@@ -0,0 +1,645 @@
+mod bmap;
+pub mod bytes;
+mod compress;
+mod disk;
+mod dmg;
+mod qcow2;
+mod sparse;
+mod split;
+mod tar;
+mod vhd;
+mod vhdx;
+mod vmdk;
+mod zip;
+
+pub use split::SplitReader;
+
+use disk::VirtualDisk;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ImageError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Unsupported image: {0}")]
+    Unsupported(String),
+    #[error("Corrupt image: {0}")]
+    Corrupt(String),
+    #[error("Entry not found in archive: {0}")]
+    EntryNotFound(String),
+    #[error("Archive contains no disk images")]
+    NoImageEntry,
+    #[error("Archive contains {0} disk images; choose one to write")]
+    AmbiguousEntry(usize),
+}
+
+/// File extensions treated as disk images when looking inside archives.
+const IMAGE_EXTENSIONS: &[&str] = &["img", "iso", "raw", "bin", "dd", "wic"];
+
+/// Raw disk image bytes, decoded from whatever container the file uses.
+pub struct ImageSource {
+    /// Name of the compression or container format, `None` for plain images.
+    pub container: Option<String>,
+    /// Archive entry the image is read from, if the file is an archive.
+    pub entry: Option<String>,
+    /// Encoding of the disk inside the file when it is not stored raw,
+    /// such as an Android sparse image.
+    pub disk_format: Option<String>,
+    /// Size of the file on disk.
+    pub file_size: u64,
+    /// Size of the decoded image, if it is known before reading it all.
+    pub size: Option<ImageSize>,
+    /// Block map found next to the image, limiting writes to mapped ranges.
+    pub bmap_path: Option<PathBuf>,
+    /// Bytes that carry data, when the image has holes that need no writing.
+    pub mapped_bytes: Option<u64>,
+    /// Damage found in the file that did not stop it from being read.
+    pub warnings: Vec<String>,
+    /// The compressed stream is cut off before its end, as an interrupted
+    /// download leaves it. `None` when that can't be told without decoding
+    /// the whole stream.
+    pub truncated: Option<bool>,
+    reader: Box<dyn ImageRead>,
+}
+
+impl ImageSource {
+    fn new<R: ImageRead + 'static>(reader: R, file_size: u64, size: Option<ImageSize>) -> Self {
+        Self {
+            container: None,
+            entry: None,
+            disk_format: None,
+            file_size,
+            size,
+            bmap_path: None,
+            mapped_bytes: None,
+            warnings: Vec::new(),
+            truncated: Some(false),
+            reader: Box::new(reader),
+        }
+    }
+
+    /// Skips a hole at the current position, returning its length. Holes
+    /// read as zeros through `Read`; writers can seek past them instead.
+    pub fn skip_hole(&mut self) -> io::Result<u64> {
+        self.reader.skip_hole()
+    }
+
+    /// Moves to `pos` in the decoded image. Returns false, without moving,
+    /// when the image can only be read front to back.
+    pub fn seek_to(&mut self, pos: u64) -> io::Result<bool> {
+        self.reader.seek_to(pos)
+    }
+
+    /// Whether any offset can be read without decoding the image up to it.
+    pub fn can_seek(&self) -> bool {
+        self.reader.can_seek()
+    }
+
+    /// Like `read_up_to`, but stops early where a hole begins.
+    pub fn read_data(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() && self.reader.hole_ahead() == 0 {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+}
+
+impl Read for ImageSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// A decoded image stream. Streams with holes never return data and hole
+/// bytes from the same `read` call.
+pub trait ImageRead: Read + Send {
+    /// Length of the hole starting at the current position, 0 when the
+    /// stream is positioned at data.
+    fn hole_ahead(&self) -> u64 {
+        0
+    }
+
+    /// If positioned at the start of a region that needs no writing,
+    /// consumes it and returns its length; returns 0 otherwise.
+    fn skip_hole(&mut self) -> io::Result<u64> {
+        Ok(0)
+    }
+
+    /// Moves to `pos` if the stream allows random access, returning false
+    /// otherwise.
+    fn seek_to(&mut self, _pos: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Whether `seek_to` moves without reading the bytes in between.
+    fn can_seek(&self) -> bool {
+        false
+    }
+}
+
+impl ImageRead for Box<dyn Read + Send> {}
+impl ImageRead for io::Empty {}
+
+impl ImageRead for io::Chain<Cursor<Vec<u8>>, Box<dyn ImageRead>> {
+    fn seek_to(&mut self, pos: u64) -> io::Result<bool> {
+        let (head, rest) = self.get_mut();
+        if !rest.seek_to(pos)? {
+            return Ok(false);
+        }
+        // The peeked bytes are behind us now
+        head.set_position(head.get_ref().len() as u64);
+        Ok(true)
+    }
+
+    fn can_seek(&self) -> bool {
+        self.get_ref().1.can_seek()
+    }
+}
+
+/// An image stored as it is, readable from any offset.
+struct RawReader<F>(F);
+
+impl<F: Read> Read for RawReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<F: Read + Seek + Send> ImageRead for RawReader<F> {
+    fn seek_to(&mut self, pos: u64) -> io::Result<bool> {
+        self.0.seek(SeekFrom::Start(pos))?;
+        Ok(true)
+    }
+
+    fn can_seek(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImageSize {
+    pub bytes: u64,
+    /// False when the format only records a lower bound (gzip keeps the
+    /// size modulo 4 GiB).
+    pub exact: bool,
+}
+
+impl ImageSize {
+    fn exact(bytes: u64) -> Self {
+        Self { bytes, exact: true }
+    }
+}
+
+/// An image candidate inside an archive.
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Opens `path` as a raw image stream. For archives, `entry` names the member
+/// to read; it may be omitted when the archive holds a single disk image.
+pub fn open_image(path: &str, entry: Option<&str>) -> Result<ImageSource, ImageError> {
+    let mut source = open_stream(path, entry)?;
+
+    // Sparse images are often compressed too, so look at the decoded stream
+    let header = peek(&mut source, sparse::HEADER_LEN)?;
+    if let (Some(container), Some(format)) = (&source.container, virtual_disk_format(&header)) {
+        // These formats need random access, which a compressed stream can't give
+        return Err(ImageError::Unsupported(format!(
+            "{} image inside a {} file; extract it first",
+            format, container
+        )));
+    }
+    if let Some(header) = sparse::parse_header(&header)? {
+        source.reader.read_exact(&mut [0u8; sparse::HEADER_LEN])?;
+        if source.container.is_none() {
+            let mut file = open_file(path)?;
+            source.mapped_bytes = Some(sparse::mapped_bytes(&mut file, &header)?);
+        }
+        source.size = Some(ImageSize::exact(header.image_size()));
+        source.disk_format = Some("Android sparse".to_string());
+        source.reader = Box::new(sparse::SparseReader::new(source.reader, header)?);
+        return Ok(source);
+    }
+
+    if let Some(bmap_path) = bmap::find(path) {
+        let bmap = bmap::load(&bmap_path)?;
+        if let Some(size) = source
+            .size
+            .filter(|s| s.exact && s.bytes != bmap.image_size)
+        {
+            return Err(ImageError::Corrupt(format!(
+                "bmap file describes a {} byte image, but the image is {} bytes",
+                bmap.image_size, size.bytes
+            )));
+        }
+        source.size = Some(ImageSize::exact(bmap.image_size));
+        source.mapped_bytes = Some(bmap.mapped_bytes());
+        source.bmap_path = Some(bmap_path);
+        source.reader = Box::new(bmap::BmapReader::new(source.reader, bmap));
+    }
+
+    Ok(source)
+}
+
+fn open_stream(path: &str, entry: Option<&str>) -> Result<ImageSource, ImageError> {
+    if let Some(parts) = split::find_parts(Path::new(path))? {
+        return open_split(parts);
+    }
+
+    let mut file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+
+    let mut magic = [0u8; 32];
+    let magic_len = read_up_to(&mut file, &mut magic)?;
+    let magic = &magic[..magic_len];
+    file.seek(SeekFrom::Start(0))?;
+
+    // UDIF keeps its trailer at the end, and its data fork may well start
+    // with a bzip2 chunk, so look for it before anything else
+    if dmg::has_trailer(&mut file, file_size)? {
+        let disk = dmg::Dmg::open(file)?;
+        let format = disk.format_name();
+        return Ok(disk_source(disk, format, file_size));
+    }
+
+    if zip::is_zip(magic) {
+        let entries = zip::list_entries(&mut file)?;
+        let selected = select_entry(&entries, |e| e.name.as_str(), entry)?;
+        let reader = zip::open_entry(file, selected)?;
+        let size = Some(ImageSize::exact(selected.size));
+        let mut source = ImageSource::new(reader, file_size, size);
+        source.container = Some("zip".to_string());
+        source.entry = Some(selected.name.clone());
+        return Ok(source);
+    }
+
+    if qcow2::is_qcow2(magic) {
+        let disk = qcow2::Qcow2::open(file)?;
+        return Ok(disk_source(disk, "qcow2", file_size));
+    }
+    if vhdx::is_vhdx(magic) {
+        let disk = vhdx::Vhdx::open(file)?;
+        return Ok(disk_source(disk, "VHDX", file_size));
+    }
+    if vmdk::is_sparse_vmdk(magic) {
+        let disk = vmdk::Vmdk::open(file)?;
+        let format = disk.format_name();
+        return Ok(disk_source(disk, &format, file_size));
+    }
+    if vmdk::is_descriptor(magic) {
+        return Err(vmdk::descriptor_error(&mut file));
+    }
+
+    let compression = compress::detect(magic);
+    if is_tar_file(path, compression)? {
+        return open_tar_entry(path, compression, file_size, entry);
+    }
+
+    if let Some(compression) = compression {
+        // A damaged index or trailer just leaves the size unknown
+        let size = compression.recorded_size(&mut file).unwrap_or(None);
+        let truncated = compression.ends_cleanly(&mut file)?.map(|clean| !clean);
+        file.seek(SeekFrom::Start(0))?;
+        let reader = compression.decoder(BufReader::new(file));
+        let mut source = ImageSource::new(reader, file_size, size);
+        source.container = Some(compression.name().to_string());
+        source.truncated = truncated;
+        return Ok(source);
+    }
+
+    // Fixed VHDs are only recognisable by the footer at the end
+    if let Some(footer) = vhd::read_footer(&mut file, file_size)? {
+        let disk = vhd::Vhd::open(file, footer)?;
+        let format = disk.format_name();
+        return Ok(disk_source(disk, format, file_size));
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    Ok(ImageSource::new(
+        RawReader(file),
+        file_size,
+        Some(ImageSize::exact(file_size)),
+    ))
+}
+
+/// Split images are read as one stream, so only raw and compressed images
+/// work; archives and virtual disks need their parts joined on disk.
+fn open_split(parts: Vec<(PathBuf, u64)>) -> Result<ImageSource, ImageError> {
+    let count = parts.len();
+    let mut reader = split::SplitReader::new(parts.clone());
+    let file_size = reader.size();
+
+    let mut magic = [0u8; tar::BLOCK_LEN];
+    let magic_len = read_up_to(&mut reader, &mut magic)?;
+    let magic = &magic[..magic_len];
+    reader.seek(SeekFrom::Start(0))?;
+
+    let compression = compress::detect(magic);
+    let format = if dmg::has_trailer(&mut reader, file_size)? {
+        Some("DMG")
+    } else if zip::is_zip(magic) {
+        Some("zip")
+    } else if vmdk::is_descriptor(magic) {
+        Some("VMDK")
+    } else if compression.is_none() && vhd::read_footer(&mut reader, file_size)?.is_some() {
+        Some("VHD")
+    } else {
+        virtual_disk_format(magic)
+    };
+    let format = match format {
+        Some(format) => Some(format),
+        None => {
+            // Only a decoded stream shows whether a compressed file is a tar
+            let mut block = [0u8; tar::BLOCK_LEN];
+            let len = match compression {
+                Some(compression) => {
+                    let mut decoder =
+                        compression.decoder(BufReader::new(split::SplitReader::new(parts.clone())));
+                    read_up_to(&mut decoder, &mut block).unwrap_or(0)
+                }
+                None => {
+                    block[..magic.len()].copy_from_slice(magic);
+                    magic.len()
+                }
+            };
+            tar::is_tar(&block[..len]).then_some("tar")
+        }
+    };
+    if let Some(format) = format {
+        return Err(ImageError::Unsupported(format!(
+            "{} file split into {} parts; join the parts first",
+            format, count
+        )));
+    }
+    reader.seek(SeekFrom::Start(0))?;
+
+    if let Some(compression) = compression {
+        let size = compression.recorded_size(&mut reader).unwrap_or(None);
+        let truncated = compression.ends_cleanly(&mut reader)?.map(|clean| !clean);
+        reader.seek(SeekFrom::Start(0))?;
+        let decoder = compression.decoder(BufReader::new(reader));
+        let mut source = ImageSource::new(decoder, file_size, size);
+        source.container = Some(compression.name().to_string());
+        source.truncated = truncated;
+        return Ok(source);
+    }
+
+    Ok(ImageSource::new(
+        RawReader(reader),
+        file_size,
+        Some(ImageSize::exact(file_size)),
+    ))
+}
+
+/// The bytes of the file at `path`, undecoded. A split image reads as its
+/// parts joined together.
+pub fn open_file(path: &str) -> Result<SplitReader, ImageError> {
+    let parts = match split::find_parts(Path::new(path))? {
+        Some(parts) => parts,
+        None => vec![(PathBuf::from(path), std::fs::metadata(path)?.len())],
+    };
+    Ok(split::SplitReader::new(parts))
+}
+
+fn open_tar_input(
+    path: &str,
+    compression: Option<compress::Compression>,
+) -> io::Result<tar::TarInput> {
+    let file = File::open(path)?;
+    Ok(match compression {
+        Some(compression) => tar::TarInput::Stream(compression.decoder(BufReader::new(file))),
+        None => tar::TarInput::File(file),
+    })
+}
+
+fn is_tar_file(path: &str, compression: Option<compress::Compression>) -> io::Result<bool> {
+    let mut block = [0u8; tar::BLOCK_LEN];
+    // Decoding errors just mean this is not a tar
+    let len = match read_up_to(&mut open_tar_input(path, compression)?, &mut block) {
+        Ok(len) => len,
+        Err(_) => return Ok(false),
+    };
+    Ok(tar::is_tar(&block[..len]))
+}
+
+/// Identifies a version of a file: its path, size and modification time.
+type FileKey = (PathBuf, u64, Option<std::time::SystemTime>);
+
+/// The entries of the last tar listed. Listing, inspecting and writing an
+/// image each open it again, and a compressed tar can only be listed by
+/// decompressing all of it.
+static TAR_LISTING: Mutex<Option<(FileKey, Vec<tar::TarEntry>)>> = Mutex::new(None);
+
+fn file_key(path: &str) -> io::Result<FileKey> {
+    let metadata = std::fs::metadata(path)?;
+    Ok((
+        PathBuf::from(path),
+        metadata.len(),
+        metadata.modified().ok(),
+    ))
+}
+
+fn cached_tar_listing(key: &FileKey) -> Option<Vec<tar::TarEntry>> {
+    let listing = TAR_LISTING.lock().ok()?;
+    listing
+        .as_ref()
+        .filter(|(cached, _)| cached == key)
+        .map(|(_, entries)| entries.clone())
+}
+
+fn list_tar(
+    path: &str,
+    compression: Option<compress::Compression>,
+) -> Result<Vec<tar::TarEntry>, ImageError> {
+    let key = file_key(path)?;
+    if let Some(entries) = cached_tar_listing(&key) {
+        return Ok(entries);
+    }
+
+    let mut walker = tar::TarWalker::new(open_tar_input(path, compression)?);
+    let mut entries = Vec::new();
+    while let Some(entry) = walker.next_file()? {
+        entries.push(entry);
+    }
+    if let Ok(mut listing) = TAR_LISTING.lock() {
+        *listing = Some((key, entries.clone()));
+    }
+    Ok(entries)
+}
+
+fn open_tar_entry(
+    path: &str,
+    compression: Option<compress::Compression>,
+    file_size: u64,
+    entry: Option<&str>,
+) -> Result<ImageSource, ImageError> {
+    let input = open_tar_input(path, compression)?;
+    let (found, reader) = match (entry, cached_tar_listing(&file_key(path)?)) {
+        // A requested entry is found in a single pass, up to where it is
+        (Some(name), None) => {
+            let mut walker = tar::TarWalker::new(input);
+            let found = walker
+                .find(name)?
+                .ok_or_else(|| ImageError::EntryNotFound(name.to_string()))?;
+            let reader = walker.into_reader(&found);
+            (found, reader)
+        }
+        // Finding the only image needs the whole listing, which tar has no
+        // index for; once listed, entries are skipped to directly
+        (entry, cached) => {
+            let entries = match cached {
+                Some(entries) => entries,
+                None => list_tar(path, compression)?,
+            };
+            let found = select_entry(&entries, |e| e.name.as_str(), entry)?.clone();
+            let reader = tar::TarWalker::open_entry(input, &found)?;
+            (found, reader)
+        }
+    };
+    let reader: Box<dyn Read + Send> = Box::new(reader);
+    let mut source = ImageSource::new(reader, file_size, Some(ImageSize::exact(found.size)));
+    source.container = Some(match compression {
+        Some(compression) => format!("tar ({})", compression.name()),
+        None => "tar".to_string(),
+    });
+    if let Some(compression) = compression {
+        source.truncated = compression
+            .ends_cleanly(&mut File::open(path)?)?
+            .map(|clean| !clean);
+    }
+    source.entry = Some(found.name);
+    Ok(source)
+}
+
+fn disk_source<D: VirtualDisk + 'static>(disk: D, format: &str, file_size: u64) -> ImageSource {
+    let size = Some(ImageSize::exact(disk.size()));
+    let warnings = disk.warnings().to_vec();
+    let mut source = ImageSource::new(disk::DiskReader::new(disk), file_size, size);
+    source.disk_format = Some(format.to_string());
+    source.warnings = warnings;
+    source
+}
+
+/// Names the virtual disk format that starts with `magic`, if any.
+fn virtual_disk_format(magic: &[u8]) -> Option<&'static str> {
+    if qcow2::is_qcow2(magic) {
+        Some("qcow2")
+    } else if vhdx::is_vhdx(magic) {
+        Some("VHDX")
+    } else if vhd::is_dynamic_vhd(magic) {
+        Some("VHD")
+    } else if vmdk::is_sparse_vmdk(magic) {
+        Some("VMDK")
+    } else {
+        None
+    }
+}
+
+/// Lists the disk image entries of an archive. Returns an empty list for
+/// files that are not archives.
+pub fn list_archive_entries(path: &str) -> Result<Vec<ArchiveEntry>, ImageError> {
+    let mut file = File::open(path)?;
+
+    let mut magic = [0u8; 16];
+    let magic_len = read_up_to(&mut file, &mut magic)?;
+    let magic = &magic[..magic_len];
+    file.seek(SeekFrom::Start(0))?;
+
+    let entries: Vec<ArchiveEntry> = if zip::is_zip(magic) {
+        zip::list_entries(&mut file)?
+            .into_iter()
+            .map(|e| ArchiveEntry {
+                name: e.name,
+                size: e.size,
+            })
+            .collect()
+    } else {
+        let compression = compress::detect(magic);
+        if !is_tar_file(path, compression)? {
+            return Ok(Vec::new());
+        }
+        list_tar(path, compression)?
+            .into_iter()
+            .map(|e| ArchiveEntry {
+                name: e.name,
+                size: e.size,
+            })
+            .collect()
+    };
+
+    Ok(entries
+        .into_iter()
+        .filter(|e| is_image_name(&e.name))
+        .collect())
+}
+
+fn is_image_name(name: &str) -> bool {
+    if name.ends_with('/') {
+        return false;
+    }
+    Path::new(name)
+        .extension()
+        .map(|ext| {
+            let ext = ext.to_string_lossy().to_lowercase();
+            IMAGE_EXTENSIONS.contains(&ext.as_str())
+        })
+        .unwrap_or(false)
+}
+
+/// Picks the requested entry, or the only disk image when none is requested.
+fn select_entry<'a, T>(
+    entries: &'a [T],
+    name_of: impl Fn(&T) -> &str,
+    requested: Option<&str>,
+) -> Result<&'a T, ImageError> {
+    if let Some(requested) = requested {
+        return entries
+            .iter()
+            .find(|e| name_of(e) == requested)
+            .ok_or_else(|| ImageError::EntryNotFound(requested.to_string()));
+    }
+
+    let mut images = entries.iter().filter(|e| is_image_name(name_of(e)));
+    match (images.next(), images.count()) {
+        (None, _) => Err(ImageError::NoImageEntry),
+        (Some(only), 0) => Ok(only),
+        (Some(_), others) => Err(ImageError::AmbiguousEntry(others + 1)),
+    }
+}
+
+/// Returns up to `len` leading bytes of the decoded stream without consuming them.
+fn peek(source: &mut ImageSource, len: usize) -> io::Result<Vec<u8>> {
+    let mut head = vec![0u8; len];
+    let head_len = read_up_to(&mut source.reader, &mut head)?;
+    head.truncate(head_len);
+
+    let rest = std::mem::replace(&mut source.reader, Box::new(io::empty()));
+    source.reader = Box::new(Cursor::new(head.clone()).chain(rest));
+    Ok(head)
+}
+
+/// Fills `buf` as far as the reader allows, returning the number of bytes read.
+pub fn read_up_to<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
@@ -0,0 +1,252 @@
+use super::bytes::{be_u32, be_u64};
+use super::disk::{read_exact_at, VirtualDisk};
+use super::ImageError;
+use flate2::read::DeflateDecoder;
+use ruzstd::decoding::StreamingDecoder;
+use std::fs::File;
+use std::io::{self, Read};
+
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+const V2_HEADER_LEN: usize = 72;
+
+/// Offset bits of L1 and uncompressed L2 entries
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_COMPRESSED: u64 = 1 << 62;
+/// v3 only: the cluster reads as zeros whatever it points at
+const L2_ZERO: u64 = 1;
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_DATA_FILE: u64 = 1 << 2;
+const INCOMPAT_COMPRESSION: u64 = 1 << 3;
+const INCOMPAT_EXTENDED_L2: u64 = 1 << 4;
+
+pub fn is_qcow2(magic: &[u8]) -> bool {
+    magic.starts_with(QCOW2_MAGIC)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClusterCompression {
+    Zlib,
+    Zstd,
+}
+
+/// A qcow2 (version 2 or 3) image without a backing file.
+pub struct Qcow2 {
+    file: File,
+    version: u32,
+    size: u64,
+    cluster_bits: u32,
+    compression: ClusterCompression,
+    l1: Vec<u64>,
+    /// Most recently used L2 table, by its offset in the file
+    l2_cache: Option<(u64, Vec<u64>)>,
+    /// Most recently decompressed cluster, by its index in the disk
+    cluster_cache: Option<(u64, Vec<u8>)>,
+}
+
+impl Qcow2 {
+    pub fn open(mut file: File) -> Result<Self, ImageError> {
+        let file_size = file.metadata()?.len();
+        let mut header = [0u8; 112];
+        let header_len = super::read_up_to(&mut file, &mut header)?;
+        if header_len < V2_HEADER_LEN || !is_qcow2(&header) {
+            return Err(corrupt("file header is truncated"));
+        }
+
+        let version = be_u32(&header, 4);
+        if version != 2 && version != 3 {
+            return Err(ImageError::Unsupported(format!(
+                "qcow2 version {}",
+                version
+            )));
+        }
+        if be_u64(&header, 8) != 0 {
+            return Err(ImageError::Unsupported(
+                "qcow2 images with a backing file; convert or rebase the image first".to_string(),
+            ));
+        }
+        let cluster_bits = be_u32(&header, 20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(corrupt(&format!("invalid cluster size 2^{}", cluster_bits)));
+        }
+        if be_u32(&header, 32) != 0 {
+            return Err(ImageError::Unsupported(
+                "encrypted qcow2 images".to_string(),
+            ));
+        }
+
+        let mut compression = ClusterCompression::Zlib;
+        if version == 3 {
+            if header_len < 104 {
+                return Err(corrupt("file header is truncated"));
+            }
+            let incompatible = be_u64(&header, 72);
+            if incompatible & INCOMPAT_CORRUPT != 0 {
+                return Err(corrupt("the image is marked as corrupt"));
+            }
+            if incompatible & INCOMPAT_DATA_FILE != 0 {
+                return Err(ImageError::Unsupported(
+                    "qcow2 images with an external data file".to_string(),
+                ));
+            }
+            if incompatible & INCOMPAT_EXTENDED_L2 != 0 {
+                return Err(ImageError::Unsupported(
+                    "qcow2 images with extended L2 entries".to_string(),
+                ));
+            }
+            let known = INCOMPAT_DIRTY | INCOMPAT_CORRUPT | INCOMPAT_COMPRESSION;
+            if incompatible & !known != 0 {
+                return Err(ImageError::Unsupported(format!(
+                    "qcow2 incompatible features {:#x}",
+                    incompatible & !known
+                )));
+            }
+            if incompatible & INCOMPAT_COMPRESSION != 0 {
+                let header_length = be_u32(&header, 100) as usize;
+                if header_length <= 104 || header_len <= 104 {
+                    return Err(corrupt("compression type is missing from the header"));
+                }
+                compression = match header[104] {
+                    0 => ClusterCompression::Zlib,
+                    1 => ClusterCompression::Zstd,
+                    other => {
+                        return Err(ImageError::Unsupported(format!(
+                            "qcow2 compression type {}",
+                            other
+                        )))
+                    }
+                };
+            }
+        }
+
+        let size = be_u64(&header, 24);
+        let l1_size = be_u32(&header, 36) as u64;
+        let l1_offset = be_u64(&header, 40);
+
+        let cluster_size = 1u64 << cluster_bits;
+        let bytes_per_l2 = cluster_size * (cluster_size / 8);
+        if l1_size < size.div_ceil(bytes_per_l2) {
+            return Err(corrupt("L1 table is too small for the virtual size"));
+        }
+        if l1_offset.saturating_add(l1_size * 8) > file_size {
+            return Err(corrupt("L1 table lies beyond the end of the file"));
+        }
+
+        let mut table = vec![0u8; (l1_size * 8) as usize];
+        read_exact_at(&mut file, l1_offset, &mut table, "qcow2")?;
+        let l1 = table.chunks_exact(8).map(|e| be_u64(e, 0)).collect();
+
+        Ok(Self {
+            file,
+            version,
+            size,
+            cluster_bits,
+            compression,
+            l1,
+            l2_cache: None,
+            cluster_cache: None,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// L2 entry for a guest cluster, 0 when no L2 table covers it.
+    fn l2_entry(&mut self, cluster: u64) -> io::Result<u64> {
+        let entries_per_table = self.cluster_size() / 8;
+        let l1_entry = match self.l1.get((cluster / entries_per_table) as usize) {
+            Some(entry) => entry & OFFSET_MASK,
+            None => return Ok(0),
+        };
+        if l1_entry == 0 {
+            return Ok(0);
+        }
+
+        if self.l2_cache.as_ref().map(|(offset, _)| *offset) != Some(l1_entry) {
+            let mut table = vec![0u8; self.cluster_size() as usize];
+            read_exact_at(&mut self.file, l1_entry, &mut table, "qcow2")?;
+            let entries = table.chunks_exact(8).map(|e| be_u64(e, 0)).collect();
+            self.l2_cache = Some((l1_entry, entries));
+        }
+        let (_, table) = self.l2_cache.as_ref().unwrap();
+        Ok(table[(cluster % entries_per_table) as usize])
+    }
+
+    fn load_compressed(&mut self, cluster: u64, entry: u64) -> io::Result<&[u8]> {
+        if self.cluster_cache.as_ref().map(|(index, _)| *index) != Some(cluster) {
+            // The split between offset and sector count depends on the cluster size
+            let offset_bits = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << offset_bits) - 1);
+            let sectors = ((entry & !L2_COMPRESSED) >> offset_bits) + 1;
+            let compressed_len = sectors * 512 - (offset & 511);
+
+            let mut compressed = vec![0u8; compressed_len as usize];
+            let file_size = self.file.metadata()?.len();
+            // The last compressed cluster may be cut short of its sector count
+            let available = file_size.saturating_sub(offset).min(compressed_len);
+            read_exact_at(
+                &mut self.file,
+                offset,
+                &mut compressed[..available as usize],
+                "qcow2",
+            )?;
+
+            let mut data = vec![0u8; self.cluster_size() as usize];
+            let result = match self.compression {
+                ClusterCompression::Zlib => {
+                    DeflateDecoder::new(&compressed[..]).read_exact(&mut data)
+                }
+                ClusterCompression::Zstd => StreamingDecoder::new(&compressed[..])
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+                    .and_then(|mut decoder| decoder.read_exact(&mut data)),
+            };
+            result.map_err(|e| {
+                invalid(&format!("compressed cluster {} is damaged: {}", cluster, e))
+            })?;
+            self.cluster_cache = Some((cluster, data));
+        }
+        Ok(&self.cluster_cache.as_ref().unwrap().1)
+    }
+}
+
+impl VirtualDisk for Qcow2 {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let cluster = pos >> self.cluster_bits;
+        let in_cluster = pos & (self.cluster_size() - 1);
+        let len = buf.len().min((self.cluster_size() - in_cluster) as usize);
+        let buf = &mut buf[..len];
+
+        let entry = self.l2_entry(cluster)?;
+        if entry & L2_COMPRESSED != 0 {
+            let data = self.load_compressed(cluster, entry)?;
+            buf.copy_from_slice(&data[in_cluster as usize..in_cluster as usize + len]);
+            return Ok(len);
+        }
+
+        let host = entry & OFFSET_MASK;
+        if host == 0 || (self.version == 3 && entry & L2_ZERO != 0) {
+            // Unallocated and zeroed clusters read as zeros
+            buf.fill(0);
+            return Ok(len);
+        }
+        read_exact_at(&mut self.file, host + in_cluster, buf, "qcow2")?;
+        Ok(len)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("qcow2 image: {}", message),
+    )
+}
+
+fn corrupt(message: &str) -> ImageError {
+    ImageError::Corrupt(format!("qcow2 image: {}", message))
+}
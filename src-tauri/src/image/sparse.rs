@@ -0,0 +1,312 @@
+use super::bytes::{le_u16, le_u32};
+use super::{ImageError, ImageRead};
+use crc32fast::Hasher;
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub const HEADER_LEN: usize = 28;
+const SPARSE_MAGIC: u32 = 0xED26_FF3A;
+const CHUNK_HEADER_LEN: usize = 12;
+
+const CHUNK_RAW: u16 = 0xCAC1;
+const CHUNK_FILL: u16 = 0xCAC2;
+const CHUNK_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_CRC32: u16 = 0xCAC4;
+
+static ZEROS: [u8; 64 * 1024] = [0u8; 64 * 1024];
+
+/// Android sparse image file header (libsparse format 1.0).
+#[derive(Debug, Clone, Copy)]
+pub struct SparseHeader {
+    header_len: u64,
+    chunk_header_len: u64,
+    block_size: u64,
+    total_blocks: u64,
+    total_chunks: u32,
+    image_checksum: u32,
+}
+
+impl SparseHeader {
+    pub fn image_size(&self) -> u64 {
+        self.total_blocks * self.block_size
+    }
+}
+
+pub fn parse_header(buf: &[u8]) -> Result<Option<SparseHeader>, ImageError> {
+    if buf.len() < HEADER_LEN || le_u32(buf, 0) != SPARSE_MAGIC {
+        return Ok(None);
+    }
+    if le_u16(buf, 4) != 1 {
+        return Err(ImageError::Unsupported(format!(
+            "Android sparse image version {}.{}",
+            le_u16(buf, 4),
+            le_u16(buf, 6)
+        )));
+    }
+
+    let header = SparseHeader {
+        header_len: le_u16(buf, 8) as u64,
+        chunk_header_len: le_u16(buf, 10) as u64,
+        block_size: le_u32(buf, 12) as u64,
+        total_blocks: le_u32(buf, 16) as u64,
+        total_chunks: le_u32(buf, 20),
+        image_checksum: le_u32(buf, 24),
+    };
+    if header.header_len < HEADER_LEN as u64
+        || header.chunk_header_len < CHUNK_HEADER_LEN as u64
+        || header.block_size == 0
+        || !header.block_size.is_multiple_of(4)
+    {
+        return Err(corrupt("invalid file header"));
+    }
+    Ok(Some(header))
+}
+
+/// Walks the chunk headers of a sparse image file and adds up the bytes
+/// that are not DONT_CARE, without reading any chunk data.
+pub fn mapped_bytes<F: Read + Seek>(
+    file: &mut F,
+    header: &SparseHeader,
+) -> Result<u64, ImageError> {
+    let mut pos = header.header_len;
+    let mut mapped = 0u64;
+    for _ in 0..header.total_chunks {
+        let mut chunk = [0u8; CHUNK_HEADER_LEN];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut chunk).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => corrupt("file is truncated"),
+            _ => ImageError::Io(e),
+        })?;
+        let chunk_type = le_u16(&chunk, 0);
+        if chunk_type != CHUNK_DONT_CARE && chunk_type != CHUNK_CRC32 {
+            mapped += le_u32(&chunk, 4) as u64 * header.block_size;
+        }
+        pos += le_u32(&chunk, 8) as u64;
+    }
+    Ok(mapped)
+}
+
+enum Chunk {
+    Raw(u64),
+    Fill([u8; 4], u64),
+    DontCare(u64),
+    End,
+}
+
+/// Expands the chunks of a sparse image into the raw image. DONT_CARE chunks
+/// are holes, and CRC32 chunks are checked against everything expanded so far.
+pub struct SparseReader {
+    inner: Box<dyn ImageRead>,
+    header: SparseHeader,
+    chunks_left: u32,
+    chunk: Chunk,
+    /// Offset of the expanded output
+    pos: u64,
+    crc: Hasher,
+}
+
+impl SparseReader {
+    /// `inner` must be positioned just past the 28-byte file header.
+    pub fn new(inner: Box<dyn ImageRead>, header: SparseHeader) -> io::Result<Self> {
+        let mut reader = Self {
+            inner,
+            header,
+            chunks_left: header.total_chunks,
+            chunk: Chunk::End,
+            pos: 0,
+            crc: Hasher::new(),
+        };
+        reader.skip_input(header.header_len - HEADER_LEN as u64)?;
+        reader.next_chunk()?;
+        Ok(reader)
+    }
+
+    /// Loads the next chunk that produces output, checking any CRC32 chunks
+    /// on the way so holes are always known before the caller reads.
+    fn next_chunk(&mut self) -> io::Result<()> {
+        loop {
+            if self.chunks_left == 0 {
+                return self.finish();
+            }
+            self.chunks_left -= 1;
+
+            let mut chunk = [0u8; CHUNK_HEADER_LEN];
+            self.inner.read_exact(&mut chunk).map_err(truncated)?;
+            self.skip_input(self.header.chunk_header_len - CHUNK_HEADER_LEN as u64)?;
+
+            let chunk_type = le_u16(&chunk, 0);
+            let blocks = le_u32(&chunk, 4) as u64;
+            let total_len = le_u32(&chunk, 8) as u64;
+            let data_len = total_len
+                .checked_sub(self.header.chunk_header_len)
+                .ok_or_else(|| invalid("chunk is smaller than its header"))?;
+            let out_len = blocks * self.header.block_size;
+
+            match chunk_type {
+                CHUNK_RAW => {
+                    if data_len != out_len {
+                        return Err(invalid("RAW chunk size does not match its block count"));
+                    }
+                    self.chunk = Chunk::Raw(out_len);
+                }
+                CHUNK_FILL => {
+                    if data_len != 4 {
+                        return Err(invalid("FILL chunk must carry a 4-byte pattern"));
+                    }
+                    let mut pattern = [0u8; 4];
+                    self.inner.read_exact(&mut pattern).map_err(truncated)?;
+                    self.chunk = Chunk::Fill(pattern, out_len);
+                }
+                CHUNK_DONT_CARE => {
+                    if data_len != 0 {
+                        return Err(invalid("DONT_CARE chunk must not carry data"));
+                    }
+                    self.chunk = Chunk::DontCare(out_len);
+                }
+                CHUNK_CRC32 => {
+                    if data_len != 4 {
+                        return Err(invalid("CRC32 chunk must carry a 4-byte checksum"));
+                    }
+                    let mut expected = [0u8; 4];
+                    self.inner.read_exact(&mut expected).map_err(truncated)?;
+                    let actual = self.crc.clone().finalize();
+                    if actual != le_u32(&expected, 0) {
+                        return Err(invalid(&format!(
+                            "CRC32 mismatch at block {}",
+                            self.pos / self.header.block_size
+                        )));
+                    }
+                    continue;
+                }
+                other => {
+                    return Err(invalid(&format!("unknown chunk type {:#06x}", other)));
+                }
+            }
+
+            if out_len > 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.chunk = Chunk::End;
+        if self.pos != self.header.image_size() {
+            return Err(invalid(&format!(
+                "chunks expand to {} bytes, header says {}",
+                self.pos,
+                self.header.image_size()
+            )));
+        }
+        if self.header.image_checksum != 0
+            && self.crc.clone().finalize() != self.header.image_checksum
+        {
+            return Err(invalid("image checksum mismatch"));
+        }
+        Ok(())
+    }
+
+    fn skip_input(&mut self, len: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.inner).take(len), &mut io::sink())?;
+        if skipped < len {
+            return Err(truncated(io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(())
+    }
+
+    fn consume(&mut self, len: u64) -> io::Result<()> {
+        self.pos += len;
+        let remaining = match &mut self.chunk {
+            Chunk::Raw(left) | Chunk::Fill(_, left) | Chunk::DontCare(left) => {
+                *left -= len;
+                *left
+            }
+            Chunk::End => return Ok(()),
+        };
+        if remaining == 0 {
+            self.next_chunk()?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.chunk {
+            Chunk::End => return Ok(0),
+            Chunk::Raw(left) => {
+                let len = buf.len().min(left as usize);
+                let n = self.inner.read(&mut buf[..len])?;
+                if n == 0 && len > 0 {
+                    return Err(truncated(io::ErrorKind::UnexpectedEof.into()));
+                }
+                n
+            }
+            Chunk::Fill(pattern, left) => {
+                // Chunks start on block boundaries, so the pattern phase
+                // follows the output offset
+                let len = buf.len().min(left as usize);
+                let mut rotated = pattern;
+                rotated.rotate_left((self.pos % 4) as usize);
+                for word in buf[..len].chunks_mut(4) {
+                    word.copy_from_slice(&rotated[..word.len()]);
+                }
+                len
+            }
+            Chunk::DontCare(left) => {
+                let len = buf.len().min(left as usize);
+                buf[..len].fill(0);
+                len
+            }
+        };
+        self.crc.update(&buf[..n]);
+        self.consume(n as u64)?;
+        Ok(n)
+    }
+}
+
+impl ImageRead for SparseReader {
+    fn hole_ahead(&self) -> u64 {
+        match self.chunk {
+            Chunk::DontCare(left) => left,
+            _ => 0,
+        }
+    }
+
+    fn skip_hole(&mut self) -> io::Result<u64> {
+        let len = self.hole_ahead();
+        if len == 0 {
+            return Ok(0);
+        }
+        // The running CRC covers DONT_CARE regions as zeros
+        let mut left = len;
+        while left > 0 {
+            let step = left.min(ZEROS.len() as u64) as usize;
+            self.crc.update(&ZEROS[..step]);
+            left -= step as u64;
+        }
+        self.consume(len)?;
+        Ok(len)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Android sparse image: {}", message),
+    )
+}
+
+fn truncated(e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Android sparse image is truncated",
+        )
+    } else {
+        e
+    }
+}
+
+fn corrupt(message: &str) -> ImageError {
+    ImageError::Corrupt(format!("Android sparse image: {}", message))
+}
@@ -0,0 +1,286 @@
+use super::ImageError;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Suffix styles `split` and file archivers use for the parts of a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Numbering {
+    /// `image.iso.001`, `image.iso.002`, ... (some tools start at `.000`)
+    Digits { width: usize },
+    /// `xaa`, `xab`, ..., `xba`, ...
+    Letters { width: usize },
+}
+
+impl Numbering {
+    fn suffix(self, index: u64) -> String {
+        match self {
+            Numbering::Digits { width } => format!(".{:0width$}", index, width = width),
+            Numbering::Letters { width } => {
+                let mut letters = vec![b'a'; width];
+                let mut rest = index;
+                for letter in letters.iter_mut().rev() {
+                    *letter = b'a' + (rest % 26) as u8;
+                    rest /= 26;
+                }
+                String::from_utf8(letters).unwrap()
+            }
+        }
+    }
+
+    /// Index of a part from the suffix following the shared prefix.
+    fn index(self, suffix: &str) -> Option<u64> {
+        match self {
+            Numbering::Digits { width } => {
+                let digits = suffix.strip_prefix('.')?;
+                if digits.len() != width || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                digits.parse().ok()
+            }
+            Numbering::Letters { width } => {
+                if suffix.len() != width || !suffix.bytes().all(|b| b.is_ascii_lowercase()) {
+                    return None;
+                }
+                Some(
+                    suffix
+                        .bytes()
+                        .fold(0u64, |index, b| index * 26 + (b - b'a') as u64),
+                )
+            }
+        }
+    }
+}
+
+/// Finds the parts of a split image when `path` is its first part. Returns
+/// `None` for ordinary files, numbered ones outside the run of parts
+/// included, and an error when parts are missing or cut short, or when
+/// `path` is a later part.
+pub fn find_parts(path: &Path) -> Result<Option<Vec<(PathBuf, u64)>>, ImageError> {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Ok(None),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let (prefix, numbering) = match detect(&dir, &name) {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    let mut indices: Vec<u64> = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            numbering.index(name.strip_prefix(&prefix)?)
+        })
+        .collect();
+    indices.sort_unstable();
+
+    // Numbered parts start at 1 or 0, lettered ones always at "aa"
+    let first = match numbering {
+        Numbering::Digits { .. } => indices[0].min(1),
+        Numbering::Letters { .. } => 0,
+    };
+    // The image is the unbroken run of parts from the first one
+    let mut end = first;
+    while indices.binary_search(&end).is_ok() {
+        end += 1;
+    }
+
+    let mut parts = Vec::new();
+    for index in first..end {
+        let part = dir.join(format!("{}{}", prefix, numbering.suffix(index)));
+        let size = std::fs::metadata(&part)?.len();
+        parts.push((part, size));
+    }
+
+    // A short part ends the image, so a numbered file after a gap is
+    // unrelated to it
+    let part_size = parts[0].1;
+    let own = numbering.index(&name[prefix.len()..]).unwrap();
+    if own >= end && parts.last().unwrap().1 < part_size {
+        return Ok(None);
+    }
+
+    // Every part but the last is as long as the first
+    for (part, size) in &parts[..parts.len() - 1] {
+        if *size != part_size {
+            return Err(corrupt(&format!(
+                "part {} is {} bytes, but the first part is {}",
+                part.file_name().unwrap_or_default().to_string_lossy(),
+                size,
+                part_size
+            )));
+        }
+    }
+    if parts.last().unwrap().1 > part_size {
+        return Err(corrupt("the last part is longer than the others"));
+    }
+
+    // After a full-size part, a gap is a missing part
+    if indices.last().is_some_and(|&last| last > end) && parts.last().unwrap().1 == part_size {
+        return Err(corrupt(&format!(
+            "part {}{} is missing",
+            prefix,
+            numbering.suffix(end)
+        )));
+    }
+
+    if own != first {
+        return Err(ImageError::Unsupported(format!(
+            "{} is part {} of a split image; select the first part, {}{}",
+            name,
+            own - first + 1,
+            prefix,
+            numbering.suffix(first)
+        )));
+    }
+    Ok(Some(parts))
+}
+
+/// Works out the prefix and numbering from one part's name. Only counts as a
+/// split image if the first two parts exist, so lone files are left alone.
+fn detect(dir: &Path, name: &str) -> Option<(String, Numbering)> {
+    let exists = |prefix: &str, numbering: Numbering, index: u64| {
+        dir.join(format!("{}{}", prefix, numbering.suffix(index)))
+            .is_file()
+    };
+
+    if let Some((prefix, digits)) = name.rsplit_once('.') {
+        let numbering = Numbering::Digits {
+            width: digits.len(),
+        };
+        // Needs the first two parts, from .000 or .001, so that unrelated
+        // numbered files are left alone
+        if digits.len() >= 3
+            && !prefix.is_empty()
+            && numbering.index(&name[prefix.len()..]).is_some()
+        {
+            let first = if exists(prefix, numbering, 0) { 0 } else { 1 };
+            if exists(prefix, numbering, first) && exists(prefix, numbering, first + 1) {
+                return Some((prefix.to_string(), numbering));
+            }
+        }
+    }
+
+    let letters = name
+        .bytes()
+        .rev()
+        .take_while(|b| b.is_ascii_lowercase())
+        .count();
+    for width in 2..=letters.min(4) {
+        let prefix = &name[..name.len() - width];
+        let numbering = Numbering::Letters { width };
+        // Needs "aa" and "ab" as well, or any name ending in letters would match
+        if exists(prefix, numbering, 0) && exists(prefix, numbering, 1) {
+            return Some((prefix.to_string(), numbering));
+        }
+    }
+    None
+}
+
+/// Files read back to back as one: the parts of a split image, or just a
+/// single ordinary file.
+pub struct SplitReader {
+    parts: Vec<(PathBuf, u64)>,
+    current: usize,
+    file: Option<File>,
+    /// Offset into the current part
+    offset: u64,
+}
+
+impl SplitReader {
+    pub fn new(parts: Vec<(PathBuf, u64)>) -> Self {
+        Self {
+            parts,
+            current: 0,
+            file: None,
+            offset: 0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.parts.iter().map(|(_, size)| size).sum()
+    }
+
+    pub fn part_count(&self) -> usize {
+        self.parts.len()
+    }
+}
+
+impl Read for SplitReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let (path, size) = match self.parts.get(self.current) {
+                Some(part) => part,
+                None => return Ok(0),
+            };
+            if self.offset >= *size {
+                self.current += 1;
+                self.offset = 0;
+                self.file = None;
+                continue;
+            }
+
+            if self.file.is_none() {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(self.offset))?;
+                self.file = Some(file);
+            }
+            let len = buf
+                .len()
+                .min((size - self.offset).min(usize::MAX as u64) as usize);
+            let n = self.file.as_mut().unwrap().read(&mut buf[..len])?;
+            if n == 0 && len > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "split image part {} ended after {} of {} bytes",
+                        path.file_name().unwrap_or_default().to_string_lossy(),
+                        self.offset,
+                        size
+                    ),
+                ));
+            }
+            self.offset += n as u64;
+            return Ok(n);
+        }
+    }
+}
+
+impl Seek for SplitReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let current = self.parts[..self.current.min(self.parts.len())]
+            .iter()
+            .map(|(_, size)| size)
+            .sum::<u64>()
+            + self.offset;
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.size().checked_add_signed(delta),
+            SeekFrom::Current(delta) => current.checked_add_signed(delta),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))?;
+
+        let mut rest = target;
+        self.current = 0;
+        while let Some((_, size)) = self.parts.get(self.current) {
+            if rest < *size {
+                break;
+            }
+            rest -= size;
+            self.current += 1;
+        }
+        self.offset = rest;
+        self.file = None;
+        Ok(target)
+    }
+}
+
+fn corrupt(message: &str) -> ImageError {
+    ImageError::Corrupt(format!("split image: {}", message))
+}
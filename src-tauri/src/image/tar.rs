@@ -0,0 +1,308 @@
+use super::ImageError;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub const BLOCK_LEN: usize = 512;
+/// Upper bound for pax and GNU long-name records, which are read into memory
+const MAX_META_LEN: u64 = 1024 * 1024;
+
+/// Checks for a tar header block. The header checksum is the only marker
+/// pre-POSIX archives have, so it is what decides.
+pub fn is_tar(block: &[u8]) -> bool {
+    block.len() >= BLOCK_LEN
+        && block[..BLOCK_LEN].iter().any(|&b| b != 0)
+        && checksum_matches(block)
+}
+
+fn checksum_matches(block: &[u8]) -> bool {
+    let stored = match parse_octal(&block[148..156]) {
+        Some(value) => value,
+        None => return false,
+    };
+    // The checksum field counts as spaces; some old tars summed signed bytes
+    let unsigned: u64 = block[..BLOCK_LEN]
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+        .sum();
+    let signed: i64 = block[..BLOCK_LEN]
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as i8 } else { b as i8 } as i64)
+        .sum();
+    stored == unsigned || stored as i64 == signed
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let text = std::str::from_utf8(field).ok()?;
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(text, 8).ok()
+}
+
+/// Entry sizes are octal, or big-endian binary when the top bit is set (GNU).
+fn parse_size(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut value = (field[0] & 0x7F) as u64;
+        for &b in &field[1..] {
+            value = value.checked_mul(256)?.checked_add(b as u64)?;
+        }
+        return Some(value);
+    }
+    parse_octal(field)
+}
+
+fn field_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Where the tar comes from: a plain file can seek past entries, a
+/// decompressed stream has to read through them.
+pub enum TarInput {
+    File(File),
+    Stream(Box<dyn Read + Send>),
+}
+
+impl TarInput {
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        match self {
+            TarInput::File(file) => {
+                let pos = file.seek(SeekFrom::Current(len as i64))?;
+                if pos > file.metadata()?.len() {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(())
+            }
+            TarInput::Stream(stream) => {
+                let skipped = io::copy(&mut stream.take(len), &mut io::sink())?;
+                if skipped < len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Read for TarInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TarInput::File(file) => file.read(buf),
+            TarInput::Stream(stream) => stream.read(buf),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TarEntry {
+    pub name: String,
+    pub size: u64,
+    /// Where the entry's data starts in the tar stream
+    pub offset: u64,
+}
+
+/// Walks the headers of a tar stream, yielding its regular files.
+pub struct TarWalker {
+    input: TarInput,
+    /// Data and padding of the last returned entry still to be skipped
+    pending: u64,
+    /// Bytes of the tar stream consumed so far
+    pos: u64,
+}
+
+impl TarWalker {
+    pub fn new(input: TarInput) -> Self {
+        Self {
+            input,
+            pending: 0,
+            pos: 0,
+        }
+    }
+
+    pub fn next_file(&mut self) -> Result<Option<TarEntry>, ImageError> {
+        let mut long_name = None;
+        let mut pax_path = None;
+        let mut pax_size = None;
+
+        loop {
+            let pending = std::mem::take(&mut self.pending);
+            self.input.skip(pending).map_err(truncated)?;
+            self.pos += pending;
+
+            let mut block = [0u8; BLOCK_LEN];
+            let filled = super::read_up_to(&mut self.input, &mut block)?;
+            self.pos += filled as u64;
+            if filled == 0 || block.iter().all(|&b| b == 0) {
+                // End-of-archive marker, or an archive without one
+                return Ok(None);
+            }
+            if filled < BLOCK_LEN {
+                return Err(truncated(io::ErrorKind::UnexpectedEof.into()));
+            }
+            if !checksum_matches(&block) {
+                return Err(corrupt("header checksum mismatch"));
+            }
+
+            let size = parse_size(&block[124..136]).ok_or_else(|| corrupt("invalid entry size"))?;
+            let padded = size.next_multiple_of(BLOCK_LEN as u64);
+            match block[156] {
+                b'x' | b'L' => {
+                    let data = self.read_meta(size)?;
+                    self.input.skip(padded - size).map_err(truncated)?;
+                    self.pos += padded;
+                    if block[156] == b'L' {
+                        long_name = Some(field_str(&data));
+                    } else {
+                        for (key, value) in parse_pax(&data) {
+                            match key.as_str() {
+                                "path" => pax_path = Some(value),
+                                "size" => pax_size = value.parse().ok(),
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                b'0' | b'\0' | b'7' => {
+                    let size = pax_size.unwrap_or(size);
+                    let name = pax_path
+                        .or(long_name)
+                        .unwrap_or_else(|| header_name(&block));
+                    self.pending = size.next_multiple_of(BLOCK_LEN as u64);
+                    return Ok(Some(TarEntry {
+                        name,
+                        size,
+                        offset: self.pos,
+                    }));
+                }
+                _ => {
+                    // Directories, links, global pax headers and the like
+                    self.pending = padded;
+                    long_name = None;
+                    pax_path = None;
+                    pax_size = None;
+                }
+            }
+        }
+    }
+
+    /// Walks forward to the regular file called `name`.
+    pub fn find(&mut self, name: &str) -> Result<Option<TarEntry>, ImageError> {
+        while let Some(entry) = self.next_file()? {
+            if entry.name == name {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the data of the entry `next_file` or `find` just returned.
+    pub fn into_reader(self, entry: &TarEntry) -> TarEntryReader {
+        TarEntryReader {
+            input: self.input,
+            left: entry.size,
+            read: 0,
+        }
+    }
+
+    /// Reads the data of an entry found by an earlier walk of the same
+    /// archive, skipping to it without going through the headers again.
+    pub fn open_entry(mut input: TarInput, entry: &TarEntry) -> Result<TarEntryReader, ImageError> {
+        input.skip(entry.offset).map_err(truncated)?;
+        Ok(TarEntryReader {
+            input,
+            left: entry.size,
+            read: 0,
+        })
+    }
+
+    fn read_meta(&mut self, size: u64) -> Result<Vec<u8>, ImageError> {
+        if size > MAX_META_LEN {
+            return Err(corrupt("extended header is too large"));
+        }
+        let mut data = vec![0u8; size as usize];
+        self.input.read_exact(&mut data).map_err(truncated)?;
+        Ok(data)
+    }
+}
+
+/// Name from the header itself, joined with the ustar prefix when present.
+fn header_name(block: &[u8]) -> String {
+    let name = field_str(&block[0..100]);
+    // GNU tar uses the prefix area for other fields
+    if block[257..263] == *b"ustar\0" {
+        let prefix = field_str(&block[345..500]);
+        if !prefix.is_empty() {
+            return format!("{}/{}", prefix, name);
+        }
+    }
+    name
+}
+
+/// Pax records are "<length> <key>=<value>\n".
+fn parse_pax(data: &[u8]) -> Vec<(String, String)> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = match rest.iter().position(|&b| b == b' ') {
+            Some(space) => space,
+            None => break,
+        };
+        let len: usize = match std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|s| s.parse().ok())
+        {
+            Some(len) if len > space && len <= rest.len() => len,
+            _ => break,
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..len]);
+        if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+            records.push((key.to_string(), value.to_string()));
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+/// The data of a single entry; running out early means the archive is cut short.
+pub struct TarEntryReader {
+    input: TarInput,
+    left: u64,
+    read: u64,
+}
+
+impl Read for TarEntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.left.min(usize::MAX as u64) as usize);
+        let n = self.input.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "tar archive is truncated: entry ended after {} bytes",
+                    self.read
+                ),
+            ));
+        }
+        self.left -= n as u64;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+fn truncated(e: io::Error) -> ImageError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        corrupt("archive is truncated")
+    } else {
+        ImageError::Io(e)
+    }
+}
+
+fn corrupt(message: &str) -> ImageError {
+    ImageError::Corrupt(format!("tar archive: {}", message))
+}
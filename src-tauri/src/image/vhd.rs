@@ -0,0 +1,262 @@
+use super::bytes::{be_u32, be_u64};
+use super::disk::{read_exact_at, VirtualDisk};
+use super::ImageError;
+use std::fs::File;
+use std::io::{self, Read, Seek};
+
+const FOOTER_COOKIE: &[u8] = b"conectix";
+const DYNAMIC_COOKIE: &[u8] = b"cxsparse";
+const FOOTER_LEN: usize = 512;
+const DYNAMIC_HEADER_LEN: usize = 1024;
+const SECTOR: u64 = 512;
+const UNALLOCATED: u32 = 0xFFFF_FFFF;
+
+const DISK_FIXED: u32 = 2;
+const DISK_DYNAMIC: u32 = 3;
+const DISK_DIFFERENCING: u32 = 4;
+
+/// Dynamic and differencing VHDs start with a copy of their footer.
+pub fn is_dynamic_vhd(magic: &[u8]) -> bool {
+    magic.starts_with(FOOTER_COOKIE)
+}
+
+/// The VHD footer, taken from whichever of its copies checks out.
+#[derive(Debug, Clone)]
+pub struct Footer {
+    data_offset: u64,
+    current_size: u64,
+    disk_type: u32,
+    warnings: Vec<String>,
+}
+
+/// Looks for a VHD footer at the end of the file, and for dynamic disks also
+/// the copy at the start. Returns `None` when the file is not a VHD.
+pub fn read_footer<F: Read + Seek>(
+    file: &mut F,
+    file_size: u64,
+) -> Result<Option<Footer>, ImageError> {
+    if file_size < FOOTER_LEN as u64 {
+        return Ok(None);
+    }
+    let mut head = [0u8; FOOTER_LEN];
+    read_exact_at(file, 0, &mut head, "VHD")?;
+    let mut tail = [0u8; FOOTER_LEN];
+    read_exact_at(file, file_size - FOOTER_LEN as u64, &mut tail, "VHD")?;
+
+    // A fixed disk has no copy at the start, so only look there for a cookie
+    let has_head_copy = is_dynamic_vhd(&head);
+    let footer = match (parse_footer(&tail), has_head_copy) {
+        (Ok(None), false) => return Ok(None),
+        (Ok(Some(footer)), false) => footer,
+        (Ok(Some(mut footer)), true) => {
+            if parse_footer(&head).is_err() {
+                footer.warnings.push(
+                    "The copy of the VHD footer at the start of the file is damaged.".to_string(),
+                );
+            } else if head != tail {
+                footer.warnings.push(
+                    "The two copies of the VHD footer differ; using the one at the end of the file."
+                        .to_string(),
+                );
+            }
+            footer
+        }
+        (tail_result, true) => {
+            let mut footer = parse_footer(&head)?.ok_or_else(|| corrupt("footer is missing"))?;
+            footer.warnings.push(match tail_result {
+                Ok(_) => {
+                    "The VHD footer at the end of the file is missing; using the copy at the start."
+                        .to_string()
+                }
+                Err(_) => {
+                    "The VHD footer at the end of the file is damaged; using the copy at the start."
+                        .to_string()
+                }
+            });
+            footer
+        }
+        (Err(e), false) => return Err(e),
+    };
+
+    match footer.disk_type {
+        DISK_FIXED => {
+            if footer.current_size > file_size - FOOTER_LEN as u64 {
+                return Err(corrupt(&format!(
+                    "fixed disk of {} bytes does not fit in the file",
+                    footer.current_size
+                )));
+            }
+        }
+        DISK_DYNAMIC => {}
+        DISK_DIFFERENCING => {
+            return Err(ImageError::Unsupported(
+                "differencing VHD images; merge them into their parent first".to_string(),
+            ))
+        }
+        other => return Err(corrupt(&format!("unknown disk type {}", other))),
+    }
+    Ok(Some(footer))
+}
+
+fn parse_footer(buf: &[u8]) -> Result<Option<Footer>, ImageError> {
+    if !buf.starts_with(FOOTER_COOKIE) {
+        return Ok(None);
+    }
+    if be_u32(buf, 64) != checksum(buf, 64) {
+        return Err(corrupt("footer checksum mismatch"));
+    }
+    Ok(Some(Footer {
+        data_offset: be_u64(buf, 16),
+        current_size: be_u64(buf, 48),
+        disk_type: be_u32(buf, 60),
+        warnings: Vec::new(),
+    }))
+}
+
+/// One's complement of the byte sum, skipping the 4-byte checksum field.
+fn checksum(buf: &[u8], field: usize) -> u32 {
+    let sum = buf
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(field..field + 4).contains(i))
+        .fold(0u32, |sum, (_, &b)| sum.wrapping_add(b as u32));
+    !sum
+}
+
+enum Layout {
+    /// The raw disk followed by the footer
+    Fixed,
+    /// Blocks located through the block allocation table, each led by a
+    /// sector bitmap
+    Dynamic {
+        block_size: u64,
+        bitmap_len: u64,
+        bat: Vec<u32>,
+    },
+}
+
+/// A fixed or dynamic VHD image.
+pub struct Vhd {
+    file: File,
+    size: u64,
+    layout: Layout,
+    warnings: Vec<String>,
+}
+
+impl Vhd {
+    pub fn open(mut file: File, footer: Footer) -> Result<Self, ImageError> {
+        let layout = match footer.disk_type {
+            DISK_DYNAMIC => open_dynamic(&mut file, &footer)?,
+            _ => Layout::Fixed,
+        };
+        Ok(Self {
+            file,
+            size: footer.current_size,
+            layout,
+            warnings: footer.warnings,
+        })
+    }
+
+    pub fn format_name(&self) -> &'static str {
+        match self.layout {
+            Layout::Fixed => "VHD (fixed)",
+            Layout::Dynamic { .. } => "VHD (dynamic)",
+        }
+    }
+}
+
+fn open_dynamic(file: &mut File, footer: &Footer) -> Result<Layout, ImageError> {
+    let file_size = file.metadata()?.len();
+    let mut header = [0u8; DYNAMIC_HEADER_LEN];
+    if footer.data_offset.saturating_add(DYNAMIC_HEADER_LEN as u64) > file_size {
+        return Err(corrupt(
+            "dynamic disk header lies beyond the end of the file",
+        ));
+    }
+    read_exact_at(file, footer.data_offset, &mut header, "VHD")?;
+    if !header.starts_with(DYNAMIC_COOKIE) {
+        return Err(corrupt("dynamic disk header is missing"));
+    }
+    if be_u32(&header, 36) != checksum(&header, 36) {
+        return Err(corrupt("dynamic disk header checksum mismatch"));
+    }
+
+    let table_offset = be_u64(&header, 16);
+    let max_entries = be_u32(&header, 28) as u64;
+    let block_size = be_u32(&header, 32) as u64;
+    if block_size < SECTOR || !block_size.is_power_of_two() {
+        return Err(corrupt(&format!("invalid block size {}", block_size)));
+    }
+    if footer.current_size > max_entries * block_size {
+        return Err(corrupt(
+            "block allocation table is too small for the disk size",
+        ));
+    }
+    if table_offset.saturating_add(max_entries * 4) > file_size {
+        return Err(corrupt(
+            "block allocation table lies beyond the end of the file",
+        ));
+    }
+
+    let mut table = vec![0u8; (max_entries * 4) as usize];
+    read_exact_at(file, table_offset, &mut table, "VHD")?;
+    let bat: Vec<u32> = table.chunks_exact(4).map(|e| be_u32(e, 0)).collect();
+
+    // One bit per sector, padded to whole sectors
+    let bitmap_len = (block_size / SECTOR).div_ceil(8).next_multiple_of(SECTOR);
+    for (block, &entry) in bat.iter().enumerate() {
+        if entry != UNALLOCATED && entry as u64 * SECTOR + bitmap_len + block_size > file_size {
+            return Err(corrupt(&format!(
+                "block {} lies beyond the end of the file",
+                block
+            )));
+        }
+    }
+
+    Ok(Layout::Dynamic {
+        block_size,
+        bitmap_len,
+        bat,
+    })
+}
+
+impl VirtualDisk for Vhd {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        match &self.layout {
+            Layout::Fixed => {
+                read_exact_at(&mut self.file, pos, buf, "VHD")?;
+                Ok(buf.len())
+            }
+            Layout::Dynamic {
+                block_size,
+                bitmap_len,
+                bat,
+            } => {
+                let block = pos / block_size;
+                let in_block = pos % block_size;
+                let len = buf.len().min((block_size - in_block) as usize);
+                match bat.get(block as usize) {
+                    Some(&entry) if entry != UNALLOCATED => {
+                        // The sector bitmap only matters for differencing disks
+                        let offset = entry as u64 * SECTOR + bitmap_len + in_block;
+                        read_exact_at(&mut self.file, offset, &mut buf[..len], "VHD")?;
+                    }
+                    _ => buf[..len].fill(0),
+                }
+                Ok(len)
+            }
+        }
+    }
+
+    fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
+
+fn corrupt(message: &str) -> ImageError {
+    ImageError::Corrupt(format!("VHD image: {}", message))
+}
@@ -0,0 +1,388 @@
+use super::bytes::{le_u16, le_u32, le_u64};
+use super::disk::{read_exact_at, VirtualDisk};
+use super::ImageError;
+use std::fs::File;
+use std::io;
+
+const FILE_SIGNATURE: &[u8] = b"vhdxfile";
+const HEADER_SIGNATURE: &[u8] = b"head";
+const REGION_SIGNATURE: &[u8] = b"regi";
+const METADATA_SIGNATURE: &[u8] = b"metadata";
+
+const HEADER_OFFSETS: [u64; 2] = [64 * 1024, 128 * 1024];
+const HEADER_LEN: usize = 4 * 1024;
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 * 1024, 256 * 1024];
+const REGION_TABLE_LEN: usize = 64 * 1024;
+const MAX_ENTRIES: usize = 2047;
+/// The spec caps the metadata region well below this
+const MAX_METADATA_LEN: u64 = 16 * 1024 * 1024;
+
+const REGION_BAT: [u8; 16] = guid(
+    0x2DC2_7766,
+    0xF623,
+    0x4200,
+    [0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08],
+);
+const REGION_METADATA: [u8; 16] = guid(
+    0x8B7C_A206,
+    0x4790,
+    0x4B9A,
+    [0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E],
+);
+
+const META_FILE_PARAMETERS: [u8; 16] = guid(
+    0xCAA1_6737,
+    0xFA36,
+    0x4D43,
+    [0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B],
+);
+const META_VIRTUAL_DISK_SIZE: [u8; 16] = guid(
+    0x2FA5_4224,
+    0xCD1B,
+    0x4876,
+    [0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8],
+);
+const META_LOGICAL_SECTOR_SIZE: [u8; 16] = guid(
+    0x8141_BF1D,
+    0xA96F,
+    0x4709,
+    [0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F],
+);
+const META_PHYSICAL_SECTOR_SIZE: [u8; 16] = guid(
+    0xCDA3_48C7,
+    0x445D,
+    0x4471,
+    [0x9C, 0xC9, 0xE9, 0x88, 0x52, 0x51, 0xC5, 0x56],
+);
+const META_PAGE_83: [u8; 16] = guid(
+    0xBECA_12AB,
+    0xB2E6,
+    0x4523,
+    [0x93, 0xEF, 0xC3, 0x09, 0xE0, 0x00, 0xC7, 0x46],
+);
+const META_PARENT_LOCATOR: [u8; 16] = guid(
+    0xA8D3_5F2D,
+    0xB30B,
+    0x454D,
+    [0xAB, 0xF7, 0xD3, 0xD8, 0x48, 0x34, 0xAB, 0x0C],
+);
+
+const BLOCK_FULLY_PRESENT: u64 = 6;
+const BLOCK_PARTIALLY_PRESENT: u64 = 7;
+const BLOCK_OFFSET_MASK: u64 = !0xF_FFFF;
+
+/// GUIDs are stored with their first three fields little-endian.
+const fn guid(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> [u8; 16] {
+    let a = d1.to_le_bytes();
+    let b = d2.to_le_bytes();
+    let c = d3.to_le_bytes();
+    [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d4[0], d4[1], d4[2], d4[3], d4[4], d4[5],
+        d4[6], d4[7],
+    ]
+}
+
+pub fn is_vhdx(magic: &[u8]) -> bool {
+    magic.starts_with(FILE_SIGNATURE)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Region {
+    offset: u64,
+    len: u64,
+}
+
+/// A VHDX image without a parent disk.
+pub struct Vhdx {
+    file: File,
+    size: u64,
+    block_size: u64,
+    /// Payload blocks between consecutive sector bitmap entries in the BAT
+    chunk_ratio: u64,
+    bat: Vec<u64>,
+    warnings: Vec<String>,
+}
+
+impl Vhdx {
+    pub fn open(mut file: File) -> Result<Self, ImageError> {
+        let file_size = file.metadata()?.len();
+        let mut warnings = Vec::new();
+
+        check_headers(&mut file, &mut warnings)?;
+        let (bat_region, metadata_region) = read_region_table(&mut file, &mut warnings)?;
+        for region in [bat_region, metadata_region] {
+            if region.offset.saturating_add(region.len) > file_size {
+                return Err(corrupt("a region lies beyond the end of the file"));
+            }
+        }
+
+        let metadata = read_metadata(&mut file, metadata_region)?;
+        let chunk_ratio = (1u64 << 23) * metadata.logical_sector_size / metadata.block_size;
+        let data_blocks = metadata.size.div_ceil(metadata.block_size);
+        let bat_entries = data_blocks + data_blocks.saturating_sub(1) / chunk_ratio;
+        if bat_region.len < bat_entries * 8 {
+            return Err(corrupt(
+                "block allocation table is too small for the disk size",
+            ));
+        }
+
+        let mut table = vec![0u8; (bat_entries * 8) as usize];
+        read_exact_at(&mut file, bat_region.offset, &mut table, "VHDX")?;
+        let bat: Vec<u64> = table.chunks_exact(8).map(|e| le_u64(e, 0)).collect();
+
+        for block in 0..data_blocks {
+            let entry = bat[(block + block / chunk_ratio) as usize];
+            match entry & 7 {
+                0..=3 => {}
+                BLOCK_FULLY_PRESENT => {
+                    if (entry & BLOCK_OFFSET_MASK).saturating_add(metadata.block_size) > file_size {
+                        return Err(corrupt(&format!(
+                            "block {} lies beyond the end of the file",
+                            block
+                        )));
+                    }
+                }
+                BLOCK_PARTIALLY_PRESENT => {
+                    return Err(corrupt(&format!(
+                        "block {} is only partially present, but the disk has no parent",
+                        block
+                    )))
+                }
+                state => {
+                    return Err(corrupt(&format!(
+                        "block {} has unknown state {}",
+                        block, state
+                    )))
+                }
+            }
+        }
+
+        Ok(Self {
+            file,
+            size: metadata.size,
+            block_size: metadata.block_size,
+            chunk_ratio,
+            bat,
+            warnings,
+        })
+    }
+}
+
+/// Checks that at least one of the two headers is intact and that the newer
+/// one leaves no log to replay.
+fn check_headers(file: &mut File, warnings: &mut Vec<String>) -> Result<(), ImageError> {
+    let mut newest: Option<(u64, [u8; HEADER_LEN])> = None;
+    let mut damaged = 0;
+    for offset in HEADER_OFFSETS {
+        let mut header = [0u8; HEADER_LEN];
+        read_exact_at(file, offset, &mut header, "VHDX")?;
+        if !header.starts_with(HEADER_SIGNATURE) || !checksum_matches(&header) {
+            damaged += 1;
+            continue;
+        }
+        let sequence = le_u64(&header, 8);
+        if newest.as_ref().is_none_or(|(newest, _)| sequence > *newest) {
+            newest = Some((sequence, header));
+        }
+    }
+
+    let (_, header) = newest.ok_or_else(|| corrupt("both headers are damaged"))?;
+    if damaged > 0 {
+        warnings.push("One of the two VHDX headers is damaged; using the other copy.".to_string());
+    }
+    if le_u16(&header, 66) != 1 {
+        return Err(ImageError::Unsupported(format!(
+            "VHDX version {}",
+            le_u16(&header, 66)
+        )));
+    }
+    // A log GUID means writes were cut off and the log still has to be replayed
+    if header[48..64].iter().any(|&b| b != 0) {
+        return Err(ImageError::Unsupported(
+            "VHDX image with a pending log; attach and detach it in Hyper-V first".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the BAT and metadata regions from the first intact region table.
+fn read_region_table(
+    file: &mut File,
+    warnings: &mut Vec<String>,
+) -> Result<(Region, Region), ImageError> {
+    let mut tables = Vec::new();
+    for offset in REGION_TABLE_OFFSETS {
+        let mut table = vec![0u8; REGION_TABLE_LEN];
+        read_exact_at(file, offset, &mut table, "VHDX")?;
+        let count = le_u32(&table, 8) as usize;
+        if table.starts_with(REGION_SIGNATURE) && count <= MAX_ENTRIES && checksum_matches(&table) {
+            tables.push(table);
+        }
+    }
+
+    let table = match tables.as_slice() {
+        [] => return Err(corrupt("both region tables are damaged")),
+        [only] => {
+            warnings.push(
+                "One of the two VHDX region tables is damaged; using the other copy.".to_string(),
+            );
+            only
+        }
+        [first, second, ..] => {
+            if first != second {
+                warnings
+                    .push("The two VHDX region tables differ; using the first one.".to_string());
+            }
+            first
+        }
+    };
+
+    let mut bat = None;
+    let mut metadata = None;
+    for i in 0..le_u32(table, 8) as usize {
+        let entry = &table[16 + i * 32..16 + (i + 1) * 32];
+        let region = Region {
+            offset: le_u64(entry, 16),
+            len: le_u32(entry, 24) as u64,
+        };
+        let required = le_u32(entry, 28) & 1 != 0;
+        if entry[..16] == REGION_BAT {
+            bat = Some(region);
+        } else if entry[..16] == REGION_METADATA {
+            metadata = Some(region);
+        } else if required {
+            return Err(ImageError::Unsupported(
+                "VHDX image with an unknown required region".to_string(),
+            ));
+        }
+    }
+
+    match (bat, metadata) {
+        (Some(bat), Some(metadata)) => Ok((bat, metadata)),
+        (None, _) => Err(corrupt("region table has no block allocation table")),
+        (_, None) => Err(corrupt("region table has no metadata region")),
+    }
+}
+
+struct Metadata {
+    size: u64,
+    block_size: u64,
+    logical_sector_size: u64,
+}
+
+fn read_metadata(file: &mut File, region: Region) -> Result<Metadata, ImageError> {
+    if region.len < 64 * 1024 || region.len > MAX_METADATA_LEN {
+        return Err(corrupt("invalid metadata region size"));
+    }
+    let mut data = vec![0u8; region.len as usize];
+    read_exact_at(file, region.offset, &mut data, "VHDX")?;
+    if !data.starts_with(METADATA_SIGNATURE) {
+        return Err(corrupt("metadata table is missing"));
+    }
+    let count = le_u16(&data, 10) as usize;
+    if count > MAX_ENTRIES {
+        return Err(corrupt("metadata table has too many entries"));
+    }
+
+    let mut size = None;
+    let mut block_size = None;
+    let mut logical_sector_size = None;
+    for i in 0..count {
+        let entry = &data[32 + i * 32..32 + (i + 1) * 32];
+        let offset = le_u32(entry, 16) as usize;
+        let len = le_u32(entry, 20) as usize;
+        let required = le_u32(entry, 24) & 4 != 0;
+        let item = data
+            .get(offset..offset.saturating_add(len))
+            .ok_or_else(|| corrupt("metadata item lies outside the metadata region"))?;
+        let item_u32 = |at: usize| (item.len() >= at + 4).then(|| le_u32(item, at));
+
+        let id = &entry[..16];
+        if id == META_FILE_PARAMETERS {
+            let flags = item_u32(4).ok_or_else(|| corrupt("file parameters are truncated"))?;
+            if flags & 2 != 0 {
+                return Err(ImageError::Unsupported(
+                    "differencing VHDX images; merge them into their parent first".to_string(),
+                ));
+            }
+            block_size = item_u32(0).map(u64::from);
+        } else if id == META_VIRTUAL_DISK_SIZE {
+            size = (item.len() >= 8).then(|| le_u64(item, 0));
+        } else if id == META_LOGICAL_SECTOR_SIZE {
+            logical_sector_size = item_u32(0).map(u64::from);
+        } else if id == META_PHYSICAL_SECTOR_SIZE || id == META_PAGE_83 {
+            // Not needed to read the disk
+        } else if id == META_PARENT_LOCATOR {
+            return Err(ImageError::Unsupported(
+                "differencing VHDX images; merge them into their parent first".to_string(),
+            ));
+        } else if required {
+            return Err(ImageError::Unsupported(
+                "VHDX image with unknown required metadata".to_string(),
+            ));
+        }
+    }
+
+    let size = size.ok_or_else(|| corrupt("virtual disk size is missing"))?;
+    let block_size = block_size
+        .filter(|s| s.is_power_of_two() && (1 << 20..=256 << 20).contains(s))
+        .ok_or_else(|| corrupt("block size is missing or invalid"))?;
+    let logical_sector_size = logical_sector_size
+        .filter(|&s| s == 512 || s == 4096)
+        .ok_or_else(|| corrupt("logical sector size is missing or invalid"))?;
+    Ok(Metadata {
+        size,
+        block_size,
+        logical_sector_size,
+    })
+}
+
+/// Headers and region tables carry a CRC-32C over the whole structure, taken
+/// with the checksum field at offset 4 zeroed.
+fn checksum_matches(buf: &[u8]) -> bool {
+    let stored = le_u32(buf, 4);
+    let mut zeroed = buf.to_vec();
+    zeroed[4..8].fill(0);
+    crc32c(&zeroed) == stored
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82F6_3B78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+impl VirtualDisk for Vhdx {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let block = pos / self.block_size;
+        let in_block = pos % self.block_size;
+        let len = buf.len().min((self.block_size - in_block) as usize);
+
+        let entry = self.bat[(block + block / self.chunk_ratio) as usize];
+        if entry & 7 == BLOCK_FULLY_PRESENT {
+            let offset = (entry & BLOCK_OFFSET_MASK) + in_block;
+            read_exact_at(&mut self.file, offset, &mut buf[..len], "VHDX")?;
+        } else {
+            // Not present, zeroed, undefined and unmapped blocks all read as zeros
+            buf[..len].fill(0);
+        }
+        Ok(len)
+    }
+
+    fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
+
+fn corrupt(message: &str) -> ImageError {
+    ImageError::Corrupt(format!("VHDX image: {}", message))
+}
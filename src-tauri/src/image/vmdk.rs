@@ -0,0 +1,300 @@
+use super::bytes::{le_u16, le_u32, le_u64};
+use super::disk::{read_exact_at, VirtualDisk};
+use super::ImageError;
+use flate2::read::ZlibDecoder;
+use std::fs::File;
+use std::io::{self, Read};
+
+const SPARSE_MAGIC: &[u8] = b"KDMV";
+const DESCRIPTOR_MAGIC: &[u8] = b"# Disk DescriptorFile";
+const SECTOR: u64 = 512;
+/// streamOptimized images keep the real header in a footer near the end
+const GD_AT_END: u64 = u64::MAX;
+
+const FLAG_NEWLINE_TEST: u32 = 1 << 0;
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const COMPRESSION_DEFLATE: u16 = 1;
+
+/// Grain table entry for a grain that reads as zeros
+const GRAIN_ZERO: u32 = 1;
+
+pub fn is_sparse_vmdk(magic: &[u8]) -> bool {
+    magic.starts_with(SPARSE_MAGIC)
+}
+
+pub fn is_descriptor(magic: &[u8]) -> bool {
+    magic.starts_with(DESCRIPTOR_MAGIC)
+}
+
+/// A text descriptor only lists extents kept in other files. Names them in
+/// the error, since a flat extent can be written as it is.
+pub fn descriptor_error(file: &mut File) -> ImageError {
+    let mut text = Vec::new();
+    if let Err(e) = file.take(64 * 1024).read_to_end(&mut text) {
+        return e.into();
+    }
+    let text = String::from_utf8_lossy(&text);
+
+    let create_type = descriptor_value(&text, "createType").unwrap_or("unknown");
+    let extents: Vec<&str> = text
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let access = line.split_whitespace().next()?;
+            if !matches!(access, "RW" | "RDONLY" | "NOACCESS") {
+                return None;
+            }
+            line.split('"').nth(1)
+        })
+        .collect();
+
+    ImageError::Unsupported(if extents.is_empty() {
+        format!("VMDK descriptor ({}) without any extents", create_type)
+    } else {
+        format!(
+            "VMDK descriptor ({}) that keeps the disk in {}; select the extent file instead",
+            create_type,
+            extents.join(", ")
+        )
+    })
+}
+
+fn descriptor_value<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    text.lines().find_map(|line| {
+        let (name, value) = line.split_once('=')?;
+        (name.trim() == key).then(|| value.trim().trim_matches('"'))
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    flags: u32,
+    capacity: u64,
+    grain_size: u64,
+    descriptor_offset: u64,
+    descriptor_size: u64,
+    gtes_per_gt: u64,
+    gd_offset: u64,
+    compression: u16,
+}
+
+fn parse_header(buf: &[u8]) -> Result<Header, ImageError> {
+    if !is_sparse_vmdk(buf) {
+        return Err(corrupt("sparse extent header is missing"));
+    }
+    let version = le_u32(buf, 4);
+    if !(1..=3).contains(&version) {
+        return Err(ImageError::Unsupported(format!(
+            "VMDK sparse extent version {}",
+            version
+        )));
+    }
+    let header = Header {
+        flags: le_u32(buf, 8),
+        capacity: le_u64(buf, 12),
+        grain_size: le_u64(buf, 20),
+        descriptor_offset: le_u64(buf, 28),
+        descriptor_size: le_u64(buf, 36),
+        gtes_per_gt: le_u32(buf, 44) as u64,
+        gd_offset: le_u64(buf, 56),
+        compression: le_u16(buf, 77),
+    };
+    // These bytes get mangled when the file goes through a text-mode transfer
+    if header.flags & FLAG_NEWLINE_TEST != 0 && buf[73..77] != *b"\n \r\n" {
+        return Err(corrupt(
+            "line ending check failed; the file was damaged by a text-mode transfer",
+        ));
+    }
+    if header.grain_size < 8 || !header.grain_size.is_power_of_two() || header.grain_size > 2048 {
+        return Err(corrupt(&format!(
+            "invalid grain size of {} sectors",
+            header.grain_size
+        )));
+    }
+    if header.gtes_per_gt == 0 || header.gtes_per_gt > 4096 {
+        return Err(corrupt("invalid grain table size"));
+    }
+    if header.capacity > u64::MAX / SECTOR {
+        return Err(corrupt("disk capacity is out of range"));
+    }
+    if header.flags & FLAG_COMPRESSED != 0 && header.compression != COMPRESSION_DEFLATE {
+        return Err(ImageError::Unsupported(format!(
+            "VMDK compression algorithm {}",
+            header.compression
+        )));
+    }
+    Ok(header)
+}
+
+/// A hosted sparse extent: monolithicSparse, or streamOptimized with
+/// deflate-compressed grains.
+pub struct Vmdk {
+    file: File,
+    header: Header,
+    create_type: Option<String>,
+    grain_directory: Vec<u32>,
+    /// Most recently used grain table, by its sector in the file
+    gt_cache: Option<(u32, Vec<u32>)>,
+    /// Most recently decompressed grain, by its index in the disk
+    grain_cache: Option<(u64, Vec<u8>)>,
+}
+
+impl Vmdk {
+    pub fn open(mut file: File) -> Result<Self, ImageError> {
+        let file_size = file.metadata()?.len();
+        let mut buf = [0u8; SECTOR as usize];
+        read_exact_at(&mut file, 0, &mut buf, "VMDK")?;
+        let mut header = parse_header(&buf)?;
+
+        if header.gd_offset == GD_AT_END {
+            // Footer header, then the end-of-stream marker
+            if file_size < 3 * SECTOR {
+                return Err(corrupt("footer is missing"));
+            }
+            read_exact_at(&mut file, file_size - 2 * SECTOR, &mut buf, "VMDK")?;
+            header = parse_header(&buf).map_err(|_| corrupt("footer is missing or damaged"))?;
+            if header.gd_offset == GD_AT_END {
+                return Err(corrupt("footer does not locate the grain directory"));
+            }
+        }
+
+        let create_type = read_create_type(&mut file, &header, file_size);
+
+        let bytes_per_gt = header.gtes_per_gt * header.grain_size * SECTOR;
+        let gd_entries = (header.capacity * SECTOR).div_ceil(bytes_per_gt);
+        let gd_offset = header.gd_offset.saturating_mul(SECTOR);
+        if gd_offset.saturating_add(gd_entries * 4) > file_size {
+            return Err(corrupt("grain directory lies beyond the end of the file"));
+        }
+        let mut table = vec![0u8; (gd_entries * 4) as usize];
+        read_exact_at(&mut file, gd_offset, &mut table, "VMDK")?;
+        let grain_directory = table.chunks_exact(4).map(|e| le_u32(e, 0)).collect();
+
+        Ok(Self {
+            file,
+            header,
+            create_type,
+            grain_directory,
+            gt_cache: None,
+            grain_cache: None,
+        })
+    }
+
+    pub fn format_name(&self) -> String {
+        match &self.create_type {
+            Some(create_type) => format!("VMDK ({})", create_type),
+            None => "VMDK".to_string(),
+        }
+    }
+
+    fn grain_bytes(&self) -> u64 {
+        self.header.grain_size * SECTOR
+    }
+
+    /// Grain table entry for a grain, 0 when no grain table covers it.
+    fn gt_entry(&mut self, grain: u64) -> io::Result<u32> {
+        let gt_sector = match self
+            .grain_directory
+            .get((grain / self.header.gtes_per_gt) as usize)
+        {
+            Some(&sector) if sector != 0 => sector,
+            _ => return Ok(0),
+        };
+
+        if self.gt_cache.as_ref().map(|(sector, _)| *sector) != Some(gt_sector) {
+            let mut table = vec![0u8; (self.header.gtes_per_gt * 4) as usize];
+            read_exact_at(
+                &mut self.file,
+                gt_sector as u64 * SECTOR,
+                &mut table,
+                "VMDK",
+            )?;
+            let entries = table.chunks_exact(4).map(|e| le_u32(e, 0)).collect();
+            self.gt_cache = Some((gt_sector, entries));
+        }
+        let (_, table) = self.gt_cache.as_ref().unwrap();
+        Ok(table[(grain % self.header.gtes_per_gt) as usize])
+    }
+
+    fn load_compressed(&mut self, grain: u64, sector: u32) -> io::Result<&[u8]> {
+        if self.grain_cache.as_ref().map(|(index, _)| *index) != Some(grain) {
+            // Each compressed grain is led by its LBA and compressed length
+            let offset = sector as u64 * SECTOR;
+            let mut marker = [0u8; 12];
+            read_exact_at(&mut self.file, offset, &mut marker, "VMDK")?;
+            if le_u64(&marker, 0) != grain * self.header.grain_size {
+                return Err(invalid(&format!(
+                    "grain {} is stored under the wrong address",
+                    grain
+                )));
+            }
+            let mut compressed = vec![0u8; le_u32(&marker, 8) as usize];
+            read_exact_at(&mut self.file, offset + 12, &mut compressed, "VMDK")?;
+
+            let mut data = vec![0u8; self.grain_bytes() as usize];
+            // The last grain may decompress short when the capacity ends mid-grain
+            let mut decoder = ZlibDecoder::new(&compressed[..]);
+            let filled = super::read_up_to(&mut decoder, &mut data)
+                .map_err(|e| invalid(&format!("compressed grain {} is damaged: {}", grain, e)))?;
+            let needed = (self.header.capacity * SECTOR - grain * self.grain_bytes())
+                .min(self.grain_bytes()) as usize;
+            if filled < needed {
+                return Err(invalid(&format!(
+                    "compressed grain {} is damaged: it is too short",
+                    grain
+                )));
+            }
+            self.grain_cache = Some((grain, data));
+        }
+        Ok(&self.grain_cache.as_ref().unwrap().1)
+    }
+}
+
+fn read_create_type(file: &mut File, header: &Header, file_size: u64) -> Option<String> {
+    let offset = header.descriptor_offset.checked_mul(SECTOR)?;
+    let len = header.descriptor_size.checked_mul(SECTOR)?.min(64 * 1024);
+    if offset == 0 || offset.saturating_add(len) > file_size {
+        return None;
+    }
+    let mut text = vec![0u8; len as usize];
+    read_exact_at(file, offset, &mut text, "VMDK").ok()?;
+    let text = String::from_utf8_lossy(&text);
+    descriptor_value(&text, "createType").map(str::to_string)
+}
+
+impl VirtualDisk for Vmdk {
+    fn size(&self) -> u64 {
+        self.header.capacity * SECTOR
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let grain = pos / self.grain_bytes();
+        let in_grain = pos % self.grain_bytes();
+        let len = buf.len().min((self.grain_bytes() - in_grain) as usize);
+        let buf = &mut buf[..len];
+
+        let sector = self.gt_entry(grain)?;
+        if sector == 0 || sector == GRAIN_ZERO {
+            // Unallocated and zeroed grains read as zeros
+            buf.fill(0);
+        } else if self.header.flags & FLAG_COMPRESSED != 0 {
+            let data = self.load_compressed(grain, sector)?;
+            buf.copy_from_slice(&data[in_grain as usize..in_grain as usize + len]);
+        } else {
+            let offset = sector as u64 * SECTOR + in_grain;
+            read_exact_at(&mut self.file, offset, buf, "VMDK")?;
+        }
+        Ok(len)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("VMDK image: {}", message),
+    )
+}
+
+fn corrupt(message: &str) -> ImageError {
+    ImageError::Corrupt(format!("VMDK image: {}", message))
+}
@@ -0,0 +1,232 @@
+use super::bytes::{le_u16, le_u32, le_u64};
+use super::compress::Compression;
+use super::ImageError;
+use crc32fast::Hasher;
+use flate2::read::DeflateDecoder;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const EOCD_SIG: u32 = 0x0605_4b50;
+const ZIP64_EOCD_SIG: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x0706_4b50;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+const METHOD_BZIP2: u16 = 12;
+
+const FLAG_ENCRYPTED: u16 = 0x0001;
+
+#[derive(Debug, Clone)]
+pub struct ZipEntry {
+    pub name: String,
+    pub size: u64,
+    pub compressed_size: u64,
+    method: u16,
+    flags: u16,
+    crc32: u32,
+    local_header_offset: u64,
+}
+
+pub fn is_zip(magic: &[u8]) -> bool {
+    magic.len() >= 4 && le_u32(magic, 0) == LOCAL_HEADER_SIG
+}
+
+/// Reads the central directory, following the ZIP64 records when the
+/// classic 32-bit fields have overflowed.
+pub fn list_entries(file: &mut File) -> Result<Vec<ZipEntry>, ImageError> {
+    let file_size = file.metadata()?.len();
+
+    // The end of central directory record sits in the last 22 bytes,
+    // followed by a comment of up to 64 KB
+    let tail_len = file_size.min(22 + 0xFFFF);
+    let tail_start = file_size - tail_len;
+    let mut tail = vec![0u8; tail_len as usize];
+    file.seek(SeekFrom::Start(tail_start))?;
+    file.read_exact(&mut tail)?;
+
+    let eocd = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| le_u32(&tail, i) == EOCD_SIG)
+        .ok_or_else(|| corrupt("end of central directory not found"))?;
+
+    let mut entry_count = le_u16(&tail, eocd + 10) as u64;
+    let mut cd_size = le_u32(&tail, eocd + 12) as u64;
+    let mut cd_offset = le_u32(&tail, eocd + 16) as u64;
+
+    if entry_count == 0xFFFF || cd_size == 0xFFFF_FFFF || cd_offset == 0xFFFF_FFFF {
+        // ZIP64 locator immediately precedes the classic record
+        if eocd < 20 || le_u32(&tail, eocd - 20) != ZIP64_LOCATOR_SIG {
+            return Err(corrupt("ZIP64 end of central directory locator not found"));
+        }
+        let record_offset = le_u64(&tail, eocd - 20 + 8);
+
+        let mut record = [0u8; 56];
+        file.seek(SeekFrom::Start(record_offset))?;
+        file.read_exact(&mut record)?;
+        if le_u32(&record, 0) != ZIP64_EOCD_SIG {
+            return Err(corrupt("ZIP64 end of central directory record not found"));
+        }
+        entry_count = le_u64(&record, 32);
+        cd_size = le_u64(&record, 40);
+        cd_offset = le_u64(&record, 48);
+    }
+
+    if cd_offset.saturating_add(cd_size) > file_size {
+        return Err(corrupt("central directory lies beyond the end of the file"));
+    }
+
+    let mut directory = vec![0u8; cd_size as usize];
+    file.seek(SeekFrom::Start(cd_offset))?;
+    file.read_exact(&mut directory)?;
+
+    let mut entries = Vec::new();
+    let mut pos = 0usize;
+    for _ in 0..entry_count {
+        if pos + 46 > directory.len() || le_u32(&directory, pos) != CENTRAL_HEADER_SIG {
+            return Err(corrupt("malformed central directory entry"));
+        }
+        let header = &directory[pos..];
+        let name_len = le_u16(header, 28) as usize;
+        let extra_len = le_u16(header, 30) as usize;
+        let comment_len = le_u16(header, 32) as usize;
+        if 46 + name_len + extra_len + comment_len > header.len() {
+            return Err(corrupt("central directory entry overruns directory"));
+        }
+
+        let name = String::from_utf8_lossy(&header[46..46 + name_len]).to_string();
+        let extra = &header[46 + name_len..46 + name_len + extra_len];
+
+        let mut entry = ZipEntry {
+            name,
+            size: le_u32(header, 24) as u64,
+            compressed_size: le_u32(header, 20) as u64,
+            method: le_u16(header, 10),
+            flags: le_u16(header, 8),
+            crc32: le_u32(header, 16),
+            local_header_offset: le_u32(header, 42) as u64,
+        };
+        apply_zip64_extra(&mut entry, extra);
+
+        entries.push(entry);
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+
+    Ok(entries)
+}
+
+/// The ZIP64 extra field only carries the values whose 32-bit slot is
+/// saturated, in a fixed order.
+fn apply_zip64_extra(entry: &mut ZipEntry, extra: &[u8]) {
+    let mut pos = 0;
+    while pos + 4 <= extra.len() {
+        let id = le_u16(extra, pos);
+        let len = le_u16(extra, pos + 2) as usize;
+        let data = &extra[pos + 4..(pos + 4 + len).min(extra.len())];
+        if id == ZIP64_EXTRA_ID {
+            let mut values = data.chunks_exact(8).map(|value| le_u64(value, 0));
+            if entry.size == 0xFFFF_FFFF {
+                entry.size = values.next().unwrap_or(entry.size);
+            }
+            if entry.compressed_size == 0xFFFF_FFFF {
+                entry.compressed_size = values.next().unwrap_or(entry.compressed_size);
+            }
+            if entry.local_header_offset == 0xFFFF_FFFF {
+                entry.local_header_offset = values.next().unwrap_or(entry.local_header_offset);
+            }
+            return;
+        }
+        pos += 4 + len;
+    }
+}
+
+/// Streams the decompressed contents of `entry`, checking its CRC-32 at the end.
+pub fn open_entry(mut file: File, entry: &ZipEntry) -> Result<Box<dyn Read + Send>, ImageError> {
+    if entry.flags & FLAG_ENCRYPTED != 0 {
+        return Err(ImageError::Unsupported(format!(
+            "{} is encrypted",
+            entry.name
+        )));
+    }
+
+    let mut local = [0u8; 30];
+    file.seek(SeekFrom::Start(entry.local_header_offset))?;
+    file.read_exact(&mut local)?;
+    if le_u32(&local, 0) != LOCAL_HEADER_SIG {
+        return Err(corrupt("local file header not found"));
+    }
+    let data_offset =
+        entry.local_header_offset + 30 + le_u16(&local, 26) as u64 + le_u16(&local, 28) as u64;
+
+    file.seek(SeekFrom::Start(data_offset))?;
+    let data = file.take(entry.compressed_size);
+
+    let decoded: Box<dyn Read + Send> = match entry.method {
+        METHOD_STORED => Box::new(data),
+        METHOD_DEFLATE => Box::new(DeflateDecoder::new(BufReader::new(data))),
+        METHOD_BZIP2 => Compression::Bzip2.decoder(BufReader::new(data)),
+        method => {
+            return Err(ImageError::Unsupported(format!(
+                "{} uses unsupported zip compression method {}",
+                entry.name, method
+            )))
+        }
+    };
+
+    Ok(Box::new(EntryReader {
+        inner: decoded,
+        hasher: Hasher::new(),
+        expected_crc: entry.crc32,
+        expected_size: entry.size,
+        bytes_read: 0,
+    }))
+}
+
+struct EntryReader {
+    inner: Box<dyn Read + Send>,
+    hasher: Hasher,
+    expected_crc: u32,
+    expected_size: u64,
+    bytes_read: u64,
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.hasher.update(&buf[..n]);
+            self.bytes_read += n as u64;
+            return Ok(n);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.bytes_read != self.expected_size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "zip entry is truncated: expected {} bytes, got {}",
+                    self.expected_size, self.bytes_read
+                ),
+            ));
+        }
+        let crc = self.hasher.clone().finalize();
+        if crc != self.expected_crc {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "zip entry CRC-32 mismatch: expected {:08x}, got {:08x}",
+                    self.expected_crc, crc
+                ),
+            ));
+        }
+        Ok(0)
+    }
+}
+
+fn corrupt(message: &str) -> ImageError {
+    ImageError::Corrupt(format!("zip archive: {}", message))
+}
@@ -0,0 +1,145 @@
+//! Works out which machines an image boots on, from its bootloaders, boot
+//! catalog and boot partition.
+
+use super::efi::EfiBootloader;
+use super::eltorito::BootEntry;
+use super::fs::FileSystem;
+use super::os::OsInfo;
+use super::partitions::PartitionTable;
+use super::ReadAt;
+use serde::Serialize;
+use std::io;
+
+/// Raspberry Pi firmware in the root of the boot partition, and the models
+/// it starts. The Pi 5 has its firmware in EEPROM, so its device tree has to
+/// do instead.
+const PI_FIRMWARE: &[(&str, &str)] = &[
+    ("start.elf", "Raspberry Pi 1-3 and Zero"),
+    ("start4.elf", "Raspberry Pi 4"),
+    ("bcm2712-rpi-5-b.dtb", "Raspberry Pi 5"),
+];
+/// Kernels the Pi firmware loads, and the CPU mode they run in
+const PI_KERNELS: &[(&str, &str)] = &[
+    ("kernel.img", "arm"),
+    ("kernel7.img", "arm"),
+    ("kernel7l.img", "arm"),
+    ("kernel8.img", "aa64"),
+    ("kernel_2712.img", "aa64"),
+];
+/// Release file spellings of architectures, by UEFI name
+const RELEASE_ARCHITECTURES: &[(&str, &str)] = &[
+    ("amd64", "x64"),
+    ("x86_64", "x64"),
+    ("i386", "ia32"),
+    ("i686", "ia32"),
+    ("arm64", "aa64"),
+    ("aarch64", "aa64"),
+    ("armhf", "arm"),
+    ("armel", "arm"),
+    ("armv7hl", "arm"),
+    ("riscv64", "riscv64"),
+];
+
+/// The machines an image boots on.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Platforms {
+    /// CPUs by their UEFI names: "x64", "ia32", "aa64", "arm", "riscv64",
+    /// "loongarch64", plus "ppc" for Open Firmware
+    pub architectures: Vec<String>,
+    /// Boots from PC BIOS, through El Torito or a GPT BIOS boot partition
+    pub bios: bool,
+    /// Raspberry Pi models whose firmware is in the boot partition
+    pub raspberry_pi: Vec<String>,
+}
+
+impl Platforms {
+    /// Whether an ordinary PC can start the image.
+    pub fn boots_on_pc(&self) -> bool {
+        self.bios
+            || self
+                .architectures
+                .iter()
+                .any(|arch| arch == "x64" || arch == "ia32")
+    }
+
+    fn add(&mut self, architecture: &str) {
+        if !self.architectures.iter().any(|arch| arch == architecture) {
+            self.architectures.push(architecture.to_string());
+        }
+    }
+}
+
+/// Gathers the platforms from what has already been read of the image, and
+/// looks for a Raspberry Pi boot partition among the volumes. The release
+/// files' architecture is only used when nothing else tells.
+pub fn detect(
+    disk: &mut dyn ReadAt,
+    volumes: &mut [Box<dyn FileSystem>],
+    boot_entries: &[BootEntry],
+    partitions: Option<&PartitionTable>,
+    bootloaders: &[EfiBootloader],
+    os: Option<&OsInfo>,
+) -> io::Result<Platforms> {
+    let mut platforms = Platforms::default();
+    for architecture in bootloaders
+        .iter()
+        .filter_map(|loader| loader.architecture.as_deref())
+    {
+        platforms.add(architecture);
+    }
+    for entry in boot_entries {
+        match entry.platform.as_str() {
+            "BIOS" => platforms.bios = true,
+            // Mac entries are for the PowerPC Macs; Intel Macs boot EFI
+            "PowerPC" | "Mac" => platforms.add("ppc"),
+            _ => {}
+        }
+    }
+    if partitions.is_some_and(|table| {
+        table
+            .partitions
+            .iter()
+            .any(|partition| partition.type_name.as_deref() == Some("BIOS boot"))
+    }) {
+        platforms.bios = true;
+    }
+
+    for volume in volumes {
+        let volume = volume.as_mut();
+        let root = volume.root().clone();
+        let names: Vec<String> = volume
+            .read_dir(disk, &root)?
+            .into_iter()
+            .filter(|node| !node.is_dir)
+            .map(|node| node.name.to_ascii_lowercase())
+            .collect();
+        let has = |file: &str| names.iter().any(|name| name == file);
+        let models: Vec<&str> = PI_FIRMWARE
+            .iter()
+            .filter(|(file, _)| has(file))
+            .map(|(_, model)| *model)
+            .collect();
+        if models.is_empty() {
+            continue;
+        }
+        for model in models {
+            if !platforms.raspberry_pi.iter().any(|known| known == model) {
+                platforms.raspberry_pi.push(model.to_string());
+            }
+        }
+        for (_, architecture) in PI_KERNELS.iter().filter(|(file, _)| has(file)) {
+            platforms.add(architecture);
+        }
+    }
+
+    if platforms.architectures.is_empty() {
+        let release = os.and_then(|os| os.architecture.as_deref());
+        if let Some((_, architecture)) = RELEASE_ARCHITECTURES
+            .iter()
+            .find(|(name, _)| Some(*name) == release)
+        {
+            platforms.add(architecture);
+        }
+    }
+    Ok(platforms)
+}
@@ -0,0 +1,97 @@
+use super::fs::{lookup, read_file_at, FileSystem};
+use super::pe;
+use super::ReadAt;
+use serde::Serialize;
+use std::io;
+
+/// Where firmware looks for a bootloader on removable media
+const BOOT_DIR: &str = "EFI/BOOT";
+/// Enough for the headers and section table of any bootloader
+const HEADER_LEN: usize = 4096;
+/// Certificate tables hold a signature or two, a few KiB each
+const MAX_CERTIFICATES: u32 = 256 * 1024;
+
+/// A removable-media bootloader, `EFI/BOOT/BOOT*.EFI`.
+#[derive(Debug, Clone, Serialize)]
+pub struct EfiBootloader {
+    pub path: String,
+    /// CPU it runs on, by its UEFI name ("x64", "ia32", "aa64", ...)
+    pub architecture: Option<String>,
+    /// Carries an Authenticode signature
+    pub signed: bool,
+    /// Subject and issuer of the signing certificate
+    pub signer: Option<String>,
+    pub issuer: Option<String>,
+    /// Has the `.sbat` section that revocation by generation relies on
+    pub sbat: bool,
+    /// shim, the first stage loader Microsoft signs for Linux distributions
+    pub shim: bool,
+}
+
+impl EfiBootloader {
+    /// Whether the firmware's default Secure Boot keys accept it.
+    pub fn microsoft_signed(&self) -> bool {
+        self.issuer
+            .as_deref()
+            .is_some_and(|issuer| issuer.starts_with("Microsoft"))
+    }
+}
+
+/// Finds the removable-media bootloaders across the volumes of an image. A
+/// bootloader found on more than one volume is reported once.
+pub fn find_bootloaders(
+    disk: &mut dyn ReadAt,
+    volumes: &mut [Box<dyn FileSystem>],
+) -> io::Result<Vec<EfiBootloader>> {
+    let mut bootloaders: Vec<EfiBootloader> = Vec::new();
+    for volume in volumes {
+        let volume = volume.as_mut();
+        let dir = match lookup(volume, disk, BOOT_DIR)? {
+            Some(dir) if dir.is_dir => dir,
+            _ => continue,
+        };
+        for node in volume.read_dir(disk, &dir)? {
+            let name = node.name.to_ascii_uppercase();
+            if node.is_dir || !name.starts_with("BOOT") || !name.ends_with(".EFI") {
+                continue;
+            }
+            let path = format!("{}/{}", BOOT_DIR, name);
+            if bootloaders.iter().any(|loader| loader.path == path) {
+                continue;
+            }
+
+            let mut head = vec![0u8; HEADER_LEN];
+            let len = read_file_at(disk, &node, 0, &mut head)?;
+            let headers = match pe::parse_headers(&head[..len]) {
+                Some(headers) => headers,
+                None => continue,
+            };
+            let signer = match headers.certificates {
+                Some((offset, len)) if len <= MAX_CERTIFICATES => {
+                    let mut table = vec![0u8; len as usize];
+                    let read = read_file_at(disk, &node, offset, &mut table)?;
+                    pe::parse_signer(&table[..read])
+                }
+                _ => None,
+            };
+            let sbat = headers.sections.iter().any(|section| section == ".sbat");
+            let subject = signer.as_ref().and_then(|signer| signer.subject.clone());
+            let issuer = signer.as_ref().and_then(|signer| signer.issuer.clone());
+            // Microsoft signs shim as a third-party UEFI driver
+            let shim = sbat
+                && subject
+                    .as_deref()
+                    .is_some_and(|subject| subject.contains("UEFI Driver Publisher"));
+            bootloaders.push(EfiBootloader {
+                path,
+                architecture: pe::machine_name(headers.machine).map(str::to_string),
+                signed: headers.certificates.is_some(),
+                signer: subject,
+                issuer,
+                sbat,
+                shim,
+            });
+        }
+    }
+    Ok(bootloaders)
+}
@@ -0,0 +1,133 @@
+use super::iso9660::SECTOR;
+use super::{read_exact_at, ReadAt};
+use crate::image::bytes::{le_u16, le_u32};
+use serde::Serialize;
+use std::io;
+
+pub const BOOT_SYSTEM_ID: &[u8] = b"EL TORITO SPECIFICATION";
+const ENTRY_LEN: usize = 32;
+/// Catalogs are a sector or two; this is far more than any real one uses
+const MAX_CATALOG_SECTORS: usize = 16;
+
+const HEADER_VALIDATION: u8 = 0x01;
+const HEADER_SECTION: u8 = 0x90;
+const HEADER_LAST_SECTION: u8 = 0x91;
+const EXTENSION: u8 = 0x44;
+const BOOTABLE: u8 = 0x88;
+
+const PLATFORM_X86: u8 = 0x00;
+const PLATFORM_POWERPC: u8 = 0x01;
+const PLATFORM_MAC: u8 = 0x02;
+const PLATFORM_EFI: u8 = 0xEF;
+
+/// An initial/default or section entry of an El Torito boot catalog.
+#[derive(Debug, Clone, Serialize)]
+pub struct BootEntry {
+    /// "BIOS", "UEFI", "PowerPC", "Mac", or the platform ID in hex
+    pub platform: String,
+    pub bootable: bool,
+    /// "none" for no emulation, otherwise the floppy or hard disk emulated
+    pub emulation: String,
+    /// Sector of the boot image, in 2048-byte blocks
+    pub load_rba: u32,
+    /// Length of the boot image in 512-byte sectors, as recorded (often 0 or
+    /// 1 for UEFI images that don't fit the field)
+    pub sector_count: u16,
+}
+
+/// Reads the boot catalog a boot record volume descriptor points at.
+pub fn read_catalog(disk: &mut dyn ReadAt, boot_record: &[u8]) -> io::Result<Vec<BootEntry>> {
+    let catalog_lba = le_u32(boot_record, 71) as u64;
+    let mut catalog = vec![0u8; SECTOR as usize];
+    read_exact_at(disk, catalog_lba * SECTOR, &mut catalog)?;
+
+    let validation = &catalog[..ENTRY_LEN];
+    // The 16-bit words of the validation entry sum to zero
+    let sum = validation
+        .chunks_exact(2)
+        .fold(0u16, |sum, word| sum.wrapping_add(le_u16(word, 0)));
+    if validation[0] != HEADER_VALIDATION || validation[30..32] != [0x55, 0xAA] || sum != 0 {
+        return Err(invalid("the validation entry is damaged"));
+    }
+
+    let mut entries = vec![parse_entry(&catalog[ENTRY_LEN..], validation[1])];
+    let mut pos = 2 * ENTRY_LEN;
+    loop {
+        load_entry(disk, catalog_lba, &mut catalog, pos)?;
+        let header = &catalog[pos..pos + ENTRY_LEN];
+        if header[0] != HEADER_SECTION && header[0] != HEADER_LAST_SECTION {
+            break;
+        }
+        let platform = header[1];
+        let count = le_u16(header, 2) as usize;
+        let last = header[0] == HEADER_LAST_SECTION;
+        pos += ENTRY_LEN;
+
+        let mut read = 0;
+        while read < count {
+            load_entry(disk, catalog_lba, &mut catalog, pos)?;
+            let entry = &catalog[pos..pos + ENTRY_LEN];
+            pos += ENTRY_LEN;
+            // Extension records add selection criteria to the entry before
+            if entry[0] == EXTENSION {
+                continue;
+            }
+            entries.push(parse_entry(entry, platform));
+            read += 1;
+        }
+        if last {
+            break;
+        }
+    }
+    Ok(entries)
+}
+
+/// Makes sure the catalog has been read as far as the entry at `pos`; large
+/// catalogs carry on into the following sectors.
+fn load_entry(
+    disk: &mut dyn ReadAt,
+    catalog_lba: u64,
+    catalog: &mut Vec<u8>,
+    pos: usize,
+) -> io::Result<()> {
+    while pos + ENTRY_LEN > catalog.len() {
+        if catalog.len() >= MAX_CATALOG_SECTORS * SECTOR as usize {
+            return Err(invalid("the catalog has no end"));
+        }
+        let mut next = vec![0u8; SECTOR as usize];
+        read_exact_at(disk, catalog_lba * SECTOR + catalog.len() as u64, &mut next)?;
+        catalog.extend_from_slice(&next);
+    }
+    Ok(())
+}
+
+fn parse_entry(entry: &[u8], platform: u8) -> BootEntry {
+    BootEntry {
+        platform: match platform {
+            PLATFORM_X86 => "BIOS".to_string(),
+            PLATFORM_EFI => "UEFI".to_string(),
+            PLATFORM_POWERPC => "PowerPC".to_string(),
+            PLATFORM_MAC => "Mac".to_string(),
+            other => format!("{:#04x}", other),
+        },
+        bootable: entry[0] == BOOTABLE,
+        emulation: match entry[1] & 0x0F {
+            0 => "none",
+            1 => "1.2 MB floppy",
+            2 => "1.44 MB floppy",
+            3 => "2.88 MB floppy",
+            4 => "hard disk",
+            _ => "unknown",
+        }
+        .to_string(),
+        load_rba: le_u32(entry, 8),
+        sector_count: le_u16(entry, 6),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("El Torito boot catalog: {}", message),
+    )
+}
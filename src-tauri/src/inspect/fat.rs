@@ -0,0 +1,274 @@
+use super::fs::{read_file, Data, Extent, FileSystem, Node};
+use super::{read_exact_at, ReadAt};
+use crate::image::bytes::{le_u16, le_u32};
+use std::io;
+
+/// Directories are read whole, so this bounds the memory one can take
+const MAX_DIR_LEN: u64 = 16 * 1024 * 1024;
+/// The FAT is read in blocks of this size as chains are followed
+const FAT_BLOCK: u64 = 64 * 1024;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+/// Bits of byte 12 that Windows sets for all-lowercase short names
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXT: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Width {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// A FAT12, FAT16 or FAT32 volume at some offset of the disk.
+pub struct Fat {
+    width: Width,
+    /// Byte offsets from the start of the disk
+    fat_start: u64,
+    data_start: u64,
+    cluster_size: u64,
+    cluster_count: u32,
+    root: Node,
+    /// Last block of the FAT read, and where it starts
+    cache: Option<(u64, Vec<u8>)>,
+}
+
+impl Fat {
+    /// Opens the FAT volume whose boot sector is at `start`.
+    pub fn open(disk: &mut dyn ReadAt, start: u64) -> io::Result<Self> {
+        let mut boot = [0u8; 512];
+        read_exact_at(disk, start, &mut boot)?;
+        let bytes_per_sector = le_u16(&boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = le_u16(&boot, 14) as u64;
+        let fats = boot[16] as u64;
+        if boot[510..512] != [0x55, 0xAA]
+            || !bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+        {
+            return Err(invalid("the boot sector is not a FAT boot sector"));
+        }
+        let root_entries = le_u16(&boot, 17) as u64;
+        let total_sectors = match le_u16(&boot, 19) {
+            0 => le_u32(&boot, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_size = match le_u16(&boot, 22) {
+            0 => le_u32(&boot, 36) as u64,
+            sectors => sectors as u64,
+        };
+
+        let root_start = reserved + fats * fat_size;
+        let root_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+        let data_start = root_start + root_sectors;
+        let cluster_count =
+            (total_sectors.saturating_sub(data_start) / sectors_per_cluster).min(0x0FFF_FFF5);
+        let width = if root_entries == 0 && le_u16(&boot, 22) == 0 {
+            Width::Fat32
+        } else if cluster_count < 4085 {
+            Width::Fat12
+        } else {
+            Width::Fat16
+        };
+
+        let mut fat = Self {
+            width,
+            fat_start: start + reserved * bytes_per_sector,
+            data_start: start + data_start * bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            cluster_count: cluster_count as u32,
+            root: Node {
+                name: String::new(),
+                is_dir: true,
+                size: 0,
+                data: Data::Extents(Vec::new()),
+            },
+            cache: None,
+        };
+        // FAT12 and FAT16 keep the root directory in a fixed area before the
+        // data; FAT32 gives it a cluster chain like any other directory
+        let extents = if width == Width::Fat32 {
+            fat.chain(disk, le_u32(&boot, 44))?
+        } else {
+            vec![Extent {
+                pos: Some(start + root_start * bytes_per_sector),
+                len: root_entries * 32,
+            }]
+        };
+        fat.root.size = extents.iter().map(|extent| extent.len).sum();
+        fat.root.data = Data::Extents(extents);
+        Ok(fat)
+    }
+
+    /// Reads the FAT entry of a cluster.
+    fn next(&mut self, disk: &mut dyn ReadAt, cluster: u32) -> io::Result<u32> {
+        let offset = match self.width {
+            Width::Fat12 => cluster as u64 * 3 / 2,
+            Width::Fat16 => cluster as u64 * 2,
+            Width::Fat32 => cluster as u64 * 4,
+        };
+        let block = offset - offset % FAT_BLOCK;
+        if self.cache.as_ref().map(|(start, _)| *start) != Some(block) {
+            // One spare byte, for a FAT12 entry straddling two blocks
+            let mut data = vec![0u8; FAT_BLOCK as usize + 1];
+            let read = disk.read_at(self.fat_start + block, &mut data)?;
+            data.truncate(read);
+            self.cache = Some((block, data));
+        }
+        let data = &self.cache.as_ref().unwrap().1;
+        let at = (offset - block) as usize;
+        let len = if self.width == Width::Fat32 { 4 } else { 2 };
+        if at + len > data.len() {
+            return Err(invalid("the FAT ends early"));
+        }
+        Ok(match self.width {
+            Width::Fat12 if cluster.is_multiple_of(2) => le_u16(data, at) as u32 & 0x0FFF,
+            Width::Fat12 => le_u16(data, at) as u32 >> 4,
+            Width::Fat16 => le_u16(data, at) as u32,
+            Width::Fat32 => le_u32(data, at) & 0x0FFF_FFFF,
+        })
+    }
+
+    /// Follows a cluster chain, merging runs of adjacent clusters.
+    fn chain(&mut self, disk: &mut dyn ReadAt, first: u32) -> io::Result<Vec<Extent>> {
+        let end = match self.width {
+            Width::Fat12 => 0x0FF8,
+            Width::Fat16 => 0xFFF8,
+            Width::Fat32 => 0x0FFF_FFF8,
+        };
+        let mut extents: Vec<Extent> = Vec::new();
+        let mut cluster = first;
+        // A chain can't be longer than the volume, so a loop shows up as one
+        for _ in 0..self.cluster_count {
+            if cluster < 2 || cluster >= self.cluster_count + 2 {
+                return Err(invalid("a cluster chain is broken"));
+            }
+            let pos = self.data_start + (cluster - 2) as u64 * self.cluster_size;
+            match extents.last_mut() {
+                Some(last) if last.pos.map(|start| start + last.len) == Some(pos) => {
+                    last.len += self.cluster_size
+                }
+                _ => extents.push(Extent {
+                    pos: Some(pos),
+                    len: self.cluster_size,
+                }),
+            }
+            cluster = self.next(disk, cluster)?;
+            if cluster >= end {
+                return Ok(extents);
+            }
+        }
+        Err(invalid("a cluster chain loops"))
+    }
+}
+
+impl FileSystem for Fat {
+    fn root(&self) -> &Node {
+        &self.root
+    }
+
+    fn read_dir(&mut self, disk: &mut dyn ReadAt, dir: &Node) -> io::Result<Vec<Node>> {
+        let data = read_file(disk, dir, MAX_DIR_LEN)?;
+        let mut nodes = Vec::new();
+        // Long name parts come before their short entry, last part first
+        let mut long_name: Vec<(u8, Vec<u16>)> = Vec::new();
+        for entry in data.chunks_exact(32) {
+            match entry[0] {
+                ENTRY_END => break,
+                ENTRY_DELETED => {
+                    long_name.clear();
+                    continue;
+                }
+                _ => {}
+            }
+            let attributes = entry[11];
+            if attributes & 0x3F == ATTR_LONG_NAME {
+                let units = [&entry[1..11], &entry[14..26], &entry[28..32]]
+                    .concat()
+                    .chunks_exact(2)
+                    .map(|pair| le_u16(pair, 0))
+                    .take_while(|&unit| unit != 0 && unit != 0xFFFF)
+                    .collect();
+                long_name.push((entry[13], units));
+                continue;
+            }
+            if attributes & ATTR_VOLUME_ID != 0 || entry[0] == b'.' {
+                long_name.clear();
+                continue;
+            }
+
+            let short = &entry[..11];
+            let checksum = short
+                .iter()
+                .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b));
+            let name = if !long_name.is_empty() && long_name.iter().all(|(sum, _)| *sum == checksum)
+            {
+                let units: Vec<u16> = long_name
+                    .iter()
+                    .rev()
+                    .flat_map(|(_, units)| units.iter().copied())
+                    .collect();
+                String::from_utf16_lossy(&units)
+            } else {
+                short_name(entry)
+            };
+            long_name.clear();
+
+            let is_dir = attributes & ATTR_DIRECTORY != 0;
+            let mut first = le_u16(entry, 26) as u32;
+            if self.width == Width::Fat32 {
+                first |= (le_u16(entry, 20) as u32) << 16;
+            }
+            let extents = if first == 0 {
+                Vec::new()
+            } else {
+                self.chain(disk, first)?
+            };
+            let size = if is_dir {
+                extents.iter().map(|extent| extent.len).sum()
+            } else {
+                le_u32(entry, 28) as u64
+            };
+            nodes.push(Node {
+                name,
+                is_dir,
+                size,
+                data: Data::Extents(extents),
+            });
+        }
+        Ok(nodes)
+    }
+}
+
+/// Builds "NAME.EXT" from an 8.3 entry, lowercasing the parts Windows marks.
+fn short_name(entry: &[u8]) -> String {
+    let mut base = String::from_utf8_lossy(&entry[..8]).trim_end().to_string();
+    let mut ext = String::from_utf8_lossy(&entry[8..11])
+        .trim_end()
+        .to_string();
+    if entry[12] & LOWERCASE_BASE != 0 {
+        base = base.to_lowercase();
+    }
+    if entry[12] & LOWERCASE_EXT != 0 {
+        ext = ext.to_lowercase();
+    }
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("FAT volume: {}", message),
+    )
+}
@@ -0,0 +1,124 @@
+use super::{read_exact_at, ReadAt};
+use std::io;
+
+/// A file or directory of a filesystem inside the image.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub data: Data,
+}
+
+impl Node {
+    /// Disk offset of the first stored byte, for reading files in disk order.
+    pub fn start(&self) -> Option<u64> {
+        match &self.data {
+            Data::Extents(extents) => extents.iter().find_map(|extent| extent.pos),
+            Data::Inline(_) => None,
+        }
+    }
+}
+
+/// Where the contents of a node are kept.
+#[derive(Debug, Clone)]
+pub enum Data {
+    /// Ranges of the disk, in file order
+    Extents(Vec<Extent>),
+    /// Small files some filesystems keep next to their metadata
+    Inline(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Extent {
+    /// Byte offset on the disk, `None` for a range that reads as zeros
+    pub pos: Option<u64>,
+    pub len: u64,
+}
+
+/// A filesystem that can be walked without mounting it.
+pub trait FileSystem {
+    fn root(&self) -> &Node;
+
+    /// Lists the entries of `dir`, leaving out "." and "..".
+    fn read_dir(&mut self, disk: &mut dyn ReadAt, dir: &Node) -> io::Result<Vec<Node>>;
+}
+
+/// Reads up to `buf.len()` bytes of a file from `offset`, returning fewer only
+/// at the end of the file.
+pub fn read_file_at(
+    disk: &mut dyn ReadAt,
+    node: &Node,
+    offset: u64,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let len = buf.len().min(node.size.saturating_sub(offset) as usize);
+    let buf = &mut buf[..len];
+    match &node.data {
+        Data::Inline(data) => {
+            let start = (offset as usize).min(data.len());
+            let available = len.min(data.len() - start);
+            buf[..available].copy_from_slice(&data[start..start + available]);
+            buf[available..].fill(0);
+        }
+        Data::Extents(extents) => {
+            let mut extent_start = 0u64;
+            let mut filled = 0;
+            for extent in extents {
+                let extent_end = extent_start + extent.len;
+                let pos = offset + filled as u64;
+                if filled < len && pos < extent_end {
+                    let in_extent = pos - extent_start;
+                    let n = (len - filled).min((extent.len - in_extent) as usize);
+                    let part = &mut buf[filled..filled + n];
+                    match extent.pos {
+                        Some(start) => read_exact_at(disk, start + in_extent, part)?,
+                        None => part.fill(0),
+                    }
+                    filled += n;
+                }
+                extent_start = extent_end;
+            }
+            // Extents that stop short of the file size leave the rest as zeros
+            buf[filled..].fill(0);
+        }
+    }
+    Ok(len)
+}
+
+/// Reads a whole file, refusing ones larger than `limit`.
+pub fn read_file(disk: &mut dyn ReadAt, node: &Node, limit: u64) -> io::Result<Vec<u8>> {
+    if node.size > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is too large to read ({} bytes)", node.name, node.size),
+        ));
+    }
+    let mut data = vec![0u8; node.size as usize];
+    read_file_at(disk, node, 0, &mut data)?;
+    Ok(data)
+}
+
+/// Finds the node at a "/"-separated path. Names match case-insensitively,
+/// the way the FAT and Windows media they mostly come from behave.
+pub fn lookup(
+    fs: &mut dyn FileSystem,
+    disk: &mut dyn ReadAt,
+    path: &str,
+) -> io::Result<Option<Node>> {
+    let mut node = fs.root().clone();
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        if !node.is_dir {
+            return Ok(None);
+        }
+        let entries = fs.read_dir(disk, &node)?;
+        node = match entries
+            .into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name))
+        {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(node))
+}
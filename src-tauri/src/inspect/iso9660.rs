@@ -0,0 +1,472 @@
+use super::eltorito::{self, BootEntry};
+use super::fs::{read_file, Data, Extent, FileSystem, Node};
+use super::isomd5::{self, ImplantedMd5};
+use super::{read_exact_at, ReadAt};
+use crate::image::bytes::{le_u16, le_u32};
+use serde::Serialize;
+use std::io;
+
+pub const SECTOR: u64 = 2048;
+const STANDARD_ID: &[u8] = b"CD001";
+/// The volume descriptor set starts after the 32 KiB system area
+const DESCRIPTORS_START: u64 = 16;
+/// Gives up on a set without a terminator after this many descriptors
+const MAX_DESCRIPTORS: u64 = 64;
+
+const TYPE_BOOT_RECORD: u8 = 0;
+const TYPE_PRIMARY: u8 = 1;
+const TYPE_SUPPLEMENTARY: u8 = 2;
+const TYPE_TERMINATOR: u8 = 255;
+
+/// UCS-2 escape sequences that mark a supplementary descriptor as Joliet
+const JOLIET_ESCAPES: [&[u8]; 3] = [b"%/@", b"%/C", b"%/E"];
+/// Extension identifiers Rock Ridge announces itself with
+const ROCK_RIDGE_IDS: [&[u8]; 3] = [b"RRIP_1991A", b"IEEE_P1282", b"IEEE_1282"];
+/// Continuation areas followed before giving up on a system use area
+const MAX_CONTINUATIONS: usize = 8;
+/// Directories are read whole, so this bounds the memory one can take
+const MAX_DIR_LEN: u64 = 16 * 1024 * 1024;
+
+const FLAG_DIRECTORY: u8 = 1 << 1;
+/// Set on every section of a file stored in several extents but the last
+const FLAG_MULTI_EXTENT: u8 = 1 << 7;
+/// Flags of a Rock Ridge NM entry
+const NM_CONTINUE: u8 = 1 << 0;
+const NM_CURRENT: u8 = 1 << 1;
+const NM_PARENT: u8 = 1 << 2;
+
+/// What the Primary Volume Descriptor says about an ISO 9660 volume.
+#[derive(Debug, Clone, Serialize)]
+pub struct IsoInfo {
+    pub volume_label: String,
+    pub system_id: Option<String>,
+    pub publisher: Option<String>,
+    pub application_id: Option<String>,
+    /// RFC 3339, with the time zone the image was made in
+    pub creation_date: Option<String>,
+    /// Size of the volume in logical blocks
+    pub volume_space_size: u32,
+    pub logical_block_size: u16,
+    pub joliet: bool,
+    pub rock_ridge: bool,
+    /// El Torito boot catalog entries, empty when the image is not bootable
+    /// from optical media
+    pub boot_entries: Vec<BootEntry>,
+    /// Partition tables in the system area, which let an isohybrid image
+    /// boot from a USB drive as well
+    pub hybrid_mbr: bool,
+    pub hybrid_gpt: bool,
+    /// Checksum implantisomd5 embedded in the PVD
+    pub implanted_md5: Option<ImplantedMd5>,
+    /// Damage found that left some of the details unknown.
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+}
+
+impl IsoInfo {
+    pub fn is_hybrid(&self) -> bool {
+        self.hybrid_mbr || self.hybrid_gpt
+    }
+}
+
+/// The descriptors of an ISO 9660 volume descriptor set.
+pub struct Descriptors {
+    pub primary: Vec<u8>,
+    /// Offset of the Primary Volume Descriptor in the image
+    pub primary_pos: u64,
+    pub joliet: Option<Vec<u8>>,
+    /// El Torito boot record
+    pub boot_record: Option<Vec<u8>>,
+}
+
+/// Reads the volume descriptor set, or returns `None` when the image has no
+/// ISO 9660 volume.
+pub fn read_descriptors(disk: &mut dyn ReadAt) -> io::Result<Option<Descriptors>> {
+    let mut primary = None;
+    let mut joliet = None;
+    let mut boot_record = None;
+    for index in DESCRIPTORS_START..DESCRIPTORS_START + MAX_DESCRIPTORS {
+        let mut sector = vec![0u8; SECTOR as usize];
+        if disk.read_at(index * SECTOR, &mut sector)? < sector.len() || &sector[1..6] != STANDARD_ID
+        {
+            break;
+        }
+        match sector[0] {
+            TYPE_BOOT_RECORD
+                if sector[7..7 + eltorito::BOOT_SYSTEM_ID.len()] == *eltorito::BOOT_SYSTEM_ID =>
+            {
+                boot_record = Some(sector)
+            }
+            TYPE_PRIMARY if primary.is_none() => primary = Some((sector, index * SECTOR)),
+            TYPE_SUPPLEMENTARY if joliet.is_none() && is_joliet(&sector) => joliet = Some(sector),
+            TYPE_TERMINATOR => break,
+            _ => {}
+        }
+    }
+    Ok(primary.map(|(primary, primary_pos)| Descriptors {
+        primary,
+        primary_pos,
+        joliet,
+        boot_record,
+    }))
+}
+
+fn is_joliet(descriptor: &[u8]) -> bool {
+    JOLIET_ESCAPES
+        .iter()
+        .any(|escape| descriptor[88..91] == **escape)
+}
+
+/// Reads the volume information of an ISO 9660 image, or returns `None`
+/// when it has none.
+pub fn read_info(disk: &mut dyn ReadAt) -> io::Result<Option<IsoInfo>> {
+    let descriptors = match read_descriptors(disk)? {
+        Some(descriptors) => descriptors,
+        None => return Ok(None),
+    };
+    let pvd = &descriptors.primary;
+
+    // Rock Ridge lives in the directory records, so a damaged root just
+    // means no Rock Ridge
+    let rock_ridge = DirRecord::parse(&pvd[156..190])
+        .map(|root| has_rock_ridge(disk, &root).unwrap_or(false))
+        .unwrap_or(false);
+
+    let mut warnings = Vec::new();
+    let boot_entries = match &descriptors.boot_record {
+        Some(boot_record) => eltorito::read_catalog(disk, boot_record).unwrap_or_else(|e| {
+            warnings.push(format!("Could not read the El Torito boot catalog: {}", e));
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+
+    // isohybrid and xorriso put an MBR, and for UEFI a GPT, in the 32 KiB
+    // the ISO 9660 format leaves unused
+    let mut system_area = [0u8; 1024];
+    read_exact_at(disk, 0, &mut system_area)?;
+    let hybrid_mbr = system_area[510..512] == [0x55, 0xAA]
+        && system_area[446..510]
+            .chunks_exact(16)
+            .any(|partition| partition[4] != 0);
+    let hybrid_gpt = &system_area[512..520] == b"EFI PART";
+
+    Ok(Some(IsoInfo {
+        volume_label: text(&pvd[40..72]).unwrap_or_default(),
+        system_id: text(&pvd[8..40]),
+        publisher: text(&pvd[318..446]),
+        application_id: text(&pvd[574..702]),
+        creation_date: date(&pvd[813..830]),
+        volume_space_size: le_u32(pvd, 80),
+        logical_block_size: le_u16(pvd, 128),
+        joliet: descriptors.joliet.is_some(),
+        rock_ridge,
+        boot_entries,
+        hybrid_mbr,
+        hybrid_gpt,
+        implanted_md5: isomd5::parse(pvd, descriptors.primary_pos),
+        warnings,
+    }))
+}
+
+/// A directory record.
+#[derive(Debug, Clone)]
+pub struct DirRecord {
+    pub extent: u32,
+    /// Blocks of extended attributes recorded ahead of the data
+    pub ext_attr_len: u8,
+    pub data_len: u32,
+    pub flags: u8,
+    /// File identifier, `[0]` for "." and `[1]` for ".."
+    pub name: Vec<u8>,
+    /// System use area, where SUSP entries such as Rock Ridge's go
+    pub system_use: Vec<u8>,
+}
+
+impl DirRecord {
+    pub fn parse(record: &[u8]) -> Option<Self> {
+        let len = *record.first()? as usize;
+        if len < 34 || len > record.len() {
+            return None;
+        }
+        let name_len = record[32] as usize;
+        // Names of even length are padded to keep the system use area aligned
+        let system_use_start = 33 + name_len + (name_len + 1) % 2;
+        Some(Self {
+            extent: le_u32(record, 2),
+            ext_attr_len: record[1],
+            data_len: le_u32(record, 10),
+            flags: record[25],
+            name: record.get(33..33 + name_len)?.to_vec(),
+            system_use: record
+                .get(system_use_start..len)
+                .unwrap_or_default()
+                .to_vec(),
+        })
+    }
+
+    fn is_dir(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+
+    fn extent(&self) -> Extent {
+        Extent {
+            pos: Some((self.extent as u64 + self.ext_attr_len as u64) * SECTOR),
+            len: self.data_len as u64,
+        }
+    }
+}
+
+/// Looks at the "." record of the root directory, where Rock Ridge must
+/// announce itself.
+fn has_rock_ridge(disk: &mut dyn ReadAt, root: &DirRecord) -> io::Result<bool> {
+    let mut sector = vec![0u8; SECTOR as usize];
+    read_exact_at(disk, root.extent as u64 * SECTOR, &mut sector)?;
+    let dot = match DirRecord::parse(&sector) {
+        Some(dot) => dot,
+        None => return Ok(false),
+    };
+
+    for (signature, data) in read_susp(disk, &dot)? {
+        match &signature {
+            b"RR" | b"PX" | b"NM" => return Ok(true),
+            b"ER" if data.len() >= 4 => {
+                let id_len = data[0] as usize;
+                let id = data.get(4..4 + id_len).unwrap_or_default();
+                if ROCK_RIDGE_IDS.contains(&id) {
+                    return Ok(true);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+/// Collects the SUSP entries of a record, following continuation areas.
+fn read_susp(disk: &mut dyn ReadAt, record: &DirRecord) -> io::Result<Vec<([u8; 2], Vec<u8>)>> {
+    let mut entries = Vec::new();
+    let mut area = record.system_use.clone();
+    for _ in 0..MAX_CONTINUATIONS {
+        let mut continuation = None;
+        for (signature, data) in susp_entries(&area) {
+            if signature == b"CE" && data.len() >= 24 {
+                continuation = Some((le_u32(data, 0), le_u32(data, 8), le_u32(data, 16)));
+            } else {
+                entries.push(([signature[0], signature[1]], data.to_vec()));
+            }
+        }
+        let (block, offset, len) = match continuation {
+            Some(continuation) => continuation,
+            None => break,
+        };
+        area = vec![0u8; len.min(SECTOR as u32) as usize];
+        read_exact_at(disk, block as u64 * SECTOR + offset as u64, &mut area)?;
+    }
+    Ok(entries)
+}
+
+/// Which of the name sets on the volume its directories are read with.
+#[derive(Debug, Clone, Copy)]
+enum Names {
+    RockRidge,
+    Joliet,
+    Plain,
+}
+
+/// An ISO 9660 volume, read with Rock Ridge names when it has them, else
+/// Joliet's, else the short names of plain ISO 9660.
+pub struct Iso9660 {
+    root: Node,
+    names: Names,
+}
+
+impl Iso9660 {
+    /// Opens the volume, or returns `None` when the image has none.
+    pub fn open(disk: &mut dyn ReadAt) -> io::Result<Option<Self>> {
+        let descriptors = match read_descriptors(disk)? {
+            Some(descriptors) => descriptors,
+            None => return Ok(None),
+        };
+        let primary_root = DirRecord::parse(&descriptors.primary[156..190])
+            .ok_or_else(|| invalid("the root directory record is damaged"))?;
+        let joliet_root = descriptors
+            .joliet
+            .as_ref()
+            .and_then(|joliet| DirRecord::parse(&joliet[156..190]));
+
+        let (root, names) = if has_rock_ridge(disk, &primary_root).unwrap_or(false) {
+            (primary_root, Names::RockRidge)
+        } else if let Some(joliet_root) = joliet_root {
+            (joliet_root, Names::Joliet)
+        } else {
+            (primary_root, Names::Plain)
+        };
+        Ok(Some(Self {
+            root: Node {
+                name: String::new(),
+                is_dir: true,
+                size: root.data_len as u64,
+                data: Data::Extents(vec![root.extent()]),
+            },
+            names,
+        }))
+    }
+
+    /// Turns a record into a node, or `None` for records Rock Ridge hides.
+    fn node(&self, disk: &mut dyn ReadAt, record: &DirRecord) -> io::Result<Option<Node>> {
+        let mut node = Node {
+            name: match self.names {
+                Names::Joliet => joliet_name(&record.name),
+                _ => plain_name(&record.name),
+            },
+            is_dir: record.is_dir(),
+            size: record.data_len as u64,
+            data: Data::Extents(vec![record.extent()]),
+        };
+        if let Names::RockRidge = self.names {
+            let mut name = Vec::new();
+            for (signature, data) in read_susp(disk, record)? {
+                match &signature {
+                    b"NM" if !data.is_empty() && data[0] & (NM_CURRENT | NM_PARENT) == 0 => {
+                        name.extend_from_slice(&data[1..]);
+                        if data[0] & NM_CONTINUE == 0 {
+                            node.name = String::from_utf8_lossy(&name).into_owned();
+                        }
+                    }
+                    // Deep directories are moved elsewhere and linked back
+                    b"RE" => return Ok(None),
+                    b"CL" if data.len() >= 4 => {
+                        let mut sector = vec![0u8; SECTOR as usize];
+                        read_exact_at(disk, le_u32(&data, 0) as u64 * SECTOR, &mut sector)?;
+                        let dot = DirRecord::parse(&sector)
+                            .ok_or_else(|| invalid("a relocated directory is damaged"))?;
+                        node.is_dir = true;
+                        node.size = dot.data_len as u64;
+                        node.data = Data::Extents(vec![dot.extent()]);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(Some(node))
+    }
+}
+
+impl FileSystem for Iso9660 {
+    fn root(&self) -> &Node {
+        &self.root
+    }
+
+    fn read_dir(&mut self, disk: &mut dyn ReadAt, dir: &Node) -> io::Result<Vec<Node>> {
+        let data = read_file(disk, dir, MAX_DIR_LEN)?;
+        let mut nodes = Vec::new();
+        // Sections of a file stored in several extents come one after another
+        let mut sections: Option<Node> = None;
+        for sector in data.chunks(SECTOR as usize) {
+            let mut pos = 0;
+            // Records don't cross sectors; the rest of one is zero padding
+            while pos < sector.len() && sector[pos] != 0 {
+                let record = DirRecord::parse(&sector[pos..])
+                    .ok_or_else(|| invalid("a directory is damaged"))?;
+                pos += sector[pos] as usize;
+                if record.name == [0] || record.name == [1] {
+                    continue;
+                }
+
+                let mut node = match self.node(disk, &record)? {
+                    Some(node) => node,
+                    None => continue,
+                };
+                if let Some(mut first) = sections.take() {
+                    if let (Data::Extents(extents), Data::Extents(more)) =
+                        (&mut first.data, node.data)
+                    {
+                        extents.extend(more);
+                    }
+                    first.size += node.size;
+                    node = first;
+                }
+                if record.flags & FLAG_MULTI_EXTENT != 0 {
+                    sections = Some(node);
+                } else {
+                    nodes.push(node);
+                }
+            }
+        }
+        Ok(nodes)
+    }
+}
+
+/// Strips the ";1" version and the trailing dot of a name without extension.
+fn plain_name(id: &[u8]) -> String {
+    let name = String::from_utf8_lossy(id);
+    let name = name.split(';').next().unwrap_or_default();
+    name.strip_suffix('.').unwrap_or(name).to_string()
+}
+
+/// Joliet names are UCS-2, big-endian.
+fn joliet_name(id: &[u8]) -> String {
+    let units: Vec<u16> = id
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    let name = String::from_utf16_lossy(&units);
+    name.split(';').next().unwrap_or_default().to_string()
+}
+
+/// Splits a system use area into (signature, data) pairs, the data starting
+/// after the 4-byte entry header.
+pub fn susp_entries(area: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos + 4 <= area.len() {
+        let len = area[pos + 2] as usize;
+        if len < 4 || pos + len > area.len() {
+            break;
+        }
+        let signature = &area[pos..pos + 2];
+        if signature == b"ST" {
+            break;
+        }
+        entries.push((signature, &area[pos + 4..pos + len]));
+        pos += len;
+    }
+    entries
+}
+
+/// A space-padded identifier, or `None` when it is blank.
+fn text(field: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(field);
+    let text = text.trim_end_matches([' ', '\0']);
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// Decodes a 17-byte volume date: "YYYYMMDDHHMMSShh" in ASCII, then the
+/// offset from GMT in 15-minute steps.
+fn date(field: &[u8]) -> Option<String> {
+    let digits = std::str::from_utf8(&field[..16]).ok()?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) || digits.starts_with("0000") {
+        return None;
+    }
+    let offset = (field[16] as i8) as i32 * 15;
+    Some(format!(
+        "{}-{}-{}T{}:{}:{}{}{:02}:{:02}",
+        &digits[0..4],
+        &digits[4..6],
+        &digits[6..8],
+        &digits[8..10],
+        &digits[10..12],
+        &digits[12..14],
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60
+    ))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("ISO 9660 volume: {}", message),
+    )
+}
@@ -0,0 +1,201 @@
+//! The MD5 that `implantisomd5` embeds in the Primary Volume Descriptor of
+//! Fedora, RHEL and related ISOs, checked the way `checkisomd5` does it.
+
+use super::iso9660::{self, SECTOR};
+use super::ReadAt;
+use crate::image::{self, bytes::le_u32};
+use md5::{Digest, Md5};
+use serde::Serialize;
+use std::io::{self, Read};
+
+/// Where the checksum is kept: the application use area of the PVD
+const APPLICATION_USE: u64 = 883;
+const APPLICATION_USE_LEN: u64 = 512;
+/// checkisomd5 hashes in blocks of 16 sectors, or of one fragment when that
+/// is shorter, and only checks fragment sums between blocks
+const HASH_BLOCK: usize = 16 * SECTOR as usize;
+/// Bytes read at a time, in whole sectors
+const READ_LEN: usize = 128 * HASH_BLOCK;
+/// The fragment sums share this many hex digits between them
+const FRAGMENT_SUMS_LEN: usize = 60;
+
+/// The checksum fields `implantisomd5` writes.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImplantedMd5 {
+    pub md5: String,
+    /// Sectors at the end of the volume left out of the checksum
+    pub skip_sectors: u64,
+    /// One short sum per fragment of the image, so damage is found early
+    pub fragment_sums: Option<String>,
+    pub fragment_count: u32,
+    /// Bytes covered, from the start of the image
+    pub length: u64,
+    /// Offset of the application use area, which is hashed as spaces
+    #[serde(skip)]
+    application_use: u64,
+}
+
+/// The outcome of checking an image or drive against its implanted MD5.
+#[derive(Debug, Clone, Serialize)]
+pub struct Md5Check {
+    pub expected: String,
+    /// Left unset when the check stopped at a bad fragment
+    pub actual: Option<String>,
+    pub passed: bool,
+    /// 1-based fragment whose sum did not match
+    pub failed_fragment: Option<u32>,
+    /// Bytes hashed before the check finished or stopped
+    pub bytes_checked: u64,
+}
+
+/// Reads the implanted checksum, or returns `None` when the image has no
+/// ISO 9660 volume or its PVD carries no checksum.
+pub fn read(disk: &mut dyn ReadAt) -> io::Result<Option<ImplantedMd5>> {
+    Ok(iso9660::read_descriptors(disk)?
+        .and_then(|descriptors| parse(&descriptors.primary, descriptors.primary_pos)))
+}
+
+/// Parses "ISO MD5SUM = ...;SKIPSECTORS = 15;RHLISOSTATUS=1;FRAGMENT SUMS =
+/// ...;FRAGMENT COUNT = 20;..." out of the application use area.
+pub fn parse(pvd: &[u8], pvd_pos: u64) -> Option<ImplantedMd5> {
+    let start = APPLICATION_USE as usize;
+    let area = String::from_utf8_lossy(&pvd[start..start + APPLICATION_USE_LEN as usize]);
+    let mut md5 = None;
+    let mut skip_sectors = 0;
+    let mut fragment_sums = None;
+    let mut fragment_count = 0;
+    for field in area.split(';') {
+        let Some((key, value)) = field.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "ISO MD5SUM" => md5 = Some(value.to_ascii_lowercase()),
+            "SKIPSECTORS" => skip_sectors = value.parse().ok()?,
+            "FRAGMENT SUMS" => fragment_sums = Some(value.to_ascii_lowercase()),
+            "FRAGMENT COUNT" => fragment_count = value.parse().ok()?,
+            _ => {}
+        }
+    }
+    let md5 = md5.filter(|md5| md5.len() == 32 && is_hex(md5))?;
+    // Each fragment's sum takes one digit from each of the first bytes of
+    // an MD5, so there have to be enough fragments to go round
+    let digits = FRAGMENT_SUMS_LEN
+        .checked_div(fragment_count as usize)
+        .unwrap_or(0);
+    let fragment_sums = fragment_sums.filter(|sums| {
+        (1..=16).contains(&digits) && sums.len() >= fragment_count as usize * digits && is_hex(sums)
+    });
+
+    let volume = le_u32(pvd, 80) as u64 * SECTOR;
+    Some(ImplantedMd5 {
+        md5,
+        skip_sectors,
+        fragment_count: if fragment_sums.is_some() {
+            fragment_count
+        } else {
+            0
+        },
+        fragment_sums,
+        length: volume.checked_sub(skip_sectors * SECTOR)?,
+        application_use: pvd_pos + APPLICATION_USE,
+    })
+}
+
+fn is_hex(text: &str) -> bool {
+    text.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Hashes the part of `data` the checksum covers, stopping at the first
+/// fragment that doesn't match. `progress` is told the bytes hashed so far.
+pub fn check(
+    data: &mut dyn Read,
+    implanted: &ImplantedMd5,
+    mut progress: impl FnMut(u64),
+) -> io::Result<Md5Check> {
+    let mut hasher = Md5::new();
+    let mut buffer = vec![0u8; READ_LEN];
+    let fragments = implanted.fragment_count as u64;
+    let digits = FRAGMENT_SUMS_LEN
+        .checked_div(implanted.fragment_count as usize)
+        .unwrap_or(0);
+    // Integer division first, exactly as checkisomd5 does; the rounding
+    // decides which block each fragment sum is checked after
+    let fragment_size = implanted.length / (fragments + 1);
+    let block_len = fragment_size.clamp(1, HASH_BLOCK as u64);
+    let mut previous_fragment = 0;
+    let mut pos = 0u64;
+
+    while pos < implanted.length {
+        // Whole buffers keep reads from a raw device sector-aligned
+        let read = image::read_up_to(data, &mut buffer)?;
+        let len = (read as u64).min(implanted.length - pos) as usize;
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "the data ends after {} bytes, short of the {} the checksum covers",
+                    pos, implanted.length
+                ),
+            ));
+        }
+
+        // The checksum was worked out with its own field blank
+        let start = implanted.application_use.max(pos);
+        let end = (implanted.application_use + APPLICATION_USE_LEN).min(pos + len as u64);
+        if start < end {
+            buffer[(start - pos) as usize..(end - pos) as usize].fill(b' ');
+        }
+
+        let mut rest = &buffer[..len];
+        while !rest.is_empty() {
+            // Reads are longer than a hash block and need not line up with one
+            let block_start = pos - pos % block_len;
+            let take = ((block_start + block_len - pos) as usize).min(rest.len());
+            hasher.update(&rest[..take]);
+            pos += take as u64;
+            rest = &rest[take..];
+            if !pos.is_multiple_of(block_len) && pos != implanted.length {
+                continue;
+            }
+
+            let fragment = block_start.checked_div(fragment_size).unwrap_or(0);
+            if let Some(sums) = &implanted.fragment_sums {
+                if fragment != previous_fragment {
+                    let expected = sums
+                        .get((fragment as usize - 1) * digits..)
+                        .and_then(|s| s.get(..digits));
+                    if expected.is_some_and(|expected| fragment_sum(&hasher, digits) != expected) {
+                        return Ok(Md5Check {
+                            expected: implanted.md5.clone(),
+                            actual: None,
+                            passed: false,
+                            failed_fragment: Some(fragment as u32),
+                            bytes_checked: pos,
+                        });
+                    }
+                    previous_fragment = fragment;
+                }
+            }
+        }
+        progress(pos);
+    }
+
+    let actual = format!("{:x}", hasher.finalize());
+    Ok(Md5Check {
+        passed: actual == implanted.md5,
+        expected: implanted.md5.clone(),
+        actual: Some(actual),
+        failed_fragment: None,
+        bytes_checked: pos,
+    })
+}
+
+/// The sum of everything hashed so far, as isomd5sum spells it: the first
+/// hex digit of each of the first `digits` bytes of the MD5.
+fn fragment_sum(hasher: &Md5, digits: usize) -> String {
+    hasher.clone().finalize()[..digits]
+        .iter()
+        .map(|byte| format!("{:x}", byte).remove(0))
+        .collect()
+}
@@ -0,0 +1,172 @@
+//! Looks at what a disk image holds, reading its structures directly rather
+//! than mounting it.
+
+pub mod arch;
+pub mod efi;
+pub mod eltorito;
+pub mod fat;
+pub mod fs;
+pub mod iso9660;
+pub mod isomd5;
+pub mod os;
+pub mod partitions;
+pub mod pe;
+pub mod probe;
+pub mod sums;
+pub mod udf;
+
+use crate::image::{self, ImageSource};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Random access to the bytes of a decoded image.
+pub trait ReadAt {
+    /// Reads at `pos` into `buf`, returning fewer bytes only at the end.
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// Reads exactly `buf.len()` bytes at `pos`.
+pub fn read_exact_at(disk: &mut dyn ReadAt, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+    if disk.read_at(pos, buf)? < buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("image ends before offset {}", pos + buf.len() as u64),
+        ));
+    }
+    Ok(())
+}
+
+/// Random access over an opened image. Seeks where the image format allows
+/// it; otherwise reads forward, reopening the image to go backwards. Blocks
+/// already read from such a stream are kept, so probes that go back over
+/// them don't decompress the image again.
+pub struct ImageReader {
+    path: String,
+    entry: Option<String>,
+    source: ImageSource,
+    pos: u64,
+    cache: HashMap<u64, Vec<u8>>,
+    /// Cached block indices, oldest first
+    cached: VecDeque<u64>,
+}
+
+impl ImageReader {
+    const BLOCK: u64 = 64 * 1024;
+    /// 32 MiB of blocks
+    const CACHE_BLOCKS: usize = 512;
+
+    /// `source` must be freshly opened from `path` and `entry`.
+    pub fn new(path: &str, entry: Option<&str>, source: ImageSource) -> Self {
+        Self {
+            path: path.to_string(),
+            entry: entry.map(str::to_string),
+            source,
+            pos: 0,
+            cache: HashMap::new(),
+            cached: VecDeque::new(),
+        }
+    }
+
+    pub fn source(&self) -> &ImageSource {
+        &self.source
+    }
+
+    fn move_to(&mut self, pos: u64) -> io::Result<()> {
+        if pos == self.pos || self.source.seek_to(pos)? {
+            self.pos = pos;
+            return Ok(());
+        }
+        if pos < self.pos {
+            self.source = image::open_image(&self.path, self.entry.as_deref())
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            self.pos = 0;
+        }
+        let skipped = io::copy(
+            &mut (&mut self.source).take(pos - self.pos),
+            &mut io::sink(),
+        )?;
+        self.pos += skipped;
+        Ok(())
+    }
+
+    fn read_direct(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.move_to(pos)?;
+        if self.pos < pos {
+            return Ok(0);
+        }
+        let n = image::read_up_to(&mut self.source, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// The block with index `index`, short or empty at the end of the image.
+    fn block(&mut self, index: u64) -> io::Result<&[u8]> {
+        if !self.cache.contains_key(&index) {
+            let mut block = vec![0u8; Self::BLOCK as usize];
+            let n = self.read_direct(index * Self::BLOCK, &mut block)?;
+            block.truncate(n);
+            if self.cached.len() == Self::CACHE_BLOCKS {
+                if let Some(oldest) = self.cached.pop_front() {
+                    self.cache.remove(&oldest);
+                }
+            }
+            self.cached.push_back(index);
+            self.cache.insert(index, block);
+        }
+        Ok(&self.cache[&index])
+    }
+}
+
+impl ReadAt for ImageReader {
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        // Large reads, such as whole files, would only churn the cache
+        if self.source.can_seek() || buf.len() as u64 > Self::BLOCK * 4 {
+            return self.read_direct(pos, buf);
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let offset = (at % Self::BLOCK) as usize;
+            let block = self.block(at / Self::BLOCK)?;
+            let n = block.len().saturating_sub(offset).min(buf.len() - done);
+            buf[done..done + n].copy_from_slice(&block[offset..offset + n]);
+            done += n;
+            if n == 0 || offset + n < Self::BLOCK as usize && done < buf.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+}
+
+/// Reads a block device directly. Raw devices on Windows and macOS only take
+/// reads of whole sectors, so every read is widened to aligned blocks.
+pub struct DeviceReader {
+    file: File,
+}
+
+impl DeviceReader {
+    /// Covers both 512-byte and 4K-native sectors
+    const ALIGN: u64 = 4096;
+
+    pub fn new(file: File) -> Self {
+        Self { file }
+    }
+}
+
+impl ReadAt for DeviceReader {
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let start = pos - pos % Self::ALIGN;
+        let end = (pos + buf.len() as u64).div_ceil(Self::ALIGN) * Self::ALIGN;
+        let mut block = vec![0u8; (end - start) as usize];
+        self.file.seek(SeekFrom::Start(start))?;
+        let read = image::read_up_to(&mut self.file, &mut block)?;
+
+        let skip = (pos - start) as usize;
+        let n = read.saturating_sub(skip).min(buf.len());
+        buf[..n].copy_from_slice(&block[skip..skip + n]);
+        Ok(n)
+    }
+}
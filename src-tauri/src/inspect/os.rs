@@ -0,0 +1,221 @@
+use super::fs::{lookup, read_file, FileSystem};
+use super::ReadAt;
+use serde::Serialize;
+use std::io;
+
+/// Files only Windows installation media carry.
+const WINDOWS_MARKERS: &[&str] = &["sources/install.wim", "sources/install.esd", "bootmgr"];
+/// Release files are a few lines; anything larger is not one
+const MAX_RELEASE_FILE: u64 = 64 * 1024;
+/// Architecture names as distributions spell them in release files
+const ARCHITECTURES: &[&str] = &[
+    "amd64", "x86_64", "i386", "i686", "arm64", "aarch64", "armhf", "armel", "armv7hl", "ppc64el",
+    "ppc64le", "s390x", "riscv64",
+];
+/// Windows build branches and the releases built from them
+const WINDOWS_BRANCHES: &[(&str, &str)] = &[
+    ("th1", "10 1507"),
+    ("th2", "10 1511"),
+    ("rs1", "10 1607"),
+    ("rs2", "10 1703"),
+    ("rs3", "10 1709"),
+    ("rs4", "10 1803"),
+    ("rs5", "10 1809"),
+    ("19h1", "10 1903"),
+    ("vb", "10 2004 or later"),
+    ("co", "11 21H2"),
+    ("ni", "11 22H2 or 23H2"),
+    ("ge", "11 24H2"),
+];
+
+type Parser = fn(&str) -> Option<Release>;
+
+/// Files that name the OS, most telling first
+const RELEASE_FILES: [(&str, Parser); 5] = [
+    (".disk/info", parse_disk_info),
+    (".treeinfo", parse_treeinfo),
+    ("sources/idwbinfo.txt", parse_idwbinfo),
+    ("etc/os-release", parse_os_release),
+    ("usr/lib/os-release", parse_os_release),
+];
+
+/// The operating system an image installs or boots.
+#[derive(Debug, Clone, Serialize)]
+pub struct OsInfo {
+    pub name: String,
+    pub version: Option<String>,
+    /// As the image spells it, e.g. "amd64" or "x86_64"
+    pub architecture: Option<String>,
+    /// Path of the file the details came from
+    pub source: String,
+    /// "casper" (Ubuntu and derivatives) or "live-boot" (Debian) for live
+    /// systems
+    pub live: Option<String>,
+}
+
+/// Checks a filesystem for Windows Setup.
+pub fn is_windows_installer(fs: &mut dyn FileSystem, disk: &mut dyn ReadAt) -> io::Result<bool> {
+    for marker in WINDOWS_MARKERS {
+        if lookup(fs, disk, marker)?.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Works out the operating system from the release files on a filesystem.
+pub fn identify(fs: &mut dyn FileSystem, disk: &mut dyn ReadAt) -> io::Result<Option<OsInfo>> {
+    let live = if lookup(fs, disk, "casper")?.is_some_and(|node| node.is_dir) {
+        Some("casper")
+    } else if lookup(fs, disk, "live/filesystem.squashfs")?.is_some() {
+        Some("live-boot")
+    } else {
+        None
+    };
+
+    for (path, parse) in RELEASE_FILES {
+        let node = match lookup(fs, disk, path)? {
+            Some(node) if !node.is_dir && node.size <= MAX_RELEASE_FILE => node,
+            _ => continue,
+        };
+        let text = read_file(disk, &node, MAX_RELEASE_FILE)?;
+        if let Some(release) = parse(&String::from_utf8_lossy(&text)) {
+            return Ok(Some(OsInfo {
+                name: release.name,
+                version: release.version,
+                architecture: release.architecture,
+                source: path.to_string(),
+                live: live.map(str::to_string),
+            }));
+        }
+    }
+
+    // Without a release file, the live system layout still narrows it down
+    Ok(live.map(|live| OsInfo {
+        name: String::from(if live == "casper" {
+            "Ubuntu-based live system"
+        } else {
+            "Debian-based live system"
+        }),
+        version: None,
+        architecture: None,
+        source: String::from(if live == "casper" {
+            "casper/"
+        } else {
+            "live/filesystem.squashfs"
+        }),
+        live: Some(live.to_string()),
+    }))
+}
+
+struct Release {
+    name: String,
+    version: Option<String>,
+    architecture: Option<String>,
+}
+
+/// Parses the one line Debian and Ubuntu media describe themselves with, e.g.
+/// `Ubuntu 22.04.3 LTS "Jammy Jellyfish" - Release amd64 (20230807.2)`.
+fn parse_disk_info(text: &str) -> Option<Release> {
+    let line = text.lines().next()?.trim();
+    let head = line.split(" - ").next()?;
+    let head = head.split('"').next()?.trim();
+
+    let words: Vec<&str> = head.split_whitespace().collect();
+    let version_at = words
+        .iter()
+        .position(|word| word.starts_with(|c: char| c.is_ascii_digit()));
+    let (name, version) = match version_at {
+        Some(0) | None => (head.to_string(), None),
+        Some(at) => (words[..at].join(" "), Some(words[at..].join(" "))),
+    };
+    if name.is_empty() {
+        return None;
+    }
+    Some(Release {
+        name,
+        version,
+        architecture: find_architecture(line),
+    })
+}
+
+/// Parses the INI-style `.treeinfo` of Fedora, RHEL and their rebuilds.
+fn parse_treeinfo(text: &str) -> Option<Release> {
+    let value = |section: &str, key: &str| ini_value(text, section, key);
+    let name = value("release", "name").or_else(|| value("general", "family"))?;
+    Some(Release {
+        name,
+        version: value("release", "version").or_else(|| value("general", "version")),
+        architecture: value("tree", "arch").or_else(|| value("general", "arch")),
+    })
+}
+
+/// Parses the build information Windows Setup media carry.
+fn parse_idwbinfo(text: &str) -> Option<Release> {
+    let branch = ini_value(text, "BUILDINFO", "BuildBranch");
+    let architecture = ini_value(text, "BUILDINFO", "BuildArch");
+    if branch.is_none() && architecture.is_none() {
+        return None;
+    }
+    let version = branch.map(|branch| {
+        let prefix = branch.split('_').next().unwrap_or_default();
+        WINDOWS_BRANCHES
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(prefix))
+            .map(|(_, release)| release.to_string())
+            .unwrap_or(branch)
+    });
+    Some(Release {
+        name: String::from("Windows"),
+        version,
+        architecture,
+    })
+}
+
+/// Parses the freedesktop.org os-release file.
+fn parse_os_release(text: &str) -> Option<Release> {
+    let mut name = None;
+    let mut version = None;
+    let mut version_id = None;
+    for line in text.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let value = value.trim().trim_matches(['"', '\'']).to_string();
+        match key.trim() {
+            "NAME" => name = Some(value),
+            "VERSION" => version = Some(value),
+            "VERSION_ID" => version_id = Some(value),
+            _ => {}
+        }
+    }
+    Some(Release {
+        name: name?,
+        version: version.or(version_id),
+        architecture: None,
+    })
+}
+
+/// Looks up `key` in `[section]` of an INI file, ignoring case.
+fn ini_value(text: &str, section: &str, key: &str) -> Option<String> {
+    let mut current = String::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = name.trim().to_string();
+        } else if let Some((k, v)) = line.split_once('=') {
+            if current.eq_ignore_ascii_case(section) && k.trim().eq_ignore_ascii_case(key) {
+                let v = v.trim();
+                return (!v.is_empty()).then(|| v.to_string());
+            }
+        }
+    }
+    None
+}
+
+fn find_architecture(text: &str) -> Option<String> {
+    text.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .find(|word| ARCHITECTURES.contains(word))
+        .map(str::to_string)
+}
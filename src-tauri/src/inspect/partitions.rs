@@ -0,0 +1,604 @@
+use super::probe::{self, FsInfo};
+use super::{read_exact_at, ReadAt};
+use crate::image::bytes::{le_u16, le_u32, le_u64};
+use serde::Serialize;
+use std::io;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// GPT headers sit at LBA 1, so their position gives away the sector size
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+const GPT_HEADER_MIN_LEN: u32 = 92;
+const MAX_GPT_ENTRIES: u32 = 1024;
+/// Logical partitions followed along an extended partition's chain
+const MAX_LOGICAL: usize = 128;
+
+const MBR_PROTECTIVE: u8 = 0xEE;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// MBR entries that hold other partitions or guard a GPT, as `type_id`s
+const NOT_FILESYSTEMS: [&str; 4] = ["0x05", "0x0f", "0x85", "0xee"];
+
+/// The partitions of an image or device, from its GPT when it has one and
+/// its MBR otherwise.
+#[derive(Debug, Clone, Serialize)]
+pub struct PartitionTable {
+    /// "GPT" or "MBR"
+    pub scheme: String,
+    pub sector_size: u64,
+    pub disk_guid: Option<String>,
+    /// For GPT disks: "protective" when the MBR only guards the GPT,
+    /// "hybrid" when it also lists partitions of its own, "none" when
+    /// there is no MBR at all
+    pub mbr: Option<String>,
+    pub partitions: Vec<Partition>,
+    /// Bytes a disk needs to hold every partition and the backup GPT
+    pub required_size: u64,
+    /// Damage that makes the table unusable.
+    pub errors: Vec<String>,
+    /// Problems a redundant copy made up for, and inconsistencies.
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Partition {
+    /// 1-based, as Linux numbers them; MBR logical partitions start at 5
+    pub index: u32,
+    /// Type GUID for GPT, the type byte in hex (e.g. "0x0c") for MBR
+    pub type_id: String,
+    pub type_name: Option<String>,
+    /// Offset and length in bytes
+    pub start: u64,
+    pub size: u64,
+    pub name: Option<String>,
+    /// GPT attribute bits; for MBR, the status byte (0x80 marks it bootable)
+    pub attributes: u64,
+    pub guid: Option<String>,
+    /// What the partition holds, when its superblock was recognised
+    pub filesystem: Option<FsInfo>,
+}
+
+impl Partition {
+    pub fn end(&self) -> u64 {
+        self.start.saturating_add(self.size)
+    }
+}
+
+/// Reads the partition table and probes the filesystem of each partition,
+/// or returns `None` when there is neither an MBR nor a GPT. `disk_size` is
+/// needed to find and check the backup GPT; leave it out when reading the
+/// end of the disk means decoding all of it.
+pub fn read_table(
+    disk: &mut dyn ReadAt,
+    disk_size: Option<u64>,
+) -> io::Result<Option<PartitionTable>> {
+    let mut table = match read_partitions(disk, disk_size)? {
+        Some(table) => table,
+        None => return Ok(None),
+    };
+    for partition in &table.partitions {
+        table.required_size = table.required_size.max(partition.end());
+    }
+
+    // In disk order, so images that can't seek are read through only once
+    let mut order: Vec<usize> = (0..table.partitions.len()).collect();
+    order.sort_by_key(|&i| table.partitions[i].start);
+    for i in order {
+        let partition = &mut table.partitions[i];
+        if NOT_FILESYSTEMS.contains(&partition.type_id.as_str()) {
+            continue;
+        }
+        match probe::probe(disk, partition.start, partition.size) {
+            Ok(filesystem) => partition.filesystem = filesystem,
+            Err(e) => table.warnings.push(format!(
+                "Could not read partition {}: {}",
+                partition.index, e
+            )),
+        }
+    }
+    Ok(Some(table))
+}
+
+fn read_partitions(
+    disk: &mut dyn ReadAt,
+    disk_size: Option<u64>,
+) -> io::Result<Option<PartitionTable>> {
+    let mut mbr = [0u8; 512];
+    if disk.read_at(0, &mut mbr)? < mbr.len() {
+        return Ok(None);
+    }
+    let has_mbr = mbr[510..512] == MBR_SIGNATURE;
+
+    for sector_size in SECTOR_SIZES {
+        let mut header = vec![0u8; sector_size as usize];
+        if disk.read_at(sector_size, &mut header)? == header.len()
+            && header.starts_with(GPT_SIGNATURE)
+        {
+            let mut table = read_gpt(disk, sector_size, disk_size)?;
+            check_mbr(&mut table, has_mbr.then_some(&mbr[..]));
+            return Ok(Some(table));
+        }
+    }
+    // A FAT superfloppy has its boot sector at LBA 0 and no partition
+    // table; its boot code would read as bogus MBR entries
+    if probe::is_fat(&mbr) {
+        return Ok(None);
+    }
+
+    // A primary GPT header can be wiped while the backup survives
+    if has_mbr && mbr_entries(&mbr).any(|entry| entry[4] == MBR_PROTECTIVE) {
+        let mut table = read_gpt(disk, 512, disk_size)?;
+        check_mbr(&mut table, Some(&mbr[..]));
+        return Ok(Some(table));
+    }
+
+    if !has_mbr {
+        return Ok(None);
+    }
+    read_mbr(disk, &mbr).map(Some)
+}
+
+fn mbr_entries(mbr: &[u8]) -> impl Iterator<Item = &[u8]> {
+    mbr[446..510].chunks_exact(16).filter(|entry| entry[4] != 0)
+}
+
+fn read_mbr(disk: &mut dyn ReadAt, mbr: &[u8]) -> io::Result<PartitionTable> {
+    let mut table = PartitionTable {
+        scheme: "MBR".to_string(),
+        sector_size: 512,
+        disk_guid: None,
+        mbr: None,
+        partitions: Vec::new(),
+        required_size: 0,
+        errors: Vec::new(),
+        warnings: Vec::new(),
+    };
+
+    let mut extended = None;
+    for (i, entry) in mbr[446..510].chunks_exact(16).enumerate() {
+        if entry[4] == 0 {
+            continue;
+        }
+        let partition = mbr_partition(i as u32 + 1, entry, 0);
+        if MBR_EXTENDED.contains(&entry[4]) && extended.is_none() {
+            extended = Some(partition.start / 512);
+        }
+        table.partitions.push(partition);
+    }
+
+    // Each EBR holds one logical partition and a link to the next EBR,
+    // both relative to the start of the extended partition
+    if let Some(extended_start) = extended {
+        let mut ebr_lba = extended_start;
+        let mut index = 5;
+        for _ in 0..MAX_LOGICAL {
+            let mut ebr = [0u8; 512];
+            if let Err(e) = read_exact_at(disk, ebr_lba * 512, &mut ebr) {
+                table
+                    .warnings
+                    .push(format!("Could not read a logical partition: {}", e));
+                break;
+            }
+            if ebr[510..512] != MBR_SIGNATURE {
+                table
+                    .warnings
+                    .push("The chain of logical partitions is broken.".to_string());
+                break;
+            }
+            if ebr[446 + 4] != 0 {
+                table
+                    .partitions
+                    .push(mbr_partition(index, &ebr[446..462], ebr_lba));
+                index += 1;
+            }
+            let next = &ebr[462..478];
+            if next[4] == 0 {
+                break;
+            }
+            ebr_lba = extended_start + le_u32(next, 8) as u64;
+        }
+    }
+
+    for partition in &table.partitions {
+        if partition.size == 0 {
+            table.warnings.push(format!(
+                "MBR partition {} has a length of zero.",
+                partition.index
+            ));
+        }
+    }
+    Ok(table)
+}
+
+fn mbr_partition(index: u32, entry: &[u8], base_lba: u64) -> Partition {
+    let type_byte = entry[4];
+    Partition {
+        index,
+        type_id: format!("{:#04x}", type_byte),
+        type_name: mbr_type_name(type_byte).map(str::to_string),
+        start: (base_lba + le_u32(entry, 8) as u64) * 512,
+        size: le_u32(entry, 12) as u64 * 512,
+        name: None,
+        attributes: entry[0] as u64,
+        guid: None,
+        filesystem: None,
+    }
+}
+
+#[derive(Debug, Clone)]
+struct GptHeader {
+    current_lba: u64,
+    backup_lba: u64,
+    first_usable: u64,
+    last_usable: u64,
+    disk_guid: String,
+    entries_lba: u64,
+    entry_count: u32,
+    entry_len: u32,
+    entries_crc: u32,
+}
+
+/// Parses a GPT header, checking its signature and CRC.
+fn parse_gpt_header(sector: &[u8]) -> Result<GptHeader, String> {
+    if !sector.starts_with(GPT_SIGNATURE) {
+        return Err("signature is missing".to_string());
+    }
+    let header_len = le_u32(sector, 12);
+    if header_len < GPT_HEADER_MIN_LEN || header_len as usize > sector.len() {
+        return Err(format!("header size {} is invalid", header_len));
+    }
+    let mut header = sector[..header_len as usize].to_vec();
+    let stored_crc = le_u32(&header, 16);
+    header[16..20].fill(0);
+    if crc32fast::hash(&header) != stored_crc {
+        return Err("header CRC32 does not match".to_string());
+    }
+
+    let entry_count = le_u32(sector, 80);
+    let entry_len = le_u32(sector, 84);
+    if entry_count > MAX_GPT_ENTRIES
+        || entry_len < 128
+        || !entry_len.is_multiple_of(8)
+        || entry_len > 1024
+    {
+        return Err("partition array size is invalid".to_string());
+    }
+    Ok(GptHeader {
+        current_lba: le_u64(sector, 24),
+        backup_lba: le_u64(sector, 32),
+        first_usable: le_u64(sector, 40),
+        last_usable: le_u64(sector, 48),
+        disk_guid: guid(&sector[56..72]),
+        entries_lba: le_u64(sector, 72),
+        entry_count,
+        entry_len,
+        entries_crc: le_u32(sector, 88),
+    })
+}
+
+/// Reads a header and its partition array, checking both CRCs.
+fn read_gpt_copy(
+    disk: &mut dyn ReadAt,
+    sector_size: u64,
+    lba: u64,
+) -> Result<(GptHeader, Vec<u8>), String> {
+    let pos = lba
+        .checked_mul(sector_size)
+        .ok_or_else(|| format!("LBA {} is out of range", lba))?;
+    let mut sector = vec![0u8; sector_size as usize];
+    read_exact_at(disk, pos, &mut sector).map_err(|e| e.to_string())?;
+    let header = parse_gpt_header(&sector)?;
+    if header.current_lba != lba {
+        return Err(format!(
+            "header says it is at LBA {}, but it was found at LBA {}",
+            header.current_lba, lba
+        ));
+    }
+    let entries_pos = header
+        .entries_lba
+        .checked_mul(sector_size)
+        .ok_or_else(|| format!("partition array LBA {} is out of range", header.entries_lba))?;
+    let mut entries = vec![0u8; (header.entry_count * header.entry_len) as usize];
+    read_exact_at(disk, entries_pos, &mut entries)
+        .map_err(|e| format!("partition array is unreadable: {}", e))?;
+    if crc32fast::hash(&entries) != header.entries_crc {
+        return Err("partition array CRC32 does not match".to_string());
+    }
+    Ok((header, entries))
+}
+
+fn read_gpt(
+    disk: &mut dyn ReadAt,
+    sector_size: u64,
+    disk_size: Option<u64>,
+) -> io::Result<PartitionTable> {
+    let mut table = PartitionTable {
+        scheme: "GPT".to_string(),
+        sector_size,
+        disk_guid: None,
+        mbr: None,
+        partitions: Vec::new(),
+        required_size: 0,
+        errors: Vec::new(),
+        warnings: Vec::new(),
+    };
+    let last_lba = disk_size.and_then(|size| (size / sector_size).checked_sub(1));
+
+    let primary = read_gpt_copy(disk, sector_size, 1);
+    // The backup sits in the last sector; the primary says where that is.
+    // Without the size, seeking there could mean decoding the whole image
+    let backup = match last_lba {
+        Some(last) => {
+            let backup_lba = match &primary {
+                Ok((header, _)) => header.backup_lba,
+                Err(_) => last,
+            };
+            // Past the end it is missing rather than damaged, which is
+            // reported below
+            (backup_lba <= last).then(|| read_gpt_copy(disk, sector_size, backup_lba))
+        }
+        None => {
+            table.warnings.push(
+                "The backup GPT was not checked: the image would have to be decompressed to reach it."
+                    .to_string(),
+            );
+            None
+        }
+    };
+
+    let (header, entries) = match (primary, backup) {
+        (Ok(primary), Some(Ok((backup, backup_entries)))) => {
+            if backup_entries != primary.1 || backup.backup_lba != 1 {
+                table
+                    .warnings
+                    .push("The backup GPT does not match the primary GPT.".to_string());
+            }
+            primary
+        }
+        (Ok(primary), Some(Err(e))) => {
+            table
+                .warnings
+                .push(format!("The backup GPT header is damaged: {}.", e));
+            primary
+        }
+        (Ok(primary), None) => primary,
+        (Err(e), Some(Ok(backup))) => {
+            table.warnings.push(format!(
+                "The primary GPT is damaged ({}); using the backup copy.",
+                e
+            ));
+            backup
+        }
+        (Err(e), _) => {
+            table.errors.push(format!("The GPT is damaged: {}.", e));
+            return Ok(table);
+        }
+    };
+
+    let backup_lba = if header.current_lba == 1 {
+        header.backup_lba
+    } else {
+        header.current_lba
+    };
+    match backup_lba
+        .checked_add(1)
+        .and_then(|lbas| lbas.checked_mul(sector_size))
+    {
+        Some(size) => table.required_size = size,
+        None => table.errors.push(format!(
+            "The backup GPT header's LBA {} is out of range.",
+            backup_lba
+        )),
+    }
+    // A backup past the end means the disk is too small, which callers
+    // check against `required_size`
+    if let Some(last_lba) = last_lba {
+        if backup_lba < last_lba {
+            table.warnings.push(format!(
+                "The backup GPT header is at LBA {}, not in the last sector (LBA {}).",
+                backup_lba, last_lba
+            ));
+        }
+    }
+
+    table.disk_guid = Some(header.disk_guid.clone());
+    for (i, entry) in entries.chunks_exact(header.entry_len as usize).enumerate() {
+        let type_guid = &entry[..16];
+        if type_guid.iter().all(|&b| b == 0) {
+            continue;
+        }
+        let first = le_u64(entry, 32);
+        let last = le_u64(entry, 40);
+        if last < first {
+            table
+                .warnings
+                .push(format!("GPT partition {} ends before it starts.", i + 1));
+            continue;
+        }
+        let (Some(start), Some(size)) = (
+            first.checked_mul(sector_size),
+            (last - first)
+                .checked_add(1)
+                .and_then(|lbas| lbas.checked_mul(sector_size)),
+        ) else {
+            table
+                .warnings
+                .push(format!("GPT partition {} is out of range.", i + 1));
+            continue;
+        };
+        if first < header.first_usable || last > header.last_usable {
+            table.warnings.push(format!(
+                "GPT partition {} lies outside the usable area of the disk.",
+                i + 1
+            ));
+        }
+        let type_id = guid(type_guid);
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|pair| le_u16(pair, 0))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        let name = String::from_utf16_lossy(&name);
+        table.partitions.push(Partition {
+            index: i as u32 + 1,
+            type_name: gpt_type_name(&type_id).map(str::to_string),
+            type_id,
+            start,
+            size,
+            name: (!name.is_empty()).then_some(name),
+            attributes: le_u64(entry, 48),
+            guid: Some(guid(&entry[16..32])),
+            filesystem: None,
+        });
+    }
+    Ok(table)
+}
+
+/// Works out what the MBR of a GPT disk is and flags entries that disagree
+/// with the GPT.
+fn check_mbr(table: &mut PartitionTable, mbr: Option<&[u8]>) {
+    let mbr = match mbr {
+        Some(mbr) => mbr,
+        None => {
+            table.mbr = Some("none".to_string());
+            table
+                .warnings
+                .push("The GPT disk has no protective MBR.".to_string());
+            return;
+        }
+    };
+
+    let entries: Vec<&[u8]> = mbr_entries(mbr).collect();
+    let protective: Vec<&&[u8]> = entries
+        .iter()
+        .filter(|entry| entry[4] == MBR_PROTECTIVE)
+        .collect();
+    let others: Vec<&&[u8]> = entries
+        .iter()
+        .filter(|entry| entry[4] != MBR_PROTECTIVE)
+        .collect();
+
+    table.mbr = Some(
+        if others.is_empty() {
+            "protective"
+        } else {
+            "hybrid"
+        }
+        .to_string(),
+    );
+    if entries.is_empty() {
+        table.warnings.push(
+            "The MBR of this GPT disk is empty, so tools that only know MBR will see no partitions."
+                .to_string(),
+        );
+    }
+    for entry in &protective {
+        if le_u32(entry, 8) != 1 {
+            table.warnings.push(format!(
+                "The protective MBR entry starts at LBA {} instead of LBA 1.",
+                le_u32(entry, 8)
+            ));
+        }
+    }
+
+    // A hybrid MBR entry should mirror a GPT partition, or it points at
+    // data the GPT does not know about
+    let sector_size = table.sector_size;
+    for entry in &others {
+        let start = le_u32(entry, 8) as u64 * sector_size;
+        let size = le_u32(entry, 12) as u64 * sector_size;
+        let end = start + size;
+        let matches = table
+            .partitions
+            .iter()
+            .any(|p| p.start == start && p.end() == end);
+        // isohybrid covers the whole ISO from sector 0 with one entry
+        if !matches && start != 0 {
+            table.warnings.push(format!(
+                "Hybrid MBR entry (type {:#04x}, {} bytes at offset {}) does not match any GPT partition.",
+                entry[4], size, start
+            ));
+        }
+    }
+}
+
+/// Formats a GUID as stored on disk: the first three fields little-endian.
+fn guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        le_u32(bytes, 0),
+        le_u16(bytes, 4),
+        le_u16(bytes, 6),
+        bytes[8],
+        bytes[9],
+        bytes[10..16]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>()
+    )
+}
+
+fn mbr_type_name(type_byte: u8) -> Option<&'static str> {
+    Some(match type_byte {
+        0x01 => "FAT12",
+        0x04 => "FAT16 (<32 MB)",
+        0x05 => "Extended",
+        0x06 => "FAT16",
+        0x07 => "NTFS/exFAT",
+        0x0B => "FAT32 (CHS)",
+        0x0C => "FAT32 (LBA)",
+        0x0E => "FAT16 (LBA)",
+        0x0F => "Extended (LBA)",
+        0x11 => "Hidden FAT12",
+        0x14 => "Hidden FAT16 (<32 MB)",
+        0x16 => "Hidden FAT16",
+        0x17 => "Hidden NTFS/exFAT",
+        0x1B => "Hidden FAT32 (CHS)",
+        0x1C => "Hidden FAT32 (LBA)",
+        0x1E => "Hidden FAT16 (LBA)",
+        0x27 => "Windows recovery",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x85 => "Linux extended",
+        0x8E => "Linux LVM",
+        0xA5 => "FreeBSD",
+        0xA6 => "OpenBSD",
+        0xA9 => "NetBSD",
+        0xAB => "Apple boot",
+        0xAF => "Apple HFS/HFS+",
+        0xEE => "GPT protective",
+        0xEF => "EFI System",
+        0xFD => "Linux RAID",
+        _ => return None,
+    })
+}
+
+fn gpt_type_name(type_guid: &str) -> Option<&'static str> {
+    Some(match type_guid {
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B" => "EFI System",
+        "21686148-6449-6E6F-744E-656564454649" => "BIOS boot",
+        "024DEE41-33E7-11D3-9D69-0008C781F39F" => "MBR partition scheme",
+        "E3C9E316-0B5C-4DB8-817D-F92DF00215AE" => "Microsoft reserved",
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => "Microsoft basic data",
+        "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC" => "Windows recovery",
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => "Linux filesystem",
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => "Linux swap",
+        "E6D6D379-F507-44C2-A23C-238F2A3DF928" => "Linux LVM",
+        "A19D880F-05FC-4D3B-A006-743F0F84911E" => "Linux RAID",
+        "933AC7E1-2EB4-4F13-B844-0E14E2AEF915" => "Linux home",
+        "BC13C2FF-59E6-4262-A352-B275FD6F7172" => "Linux extended boot",
+        "44479540-F297-41B2-9AF7-D131D5F0458A" => "Linux root (x86)",
+        "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709" => "Linux root (x86-64)",
+        "69DAD710-2CE4-4E3C-B16C-21A1D49ABED3" => "Linux root (ARM)",
+        "B921B045-1DF0-41C3-AF44-4C6F280D3FAE" => "Linux root (ARM64)",
+        "72EC70A6-CF74-40E6-BD49-4BDA08E8F224" => "Linux root (RISC-V 64)",
+        "48465300-0000-11AA-AA11-00306543ECAC" => "Apple HFS+",
+        "7C3457EF-0000-11AA-AA11-00306543ECAC" => "Apple APFS",
+        "426F6F74-0000-11AA-AA11-00306543ECAC" => "Apple boot",
+        "516E7CB4-6ECF-11D6-8FF8-00022D09712B" => "FreeBSD data",
+        "83BD6B9D-7F41-11DC-BE0B-001560B84F0F" => "FreeBSD boot",
+        "9E1A2D38-C612-4316-AA26-8B49521E5A8B" => "PReP boot",
+        "FE3A2A5D-4F32-41A7-B725-ACCC3285A309" => "ChromeOS kernel",
+        "3CB8E202-3B7E-47DD-8A3C-7FF2A13CFCEC" => "ChromeOS root",
+        _ => return None,
+    })
+}
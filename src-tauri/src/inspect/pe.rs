@@ -0,0 +1,225 @@
+//! Just enough of PE/COFF and Authenticode to tell how a UEFI bootloader is
+//! built and who signed it.
+
+use crate::image::bytes::{le_u16, le_u32};
+
+const PE32: u16 = 0x10B;
+const PE32_PLUS: u16 = 0x20B;
+/// The certificate table is the fifth data directory
+const SECURITY_DIRECTORY: usize = 4;
+const CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+/// OID 2.5.4.3, commonName
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// OID 2.5.4.10, organizationName
+const OID_ORGANIZATION: &[u8] = &[0x55, 0x04, 0x0A];
+
+/// What the headers of a PE image say.
+#[derive(Debug, Clone)]
+pub struct PeHeaders {
+    pub machine: u16,
+    pub sections: Vec<String>,
+    /// File offset and length of the certificate table, if there is one
+    pub certificates: Option<(u64, u32)>,
+}
+
+/// Parses the headers at the start of a PE file. `head` must cover the
+/// section table; 4 KiB does for any bootloader.
+pub fn parse_headers(head: &[u8]) -> Option<PeHeaders> {
+    if head.get(0..2)? != b"MZ" {
+        return None;
+    }
+    let pe = le_u32(head.get(0x3C..0x40)?, 0) as usize;
+    if head.get(pe..pe + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let coff = head.get(pe + 4..pe + 24)?;
+    let machine = le_u16(coff, 0);
+    let section_count = le_u16(coff, 2) as usize;
+    let optional_len = le_u16(coff, 16) as usize;
+    let optional = head.get(pe + 24..pe + 24 + optional_len)?;
+
+    let directories = match le_u16(optional.get(0..2)?, 0) {
+        PE32 => 96,
+        PE32_PLUS => 112,
+        _ => return None,
+    };
+    let directory_count = le_u32(optional.get(directories - 4..directories)?, 0) as usize;
+    let certificates = if directory_count > SECURITY_DIRECTORY {
+        let entry = optional
+            .get(directories + SECURITY_DIRECTORY * 8..)?
+            .get(..8)?;
+        // Unlike the other directories this one holds a file offset
+        let (offset, len) = (le_u32(entry, 0), le_u32(entry, 4));
+        (offset != 0 && len != 0).then_some((offset as u64, len))
+    } else {
+        None
+    };
+
+    let table = pe + 24 + optional_len;
+    let sections = (0..section_count)
+        .map_while(|i| head.get(table + i * 40..table + i * 40 + 8))
+        .map(|name| {
+            let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..end]).into_owned()
+        })
+        .collect();
+
+    Some(PeHeaders {
+        machine,
+        sections,
+        certificates,
+    })
+}
+
+/// The UEFI name of a COFF machine type, as used in `BOOT<name>.EFI`.
+pub fn machine_name(machine: u16) -> Option<&'static str> {
+    Some(match machine {
+        0x014C => "ia32",
+        0x8664 => "x64",
+        0x01C2 | 0x01C4 => "arm",
+        0xAA64 => "aa64",
+        0x5064 => "riscv64",
+        0x6264 => "loongarch64",
+        _ => return None,
+    })
+}
+
+/// The certificate that made an Authenticode signature.
+#[derive(Debug, Clone)]
+pub struct Signer {
+    /// Common name, or organization when there is none
+    pub subject: Option<String>,
+    pub issuer: Option<String>,
+}
+
+/// Finds the signer of the first PKCS#7 signature in a certificate table.
+pub fn parse_signer(table: &[u8]) -> Option<Signer> {
+    // WIN_CERTIFICATE: length, revision, type, then the certificate itself
+    let len = (le_u32(table.get(0..4)?, 0) as usize).min(table.len());
+    if len < 8 || le_u16(table, 6) != CERT_TYPE_PKCS_SIGNED_DATA {
+        return None;
+    }
+    let content_info = Der::new(&table[8..len]).sequence()?;
+
+    // ContentInfo { contentType, [0] SignedData }
+    let mut fields = Der::new(content_info);
+    fields.next()?;
+    let signed_data = Der::new(fields.next()?.1).sequence()?;
+    // SignedData { version, digestAlgorithms, encapContentInfo,
+    // [0] certificates, [1] crls, signerInfos }
+    let mut fields = Der::new(signed_data);
+    fields.next()?;
+    fields.next()?;
+    fields.next()?;
+    let mut certificates = None;
+    let mut signer_infos = None;
+    for (tag, value) in fields {
+        match tag {
+            0xA0 => certificates = Some(value),
+            0x31 => signer_infos = Some(value),
+            _ => {}
+        }
+    }
+
+    // SignerInfo { version, issuerAndSerialNumber { issuer, serial }, ... }
+    let signer_info = Der::new(signer_infos?).sequence()?;
+    let mut fields = Der::new(signer_info);
+    fields.next()?;
+    let mut sid = Der::new(fields.next()?.1);
+    sid.next()?;
+    let serial = sid.next()?.1;
+
+    for (_, certificate) in Der::new(certificates?) {
+        // Certificate { tbsCertificate { [0] version, serial, signature,
+        // issuer, validity, subject, ... }, ... }
+        let tbs = match Der::new(certificate).sequence() {
+            Some(tbs) => tbs,
+            None => continue,
+        };
+        let mut fields = Der::new(tbs);
+        let mut field = fields.next()?;
+        if field.0 == 0xA0 {
+            field = fields.next()?;
+        }
+        if field.1 != serial {
+            continue;
+        }
+        fields.next()?;
+        let issuer = fields.next()?.1;
+        fields.next()?;
+        let subject = fields.next()?.1;
+        return Some(Signer {
+            subject: name(subject),
+            issuer: name(issuer),
+        });
+    }
+    None
+}
+
+/// Picks the common name, or failing that the organization, out of an X.501
+/// name.
+fn name(rdns: &[u8]) -> Option<String> {
+    let mut common_name = None;
+    let mut organization = None;
+    for (_, set) in Der::new(rdns) {
+        for (_, attribute) in Der::new(set) {
+            let mut parts = Der::new(attribute);
+            let (_, oid) = parts.next()?;
+            let (_, value) = parts.next()?;
+            let value = String::from_utf8_lossy(value).into_owned();
+            if oid == OID_COMMON_NAME {
+                common_name = Some(value);
+            } else if oid == OID_ORGANIZATION {
+                organization = Some(value);
+            }
+        }
+    }
+    common_name.or(organization)
+}
+
+/// Walks the elements of DER-encoded data.
+struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// The contents of a leading SEQUENCE.
+    fn sequence(mut self) -> Option<&'a [u8]> {
+        match self.next()? {
+            (0x30, value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Iterator for Der<'a> {
+    /// Tag and contents
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let tag = *self.data.first()?;
+        let first = *self.data.get(1)? as usize;
+        let (len, header) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let count = first & 0x7F;
+            if count == 0 || count > 4 {
+                return None;
+            }
+            let len = self
+                .data
+                .get(2..2 + count)?
+                .iter()
+                .fold(0usize, |len, &b| len << 8 | b as usize);
+            (len, 2 + count)
+        };
+        let value = self.data.get(header..header.checked_add(len)?)?;
+        self.data = &self.data[header + len..];
+        Some((tag, value))
+    }
+}
@@ -0,0 +1,399 @@
+use super::{read_exact_at, ReadAt};
+use crate::image::bytes::{be_u32, be_u64, le_u16, le_u32, le_u64};
+use serde::Serialize;
+use std::io;
+
+/// Every superblock probed lies in this much of the start of a volume; the
+/// furthest out is btrfs's, at 64 KiB
+const WINDOW: u64 = 0x11000;
+/// Page sizes a swap signature may end
+const SWAP_PAGE_SIZES: [usize; 4] = [4096, 8192, 16384, 65536];
+/// Bytes of a FAT or exFAT root directory scanned for the volume label
+const MAX_ROOT_DIR: usize = 64 * 1024;
+
+/// What a superblock says about a filesystem, as blkid would report it.
+#[derive(Debug, Clone, Serialize)]
+pub struct FsInfo {
+    /// "FAT12", "FAT16", "FAT32", "exFAT", "NTFS", "ext2", "ext3", "ext4",
+    /// "btrfs", "XFS", "squashfs", "ISO 9660" or "swap"
+    pub fs_type: String,
+    pub label: Option<String>,
+    /// In the form blkid prints, e.g. "1234-ABCD" for FAT volumes
+    pub uuid: Option<String>,
+    /// Size of the filesystem in bytes, which can be less than its partition
+    pub size: Option<u64>,
+}
+
+impl FsInfo {
+    fn new(fs_type: &str) -> Self {
+        Self {
+            fs_type: fs_type.to_string(),
+            label: None,
+            uuid: None,
+            size: None,
+        }
+    }
+}
+
+/// Looks for a filesystem in the `len` bytes at `start`.
+pub fn probe(disk: &mut dyn ReadAt, start: u64, len: u64) -> io::Result<Option<FsInfo>> {
+    let mut window = vec![0u8; WINDOW.min(len) as usize];
+    let read = disk.read_at(start, &mut window)?;
+    window.truncate(read);
+    let w = &window[..];
+
+    // Superblocks at the very start come first: some filesystems leave the
+    // areas others use untouched, so stale signatures can linger there
+    if w.len() >= 512 {
+        if &w[3..11] == b"EXFAT   " {
+            return probe_exfat(disk, start, w).map(Some);
+        }
+        if &w[3..11] == b"NTFS    " {
+            return probe_ntfs(disk, start, w).map(Some);
+        }
+        if &w[0..4] == b"XFSB" {
+            return Ok(Some(probe_xfs(w)));
+        }
+        if &w[0..4] == b"hsqs" {
+            return Ok(Some(probe_squashfs(w)));
+        }
+        if is_fat(w) {
+            return probe_fat(disk, start, w).map(Some);
+        }
+    }
+    if w.len() >= 2048 && le_u16(w, 1024 + 0x38) == 0xEF53 {
+        return Ok(Some(probe_ext(w)));
+    }
+    if w.len() >= 0x10000 + 0x22B && &w[0x10040..0x10048] == b"_BHRfS_M" {
+        return Ok(Some(probe_btrfs(&w[0x10000..])));
+    }
+    if w.len() >= 32768 + 2048 && &w[32769..32774] == b"CD001" {
+        return Ok(Some(probe_iso9660(&w[32768..32768 + 2048])));
+    }
+    for page_size in SWAP_PAGE_SIZES {
+        if w.len() >= page_size {
+            let signature = &w[page_size - 10..page_size];
+            if signature == b"SWAPSPACE2" || signature == b"SWAP-SPACE" {
+                return Ok(Some(probe_swap(w, page_size, signature == b"SWAPSPACE2")));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Whether `boot` is a FAT boot sector with a plausible BPB.
+pub fn is_fat(boot: &[u8]) -> bool {
+    let bytes_per_sector = le_u16(boot, 11);
+    let sectors_per_cluster = boot[13];
+    boot[510..512] == [0x55, 0xAA]
+        && [0xEB, 0xE9].contains(&boot[0])
+        && (512..=4096).contains(&bytes_per_sector)
+        && bytes_per_sector.is_power_of_two()
+        && sectors_per_cluster.is_power_of_two()
+        && le_u16(boot, 14) > 0
+        && (1..=2).contains(&boot[16])
+}
+
+fn probe_fat(disk: &mut dyn ReadAt, start: u64, boot: &[u8]) -> io::Result<FsInfo> {
+    let bytes_per_sector = le_u16(boot, 11) as u64;
+    let sectors_per_cluster = boot[13] as u64;
+    let reserved = le_u16(boot, 14) as u64;
+    let fats = boot[16] as u64;
+    let root_entries = le_u16(boot, 17) as u64;
+    let total_sectors = match le_u16(boot, 19) {
+        0 => le_u32(boot, 32) as u64,
+        sectors => sectors as u64,
+    };
+    let fat_size = match le_u16(boot, 22) {
+        0 => le_u32(boot, 36) as u64,
+        sectors => sectors as u64,
+    };
+    let root_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+    let data_start = reserved + fats * fat_size + root_sectors;
+    let clusters = total_sectors.saturating_sub(data_start) / sectors_per_cluster;
+
+    // The cluster count decides the FAT width; the type string is only a hint
+    let fat32 = root_entries == 0 && le_u16(boot, 22) == 0;
+    let mut info = FsInfo::new(if fat32 {
+        "FAT32"
+    } else if clusters < 4085 {
+        "FAT12"
+    } else {
+        "FAT16"
+    });
+    info.size = Some(total_sectors * bytes_per_sector);
+
+    // The extended boot record holds the serial and a copy of the label
+    let ebr = if fat32 { 64 } else { 36 };
+    if boot[ebr + 2] == 0x29 {
+        let serial = le_u32(boot, ebr + 3);
+        info.uuid = Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF));
+        info.label = fat_label(&boot[ebr + 7..ebr + 18]);
+    }
+
+    // Tools update the label entry in the root directory, not always the
+    // boot sector, so blkid prefers it
+    let root = if fat32 {
+        let root_cluster = le_u32(boot, 44) as u64;
+        (data_start + root_cluster.saturating_sub(2) * sectors_per_cluster) * bytes_per_sector
+    } else {
+        (reserved + fats * fat_size) * bytes_per_sector
+    };
+    let root_len = if fat32 {
+        sectors_per_cluster * bytes_per_sector
+    } else {
+        root_entries * 32
+    };
+    let mut dir = vec![0u8; root_len.min(MAX_ROOT_DIR as u64) as usize];
+    if disk.read_at(start + root, &mut dir)? == dir.len() {
+        for entry in dir.chunks_exact(32) {
+            match entry[0] {
+                0x00 => break,
+                0xE5 => continue,
+                // A volume label entry, but not a long file name part
+                _ if entry[11] & 0x3F == 0x08 => {
+                    if let Some(label) = fat_label(&entry[..11]) {
+                        info.label = Some(label);
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(info)
+}
+
+fn fat_label(field: &[u8]) -> Option<String> {
+    let label = String::from_utf8_lossy(field);
+    let label = label.trim_end_matches([' ', '\0']);
+    (!label.is_empty() && label != "NO NAME").then(|| label.to_string())
+}
+
+fn probe_exfat(disk: &mut dyn ReadAt, start: u64, boot: &[u8]) -> io::Result<FsInfo> {
+    let sector_shift = boot[108] as u32;
+    let cluster_shift = sector_shift + boot[109] as u32;
+    let mut info = FsInfo::new("exFAT");
+    let serial = le_u32(boot, 100);
+    info.uuid = Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF));
+    if !(9..=12).contains(&sector_shift) || cluster_shift > 25 {
+        return Ok(info);
+    }
+    info.size = Some(le_u64(boot, 72) << sector_shift);
+
+    // The label is an entry of type 0x83 in the root directory
+    let heap = (le_u32(boot, 88) as u64) << sector_shift;
+    let root_cluster = le_u32(boot, 96) as u64;
+    let root = heap + (root_cluster.saturating_sub(2) << cluster_shift);
+    let mut dir = vec![0u8; (1usize << cluster_shift).min(MAX_ROOT_DIR)];
+    if disk.read_at(start + root, &mut dir)? == dir.len() {
+        for entry in dir.chunks_exact(32) {
+            match entry[0] {
+                0x00 => break,
+                0x83 => {
+                    let len = (entry[1] as usize).min(11);
+                    info.label = utf16_text(&entry[2..2 + len * 2]);
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(info)
+}
+
+fn probe_ntfs(disk: &mut dyn ReadAt, start: u64, boot: &[u8]) -> io::Result<FsInfo> {
+    let bytes_per_sector = le_u16(boot, 11) as u64;
+    let mut info = FsInfo::new("NTFS");
+    info.uuid = Some(format!("{:016X}", le_u64(boot, 72)));
+    if !(256..=4096).contains(&bytes_per_sector) || !bytes_per_sector.is_power_of_two() {
+        return Ok(info);
+    }
+    info.size = Some(le_u64(boot, 40) * bytes_per_sector);
+
+    // Values above 0x80 give the size as a negative power of two
+    let cluster_size = match boot[13] {
+        n if n > 0x80 => 1u64 << (256 - n as u32).min(31),
+        n => n as u64 * bytes_per_sector,
+    };
+    let record_size = match boot[64] as i8 {
+        n if n < 0 => 1u64 << (-(n as i32)).min(31),
+        n => n as u64 * cluster_size,
+    };
+    if !(1024..=65536).contains(&record_size) {
+        return Ok(info);
+    }
+
+    // The label is the $VOLUME_NAME attribute of $Volume, MFT record 3
+    let mft = le_u64(boot, 48) * cluster_size;
+    let mut record = vec![0u8; record_size as usize];
+    read_exact_at(disk, start + mft + 3 * record_size, &mut record)?;
+    if &record[0..4] != b"FILE" || !apply_fixups(&mut record, bytes_per_sector as usize) {
+        return Ok(info);
+    }
+    let mut pos = le_u16(&record, 20) as usize;
+    while pos + 24 <= record.len() {
+        let kind = le_u32(&record, pos);
+        let len = le_u32(&record, pos + 4) as usize;
+        if kind == 0xFFFF_FFFF || len == 0 || pos + len > record.len() {
+            break;
+        }
+        // Resident attributes keep their value inside the record
+        if kind == 0x60 && record[pos + 8] == 0 {
+            let value_len = le_u32(&record, pos + 16) as usize;
+            let value_start = pos + le_u16(&record, pos + 20) as usize;
+            if let Some(value) = record.get(value_start..value_start + value_len) {
+                info.label = utf16_text(value);
+            }
+            break;
+        }
+        pos += len;
+    }
+    Ok(info)
+}
+
+/// Puts back the last two bytes of each sector of an MFT record, which NTFS
+/// swaps for a sequence number to catch torn writes.
+fn apply_fixups(record: &mut [u8], sector_size: usize) -> bool {
+    let offset = le_u16(record, 4) as usize;
+    let count = le_u16(record, 6) as usize;
+    if count == 0 || offset + count * 2 > record.len() || (count - 1) * sector_size > record.len() {
+        return false;
+    }
+    let sequence = [record[offset], record[offset + 1]];
+    for i in 1..count {
+        let end = i * sector_size;
+        if record[end - 2..end] != sequence {
+            return false;
+        }
+        let fixup = offset + i * 2;
+        record[end - 2] = record[fixup];
+        record[end - 1] = record[fixup + 1];
+    }
+    true
+}
+
+fn probe_ext(w: &[u8]) -> FsInfo {
+    let sb = &w[1024..2048];
+    let compat = le_u32(sb, 0x5C);
+    let incompat = le_u32(sb, 0x60);
+    let ro_compat = le_u32(sb, 0x64);
+
+    // ext4 is ext3 plus any of the features ext3 cannot mount
+    const HAS_JOURNAL: u32 = 0x4;
+    const EXT4_INCOMPAT: u32 = 0x40 | 0x80 | 0x200 | 0x400 | 0x8000;
+    const EXT4_RO_COMPAT: u32 = 0x8 | 0x10 | 0x20 | 0x40 | 0x400;
+    const INCOMPAT_64BIT: u32 = 0x80;
+    let mut info = FsInfo::new(
+        if incompat & EXT4_INCOMPAT != 0 || ro_compat & EXT4_RO_COMPAT != 0 {
+            "ext4"
+        } else if compat & HAS_JOURNAL != 0 {
+            "ext3"
+        } else {
+            "ext2"
+        },
+    );
+
+    let mut blocks = le_u32(sb, 0x04) as u64;
+    if incompat & INCOMPAT_64BIT != 0 {
+        blocks |= (le_u32(sb, 0x150) as u64) << 32;
+    }
+    let log_block_size = le_u32(sb, 0x18);
+    if log_block_size <= 6 {
+        info.size = Some(blocks * (1024 << log_block_size));
+    }
+    info.uuid = uuid(&sb[0x68..0x78]);
+    info.label = text(&sb[0x78..0x88]);
+    info
+}
+
+fn probe_btrfs(sb: &[u8]) -> FsInfo {
+    let mut info = FsInfo::new("btrfs");
+    info.uuid = uuid(&sb[0x20..0x30]);
+    info.size = Some(le_u64(sb, 0x70));
+    info.label = text(&sb[0x12B..0x22B]);
+    info
+}
+
+fn probe_xfs(sb: &[u8]) -> FsInfo {
+    let mut info = FsInfo::new("XFS");
+    info.size = Some(be_u64(sb, 8) * be_u32(sb, 4) as u64);
+    info.uuid = uuid(&sb[32..48]);
+    info.label = text(&sb[108..120]);
+    info
+}
+
+fn probe_squashfs(sb: &[u8]) -> FsInfo {
+    // squashfs has neither a label nor a UUID
+    let mut info = FsInfo::new("squashfs");
+    info.size = Some(le_u64(sb, 40));
+    info
+}
+
+fn probe_iso9660(pvd: &[u8]) -> FsInfo {
+    let mut info = FsInfo::new("ISO 9660");
+    info.label = text(&pvd[40..72]);
+    info.size = Some(le_u32(pvd, 80) as u64 * le_u16(pvd, 128) as u64);
+    // blkid makes a UUID of the creation date, which is all ISO 9660 has
+    let date = &pvd[813..829];
+    if date.iter().all(u8::is_ascii_digit) && !date.starts_with(b"0000") {
+        let date = String::from_utf8_lossy(date);
+        info.uuid = Some(format!(
+            "{}-{}-{}-{}-{}-{}-{}",
+            &date[0..4],
+            &date[4..6],
+            &date[6..8],
+            &date[8..10],
+            &date[10..12],
+            &date[12..14],
+            &date[14..16]
+        ));
+    }
+    info
+}
+
+fn probe_swap(w: &[u8], page_size: usize, v2: bool) -> FsInfo {
+    let mut info = FsInfo::new("swap");
+    // Only version 1 swap (the SWAPSPACE2 signature) has a header
+    if v2 {
+        let last_page = le_u32(w, 1028) as u64;
+        info.size = Some((last_page + 1) * page_size as u64);
+        info.uuid = uuid(&w[1036..1052]);
+        info.label = text(&w[1052..1068]);
+    }
+    info
+}
+
+/// Formats a UUID stored in big-endian order, or `None` when it is all zeros.
+fn uuid(bytes: &[u8]) -> Option<String> {
+    if bytes.iter().all(|&b| b == 0) {
+        return None;
+    }
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+/// A NUL or space padded label, or `None` when it is blank.
+fn text(field: &[u8]) -> Option<String> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    let text = String::from_utf8_lossy(&field[..end]);
+    let text = text.trim_end();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn utf16_text(field: &[u8]) -> Option<String> {
+    let units: Vec<u16> = field
+        .chunks_exact(2)
+        .map(|pair| le_u16(pair, 0))
+        .take_while(|&unit| unit != 0)
+        .collect();
+    let text = String::from_utf16_lossy(&units);
+    (!text.is_empty()).then_some(text)
+}
@@ -0,0 +1,187 @@
+//! Checks every file of an image against the checksum list Ubuntu and Debian
+//! put on their discs, which points at the files a bad write damaged.
+
+use super::fs::{lookup, read_file, read_file_at, FileSystem, Node};
+use super::ReadAt;
+use md5::Md5;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+
+/// Checksum lists, strongest first. Ubuntu has shipped sha256sum.txt since
+/// 24.04, next to the md5sum.txt Debian and older releases have.
+const MANIFESTS: &[(&str, Algorithm)] = &[
+    ("sha256sum.txt", Algorithm::Sha256),
+    ("SHA256SUMS", Algorithm::Sha256),
+    ("md5sum.txt", Algorithm::Md5),
+    ("MD5SUMS", Algorithm::Md5),
+];
+/// Lists run to a few thousand lines; anything larger is not one
+const MAX_MANIFEST: u64 = 16 * 1024 * 1024;
+/// Files are hashed in chunks of this size
+const CHUNK_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    fn hex_len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 32,
+            Algorithm::Sha256 => 64,
+        }
+    }
+}
+
+/// The outcome of checking the files of an image against its checksum list.
+#[derive(Debug, Clone, Serialize)]
+pub struct SumsCheck {
+    /// Path of the checksum list in the image
+    pub manifest: String,
+    /// "MD5" or "SHA-256"
+    pub algorithm: String,
+    /// Files whose checksum was compared
+    pub files_checked: u32,
+    /// Listed files the image does not have
+    pub missing: Vec<String>,
+    /// Listed files whose contents don't match their checksum
+    pub corrupt: Vec<String>,
+    pub passed: bool,
+}
+
+/// Hashes every file the image's checksum list names, or returns `None` when
+/// there is no list. `progress` is told the bytes hashed so far and the total.
+pub fn check(
+    fs: &mut dyn FileSystem,
+    disk: &mut dyn ReadAt,
+    mut progress: impl FnMut(u64, u64),
+) -> io::Result<Option<SumsCheck>> {
+    let mut manifest = None;
+    for &(path, algorithm) in MANIFESTS {
+        if let Some(node) = lookup(fs, disk, path)?.filter(|node| !node.is_dir) {
+            manifest = Some((path, algorithm, read_file(disk, &node, MAX_MANIFEST)?));
+            break;
+        }
+    }
+    let Some((manifest, algorithm, list)) = manifest else {
+        return Ok(None);
+    };
+    let entries = parse(&String::from_utf8_lossy(&list), algorithm);
+
+    // One walk of the tree, rather than a lookup per listed file
+    let files = list_files(fs, disk)?;
+    let mut missing = Vec::new();
+    let mut found = Vec::new();
+    for (path, sum) in entries {
+        // A list that names itself can't hold its own checksum
+        if path.eq_ignore_ascii_case(manifest) {
+            continue;
+        }
+        match files.get(&path.to_lowercase()) {
+            Some(node) => found.push((path, sum, node)),
+            None => missing.push(path),
+        }
+    }
+    // In disk order, so images that can't seek are read through only once
+    found.sort_by_key(|(_, _, node)| node.start());
+
+    let total: u64 = found.iter().map(|(_, _, node)| node.size).sum();
+    let mut done = 0;
+    let mut corrupt = Vec::new();
+    let mut buffer = vec![0u8; CHUNK_LEN];
+    for (path, sum, node) in &found {
+        let mut advance = |n| {
+            done += n;
+            progress(done, total);
+        };
+        let actual = match algorithm {
+            Algorithm::Md5 => hash_file::<Md5>(disk, node, &mut buffer, &mut advance)?,
+            Algorithm::Sha256 => hash_file::<Sha256>(disk, node, &mut buffer, &mut advance)?,
+        };
+        if actual != *sum {
+            corrupt.push(path.clone());
+        }
+    }
+
+    Ok(Some(SumsCheck {
+        manifest: manifest.to_string(),
+        algorithm: algorithm.name().to_string(),
+        files_checked: found.len() as u32,
+        passed: missing.is_empty() && corrupt.is_empty(),
+        missing,
+        corrupt,
+    }))
+}
+
+/// Reads "<hex>  ./path" lines, as md5sum and sha256sum write them. Binary
+/// mode's "*" before the path is allowed for.
+fn parse(list: &str, algorithm: Algorithm) -> Vec<(String, String)> {
+    list.lines()
+        .filter_map(|line| {
+            let (sum, path) = line.split_once(char::is_whitespace)?;
+            let path = path.trim_start().trim_start_matches('*');
+            let path = path
+                .strip_prefix("./")
+                .unwrap_or(path)
+                .trim_end_matches('\r');
+            (sum.len() == algorithm.hex_len()
+                && sum.bytes().all(|b| b.is_ascii_hexdigit())
+                && !path.is_empty())
+            .then(|| (path.to_string(), sum.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+/// Hashes a file, telling `advance` about each chunk read.
+fn hash_file<D: Digest>(
+    disk: &mut dyn ReadAt,
+    node: &Node,
+    buffer: &mut [u8],
+    advance: &mut dyn FnMut(u64),
+) -> io::Result<String> {
+    let mut hasher = D::new();
+    let mut offset = 0;
+    while offset < node.size {
+        let n = read_file_at(disk, node, offset, buffer)?;
+        hasher.update(&buffer[..n]);
+        offset += n as u64;
+        advance(n as u64);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Every file of the filesystem by its lowercased path.
+fn list_files(fs: &mut dyn FileSystem, disk: &mut dyn ReadAt) -> io::Result<HashMap<String, Node>> {
+    let mut files = HashMap::new();
+    let mut dirs = vec![(String::new(), fs.root().clone())];
+    while let Some((path, dir)) = dirs.pop() {
+        for node in fs.read_dir(disk, &dir)? {
+            let child = if path.is_empty() {
+                node.name.to_lowercase()
+            } else {
+                format!("{}/{}", path, node.name.to_lowercase())
+            };
+            if node.is_dir {
+                dirs.push((child, node));
+            } else {
+                files.insert(child, node);
+            }
+        }
+    }
+    Ok(files)
+}
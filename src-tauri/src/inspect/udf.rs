@@ -0,0 +1,354 @@
+use super::fs::{read_file, Data, Extent, FileSystem, Node};
+use super::{read_exact_at, ReadAt};
+use crate::image::bytes::{le_u16, le_u32, le_u64};
+use serde::Serialize;
+use std::io;
+
+/// Volume structure descriptors share the ISO 9660 descriptor area
+const VRS_START: u64 = 32768;
+const VRS_LEN: u64 = 2048;
+const MAX_VRS_DESCRIPTORS: u64 = 64;
+const AVDP_SECTOR: u64 = 256;
+/// Optical media use 2048-byte blocks, UDF on hard disks their sector size
+const BLOCK_SIZES: [u64; 2] = [2048, 512];
+const MAX_VDS_DESCRIPTORS: u32 = 64;
+/// Directories are read whole, so this bounds the memory one can take
+const MAX_DIR_LEN: u64 = 16 * 1024 * 1024;
+const MAX_AD_CONTINUATIONS: usize = 64;
+
+const TAG_PRIMARY_VOLUME: u16 = 1;
+const TAG_ANCHOR: u16 = 2;
+const TAG_PARTITION: u16 = 5;
+const TAG_LOGICAL_VOLUME: u16 = 6;
+const TAG_TERMINATING: u16 = 8;
+const TAG_FILE_SET: u16 = 256;
+const TAG_FILE_IDENTIFIER: u16 = 257;
+const TAG_ALLOCATION_EXTENT: u16 = 258;
+const TAG_FILE_ENTRY: u16 = 261;
+const TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+const FILE_TYPE_DIRECTORY: u8 = 4;
+const FID_DIRECTORY: u8 = 1 << 1;
+const FID_DELETED: u8 = 1 << 2;
+const FID_PARENT: u8 = 1 << 3;
+
+/// What the volume recognition sequence and descriptors say about a UDF volume.
+#[derive(Debug, Clone, Serialize)]
+pub struct UdfInfo {
+    /// NSR02 for UDF 1.02 to 1.50, NSR03 for 2.00 and later
+    pub revision: String,
+    pub volume_label: Option<String>,
+}
+
+/// Looks for a UDF volume recognition sequence: BEA01, an NSR descriptor,
+/// then TEA01. Returns the NSR identifier, which gives the UDF revision range.
+pub fn detect(disk: &mut dyn ReadAt) -> io::Result<Option<String>> {
+    let mut in_sequence = false;
+    let mut nsr = None;
+    for index in 0..MAX_VRS_DESCRIPTORS {
+        let mut descriptor = [0u8; 7];
+        if disk.read_at(VRS_START + index * VRS_LEN, &mut descriptor)? < descriptor.len() {
+            break;
+        }
+        match &descriptor[1..6] {
+            b"BEA01" => in_sequence = true,
+            b"NSR02" | b"NSR03" if in_sequence => {
+                nsr = Some(String::from_utf8_lossy(&descriptor[1..6]).into_owned())
+            }
+            b"TEA01" => return Ok(nsr.filter(|_| in_sequence)),
+            // ISO 9660 descriptors may come before the extended area
+            b"CD001" | b"BOOT2" | b"CDW02" => {}
+            _ => break,
+        }
+    }
+    Ok(None)
+}
+
+/// A UDF volume, read through its first logical volume and file set.
+pub struct Udf {
+    block_size: u64,
+    /// Byte offset of each partition, by partition reference number
+    partitions: Vec<u64>,
+    root: Node,
+    pub volume_label: String,
+}
+
+impl Udf {
+    pub fn open(disk: &mut dyn ReadAt) -> io::Result<Self> {
+        let (block_size, anchor) = find_anchor(disk)?;
+        let vds_len = le_u32(&anchor, 16);
+        let vds_start = le_u32(&anchor, 20) as u64;
+
+        let mut partition_starts = Vec::new();
+        let mut logical_volume = None;
+        let mut volume_label = String::new();
+        let mut block = vec![0u8; block_size as usize];
+        for index in 0..(vds_len / block_size as u32).min(MAX_VDS_DESCRIPTORS) {
+            let sector = vds_start + index as u64;
+            read_exact_at(disk, sector * block_size, &mut block)?;
+            match tag_id(&block) {
+                Some(TAG_PRIMARY_VOLUME) if volume_label.is_empty() => {
+                    volume_label = dstring(&block[24..56]);
+                }
+                Some(TAG_PARTITION) => {
+                    let number = le_u16(&block, 22);
+                    let start = le_u32(&block, 188) as u64 * block_size;
+                    partition_starts.push((number, start));
+                }
+                Some(TAG_LOGICAL_VOLUME) if logical_volume.is_none() => {
+                    logical_volume = Some(block.clone());
+                }
+                Some(TAG_TERMINATING) | None => break,
+                _ => {}
+            }
+        }
+        let lvd = logical_volume.ok_or_else(|| invalid("no logical volume descriptor"))?;
+        if le_u32(&lvd, 212) as u64 != block_size {
+            return Err(invalid("logical block size differs from the sector size"));
+        }
+        let label = dstring(&lvd[84..212]);
+        if !label.is_empty() {
+            volume_label = label;
+        }
+
+        // Only plain type 1 maps; sparable and metadata partitions are left out
+        let map_count = le_u32(&lvd, 268) as usize;
+        let mut partitions = Vec::new();
+        let mut pos = 440;
+        for _ in 0..map_count {
+            let map = lvd
+                .get(pos..pos + 2)
+                .ok_or_else(|| invalid("partition maps are cut short"))?;
+            let (map_type, map_len) = (map[0], map[1] as usize);
+            if map_type != 1 || map_len != 6 || pos + map_len > lvd.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "UDF volume uses a partition map other than type 1",
+                ));
+            }
+            let number = le_u16(&lvd, pos + 4);
+            let start = partition_starts
+                .iter()
+                .find(|(n, _)| *n == number)
+                .map(|(_, start)| *start)
+                .ok_or_else(|| invalid("partition map names a missing partition"))?;
+            partitions.push(start);
+            pos += map_len;
+        }
+
+        let mut udf = Self {
+            block_size,
+            partitions,
+            root: Node {
+                name: String::new(),
+                is_dir: true,
+                size: 0,
+                data: Data::Inline(Vec::new()),
+            },
+            volume_label,
+        };
+
+        // The file set descriptor's long_ad sits in the logical volume contents use
+        let fsd_pos = udf.long_ad_pos(&lvd[248..264])?;
+        read_exact_at(disk, fsd_pos, &mut block)?;
+        if tag_id(&block) != Some(TAG_FILE_SET) {
+            return Err(invalid("file set descriptor is missing"));
+        }
+        udf.root = udf.read_icb(disk, &block[400..416], String::new())?;
+        Ok(udf)
+    }
+
+    /// Byte position of the block a long_ad points at.
+    fn long_ad_pos(&self, ad: &[u8]) -> io::Result<u64> {
+        let lbn = le_u32(ad, 4) as u64;
+        let partition = le_u16(ad, 8) as usize;
+        let start = self
+            .partitions
+            .get(partition)
+            .ok_or_else(|| invalid("reference to a missing partition"))?;
+        Ok(start + lbn * self.block_size)
+    }
+
+    /// Reads the file entry a long_ad points at into a node.
+    fn read_icb(&self, disk: &mut dyn ReadAt, icb: &[u8], name: String) -> io::Result<Node> {
+        let mut entry = vec![0u8; self.block_size as usize];
+        read_exact_at(disk, self.long_ad_pos(icb)?, &mut entry)?;
+        let (ea_len_at, ads_start) = match tag_id(&entry) {
+            Some(TAG_FILE_ENTRY) => (168, 176),
+            Some(TAG_EXTENDED_FILE_ENTRY) => (208, 216),
+            _ => return Err(invalid("file entry is damaged")),
+        };
+        let is_dir = entry[16 + 11] == FILE_TYPE_DIRECTORY;
+        let size = le_u64(&entry, 56);
+        let ad_type = le_u16(&entry, 16 + 18) & 0x07;
+        let ads_start = ads_start + le_u32(&entry, ea_len_at) as usize;
+        let ads_len = le_u32(&entry, ea_len_at + 4) as usize;
+        let ads = entry
+            .get(ads_start..ads_start + ads_len)
+            .ok_or_else(|| invalid("file entry is damaged"))?;
+        // Short allocation descriptors are relative to the entry's partition
+        let partition_start = self.partitions[le_u16(icb, 8) as usize];
+
+        let data = match ad_type {
+            0 | 1 => Data::Extents(self.read_extents(disk, ads, ad_type, partition_start)?),
+            3 => Data::Inline(ads.to_vec()),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "UDF extended allocation descriptors",
+                ))
+            }
+        };
+        Ok(Node {
+            name,
+            is_dir,
+            size,
+            data,
+        })
+    }
+
+    /// Collects short_ad (type 0) or long_ad (type 1) extents, following
+    /// continuation extents.
+    fn read_extents(
+        &self,
+        disk: &mut dyn ReadAt,
+        ads: &[u8],
+        ad_type: u16,
+        partition_start: u64,
+    ) -> io::Result<Vec<Extent>> {
+        let ad_len = if ad_type == 0 { 8 } else { 16 };
+        let mut extents = Vec::new();
+        let mut ads = ads.to_vec();
+        for _ in 0..MAX_AD_CONTINUATIONS {
+            let mut next = None;
+            for ad in ads.chunks_exact(ad_len) {
+                let raw_len = le_u32(ad, 0);
+                let len = (raw_len & 0x3FFF_FFFF) as u64;
+                if len == 0 {
+                    break;
+                }
+                let pos = if ad_type == 0 {
+                    partition_start + le_u32(ad, 4) as u64 * self.block_size
+                } else {
+                    self.long_ad_pos(ad)?
+                };
+                match raw_len >> 30 {
+                    0 => extents.push(Extent {
+                        pos: Some(pos),
+                        len,
+                    }),
+                    // Allocated or not, unrecorded extents read as zeros
+                    1 | 2 => extents.push(Extent { pos: None, len }),
+                    _ => {
+                        next = Some((pos, len));
+                        break;
+                    }
+                }
+            }
+            let (pos, len) = match next {
+                Some(next) => next,
+                None => return Ok(extents),
+            };
+            let mut block = vec![0u8; len.min(self.block_size) as usize];
+            read_exact_at(disk, pos, &mut block)?;
+            if tag_id(&block) != Some(TAG_ALLOCATION_EXTENT) {
+                return Err(invalid("allocation extent is damaged"));
+            }
+            let ads_len = (le_u32(&block, 20) as usize).min(block.len().saturating_sub(24));
+            ads = block[24..24 + ads_len].to_vec();
+        }
+        Err(invalid("too many allocation extents"))
+    }
+}
+
+impl FileSystem for Udf {
+    fn root(&self) -> &Node {
+        &self.root
+    }
+
+    fn read_dir(&mut self, disk: &mut dyn ReadAt, dir: &Node) -> io::Result<Vec<Node>> {
+        let data = read_file(disk, dir, MAX_DIR_LEN)?;
+        let mut nodes = Vec::new();
+        let mut pos = 0;
+        while pos + 38 <= data.len() {
+            let fid = &data[pos..];
+            if tag_id(fid) != Some(TAG_FILE_IDENTIFIER) {
+                return Err(invalid("directory is damaged"));
+            }
+            let characteristics = fid[18];
+            let name_len = fid[19] as usize;
+            let impl_len = le_u16(fid, 36) as usize;
+            let len = (38 + impl_len + name_len + 3) & !3;
+            if pos + 38 + impl_len + name_len > data.len() {
+                return Err(invalid("directory is damaged"));
+            }
+            pos += len;
+            if characteristics & (FID_PARENT | FID_DELETED) != 0 {
+                continue;
+            }
+
+            let name = compressed_unicode(&fid[38 + impl_len..38 + impl_len + name_len]);
+            let mut node = self.read_icb(disk, &fid[20..36], name)?;
+            node.is_dir |= characteristics & FID_DIRECTORY != 0;
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+}
+
+/// Finds the anchor volume descriptor pointer, trying each block size.
+fn find_anchor(disk: &mut dyn ReadAt) -> io::Result<(u64, Vec<u8>)> {
+    for block_size in BLOCK_SIZES {
+        let mut anchor = vec![0u8; 512];
+        if disk.read_at(AVDP_SECTOR * block_size, &mut anchor)? == anchor.len()
+            && tag_id(&anchor) == Some(TAG_ANCHOR)
+        {
+            return Ok((block_size, anchor));
+        }
+    }
+    Err(invalid("anchor volume descriptor is missing"))
+}
+
+/// Identifier of a descriptor tag, if its checksum is right.
+fn tag_id(tag: &[u8]) -> Option<u16> {
+    if tag.len() < 16 {
+        return None;
+    }
+    let sum = tag[..16]
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 4)
+        .fold(0u8, |sum, (_, b)| sum.wrapping_add(*b));
+    (sum == tag[4]).then(|| le_u16(tag, 0))
+}
+
+/// Decodes OSTA compressed Unicode: a compression ID of 8 (one byte per
+/// character) or 16 (UCS-2, big-endian), then the characters.
+fn compressed_unicode(field: &[u8]) -> String {
+    match field.split_first() {
+        Some((8, chars)) => chars.iter().map(|&b| b as char).collect(),
+        Some((16, chars)) => {
+            let units: Vec<u16> = chars
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::new(),
+    }
+}
+
+/// A dstring: compressed Unicode with its used length in the last byte.
+fn dstring(field: &[u8]) -> String {
+    let len = (*field.last().unwrap_or(&0) as usize).min(field.len() - 1);
+    compressed_unicode(&field[..len])
+        .trim_end_matches(['\0', ' '])
+        .to_string()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("UDF volume: {}", message),
+    )
+}
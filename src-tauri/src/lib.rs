@@ -1,6 +1,7 @@
 mod commands;
+mod image;
+mod inspect;
 mod platform;
-mod utils;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
@@ -8,11 +9,19 @@ pub fn run() {
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_notification::init())
         .invoke_handler(tauri::generate_handler![
+            commands::archive::list_archive_entries,
             commands::devices::list_devices,
+            commands::devices::read_partition_table,
+            commands::devices::watch_devices,
+            commands::files::list_image_files,
+            commands::files::extract_image_file,
             commands::verify::get_file_info,
             commands::verify::calculate_checksum,
+            commands::verify::check_iso_md5,
+            commands::verify::verify_image_files,
             commands::verify::validate_image,
             commands::write::write_iso_to_device,
+            commands::write::is_non_hybrid_iso,
             commands::write::eject_device,
         ])
         .setup(|_app| {
@@ -1,88 +1,460 @@
-use super::PlatformError;
-use crate::commands::devices::{format_size, BlockDevice};
+use super::{DeviceEvent, PlatformError};
+use crate::commands::devices::{format_size, BlockDevice, DevicePartition};
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::io::{self, Read};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use tokio::fs::File;
 
+/// The multicast group the kernel sends uevents to
+const UEVENT_KERNEL_GROUP: u32 = 1;
+/// The group udev passes them on to, once its database and the
+/// /dev/disk links are written
+const UEVENT_UDEV_GROUP: u32 = 2;
+/// udev's messages start with this, then a header in native byte order
+const UDEV_PREFIX: &[u8] = b"libudev\0";
+const UDEV_MAGIC: u32 = 0xfeedcafe;
+/// uevents are capped at a page or two by the kernel
+const UEVENT_BUFFER_LEN: usize = 8192;
+
 pub async fn list_removable_devices() -> Result<Vec<BlockDevice>, PlatformError> {
-    let mut devices = Vec::new();
+    Ok(scan_devices()?)
+}
 
+fn scan_devices() -> io::Result<Vec<BlockDevice>> {
+    Ok(disk_names()?
+        .iter()
+        .filter_map(|name| read_device(name))
+        .collect())
+}
+
+fn disk_names() -> io::Result<Vec<String>> {
     let block_dir = Path::new("/sys/block");
     if !block_dir.exists() {
-        return Ok(devices);
+        return Ok(Vec::new());
     }
 
+    let mut names = Vec::new();
     for entry in fs::read_dir(block_dir)? {
-        let entry = entry?;
-        let name = entry.file_name().to_string_lossy().to_string();
+        names.push(entry?.file_name().to_string_lossy().to_string());
+    }
+    Ok(names)
+}
 
-        // Skip non-disk devices (loop, ram, etc.)
-        if name.starts_with("loop")
-            || name.starts_with("ram")
-            || name.starts_with("zram")
-            || name.starts_with("dm-")
-        {
-            continue;
-        }
+/// Reads a disk from sysfs by its kernel name, or returns `None` when it is
+/// gone, not removable or has no media.
+fn read_device(name: &str) -> Option<BlockDevice> {
+    // Skip non-disk devices (loop, ram, etc.)
+    if name.starts_with("loop")
+        || name.starts_with("ram")
+        || name.starts_with("zram")
+        || name.starts_with("dm-")
+    {
+        return None;
+    }
 
-        let device_path = entry.path();
+    let device_path = Path::new("/sys/block").join(name);
 
-        // Check if removable
-        let removable_path = device_path.join("removable");
-        let removable = fs::read_to_string(&removable_path)
-            .map(|s| s.trim() == "1")
-            .unwrap_or(false);
+    // Check if removable
+    let removable_path = device_path.join("removable");
+    let removable = fs::read_to_string(&removable_path)
+        .map(|s| s.trim() == "1")
+        .unwrap_or(false);
 
-        if !removable {
-            continue;
-        }
+    if !removable {
+        return None;
+    }
 
-        // Get size
-        let size_path = device_path.join("size");
-        let size_sectors: u64 = fs::read_to_string(&size_path)
-            .map(|s| s.trim().parse().unwrap_or(0))
-            .unwrap_or(0);
-        let size = size_sectors * 512; // Sector size is typically 512 bytes
+    // Get size
+    let size_path = device_path.join("size");
+    let size_sectors: u64 = fs::read_to_string(&size_path)
+        .map(|s| s.trim().parse().unwrap_or(0))
+        .unwrap_or(0);
+    let size = size_sectors * 512; // Sector size is typically 512 bytes
 
-        if size == 0 {
-            continue;
-        }
+    if size == 0 {
+        return None;
+    }
 
-        // Get device model/name
-        let model_path = device_path.join("device/model");
-        let vendor_path = device_path.join("device/vendor");
-        let model = fs::read_to_string(&model_path)
-            .map(|s| s.trim().to_string())
-            .unwrap_or_default();
-        let vendor = fs::read_to_string(&vendor_path)
-            .map(|s| s.trim().to_string())
-            .unwrap_or_default();
-
-        let device_name = if !vendor.is_empty() && !model.is_empty() {
-            format!("{} {}", vendor, model)
-        } else if !model.is_empty() {
-            model
-        } else if !vendor.is_empty() {
-            vendor
-        } else {
-            name.clone()
-        };
+    // Get device model/name
+    let model_path = device_path.join("device/model");
+    let vendor_path = device_path.join("device/vendor");
+    let model = fs::read_to_string(&model_path)
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default();
+    let vendor = fs::read_to_string(&vendor_path)
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default();
+
+    let device_name = if !vendor.is_empty() && !model.is_empty() {
+        format!("{} {}", vendor, model)
+    } else if !model.is_empty() {
+        model
+    } else if !vendor.is_empty() {
+        vendor
+    } else {
+        name.to_string()
+    };
+
+    // Get mount points
+    let mount_points = get_mount_points(&format!("/dev/{}", name));
+
+    let udev = udev_properties(&device_path);
+    // The USB device the disk hangs off, for card readers and sticks alike
+    let sys_device = fs::canonicalize(device_path.join("device")).ok();
+    let usb = sys_device.as_deref().and_then(|path| {
+        path.ancestors()
+            .find(|dir| dir.join("idVendor").exists())
+            .map(Path::to_path_buf)
+    });
+
+    let serial = udev
+        .get("ID_SERIAL_SHORT")
+        .cloned()
+        .or_else(|| {
+            usb.as_ref()
+                .and_then(|dir| read_attribute(&dir.join("serial")))
+        })
+        .or_else(|| read_attribute(&device_path.join("device/serial")));
+    let transport = if usb.is_some() {
+        Some("usb")
+    } else if name.starts_with("mmcblk") {
+        Some("mmc")
+    } else if name.starts_with("nvme") {
+        Some("nvme")
+    } else if name.starts_with("vd") {
+        Some("virtio")
+    } else if sys_device
+        .as_deref()
+        .is_some_and(|path| path.to_string_lossy().contains("/ata"))
+    {
+        Some("ata")
+    } else {
+        None
+    };
+
+    // wwn- links name the medium rather than the drive
+    let by_id = links_to(Path::new("/dev/disk/by-id"), &format!("/dev/{}", name));
+    let by_id_path = by_id
+        .iter()
+        .find(|link| !link.starts_with("wwn-"))
+        .or(by_id.first())
+        .map(|link| format!("/dev/disk/by-id/{}", link));
+
+    Some(BlockDevice {
+        path: format!("/dev/{}", name),
+        name: device_name,
+        size,
+        size_human: format_size(size),
+        removable: true,
+        mount_points,
+        serial,
+        vendor_id: usb
+            .as_ref()
+            .and_then(|dir| read_attribute(&dir.join("idVendor"))),
+        product_id: usb
+            .as_ref()
+            .and_then(|dir| read_attribute(&dir.join("idProduct"))),
+        transport: transport.map(str::to_string),
+        by_id_path,
+        logical_sector_size: read_attribute(&device_path.join("queue/logical_block_size"))
+            .and_then(|s| s.parse().ok()),
+        physical_sector_size: read_attribute(&device_path.join("queue/physical_block_size"))
+            .and_then(|s| s.parse().ok()),
+        partitions: read_partitions(&device_path),
+    })
+}
 
-        // Get mount points
-        let mount_points = get_mount_points(&format!("/dev/{}", name));
+/// Reads the partitions sysfs lists under a disk, with what udev found out
+/// about their file systems.
+fn read_partitions(device_path: &Path) -> Vec<DevicePartition> {
+    let Ok(entries) = fs::read_dir(device_path) else {
+        return Vec::new();
+    };
+
+    let mut partitions = Vec::new();
+    for entry in entries.flatten() {
+        let sys_path = entry.path();
+        let Some(number) = read_attribute(&sys_path.join("partition")).and_then(|s| s.parse().ok())
+        else {
+            continue;
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = format!("/dev/{}", name);
+        let size = read_attribute(&sys_path.join("size"))
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(0)
+            * 512;
+
+        // Without the udev database, its symlinks still give the label and UUID
+        let udev = udev_properties(&sys_path);
+        let label = udev
+            .get("ID_FS_LABEL_ENC")
+            .map(|label| unescape_udev(label))
+            .or_else(|| {
+                links_to(Path::new("/dev/disk/by-label"), &path)
+                    .first()
+                    .map(|label| unescape_udev(label))
+            });
+        let uuid = udev.get("ID_FS_UUID").cloned().or_else(|| {
+            links_to(Path::new("/dev/disk/by-uuid"), &path)
+                .into_iter()
+                .next()
+        });
 
-        devices.push(BlockDevice {
-            path: format!("/dev/{}", name),
-            name: device_name,
+        partitions.push(DevicePartition {
+            path,
+            number,
             size,
             size_human: format_size(size),
-            removable: true,
-            mount_points,
+            fs_type: udev.get("ID_FS_TYPE").cloned(),
+            label,
+            uuid,
         });
     }
+    partitions.sort_by_key(|partition| partition.number);
+    partitions
+}
+
+/// The properties udev recorded for a device, from the "E:KEY=VALUE" lines
+/// of its entry in the udev database. Empty when udev isn't running.
+fn udev_properties(sys_path: &Path) -> HashMap<String, String> {
+    let Some(dev) = read_attribute(&sys_path.join("dev")) else {
+        return HashMap::new();
+    };
+    fs::read_to_string(format!("/run/udev/data/b{}", dev))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.strip_prefix("E:")?.split_once('='))
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Names of the symlinks in `dir` that point at `device`, sorted.
+fn links_to(dir: &Path, device: &str) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut links: Vec<String> = entries
+        .flatten()
+        .filter(|entry| {
+            fs::canonicalize(entry.path()).is_ok_and(|target| target == Path::new(device))
+        })
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    links.sort();
+    links
+}
+
+/// Undoes the \xNN escapes udev puts in labels and link names.
+fn unescape_udev(text: &str) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .strip_prefix(b"x")
+            .filter(|_| byte == b'\\')
+            .and_then(|hex| hex.get(..2))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(value) => {
+                bytes.push(value);
+                rest = &tail[3..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
 
-    Ok(devices)
+/// Reads a sysfs attribute, or `None` when it is missing or blank.
+fn read_attribute(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// Listens for block devices on the kernel's uevent socket, and for mounts
+/// through /proc/mounts, calling `on_event` from a background thread.
+pub fn watch_devices(on_event: Box<dyn Fn(DeviceEvent) + Send>) -> Result<bool, PlatformError> {
+    let socket = open_uevent_socket()?;
+    // The kernel flags the mount table for poll() whenever it changes
+    let mounts = fs::File::open("/proc/self/mounts")?;
+    let mut known: HashMap<String, BlockDevice> = HashMap::new();
+    for device in scan_devices()? {
+        known.insert(device.path.trim_start_matches("/dev/").to_string(), device);
+    }
+
+    std::thread::spawn(move || {
+        let mut buffer = vec![0u8; UEVENT_BUFFER_LEN];
+        loop {
+            let mut fds = [
+                libc::pollfd {
+                    fd: socket.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: mounts.as_raw_fd(),
+                    events: libc::POLLPRI,
+                    revents: 0,
+                },
+            ];
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                on_event(DeviceEvent::Stopped(error.to_string()));
+                return;
+            }
+
+            if fds[0].revents & libc::POLLIN != 0 {
+                match (&socket).read(&mut buffer) {
+                    Ok(len) => {
+                        if let Some(name) = uevent_disk(&buffer[..len]) {
+                            update(&mut known, &name, &*on_event);
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    // The queue overflowed and events were lost
+                    Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                        sync(&mut known, &*on_event)
+                    }
+                    Err(e) => {
+                        on_event(DeviceEvent::Stopped(e.to_string()));
+                        return;
+                    }
+                }
+            }
+            if fds[1].revents & (libc::POLLPRI | libc::POLLERR) != 0 {
+                sync(&mut known, &*on_event);
+            }
+        }
+    });
+    Ok(true)
+}
+
+/// Opens a netlink socket subscribed to the uevents the kernel broadcasts.
+fn open_uevent_socket() -> io::Result<fs::File> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    // The kernel's events come first and keep working without udev; udev's
+    // follow with the serial, labels and by-id links it has found out
+    address.nl_groups = UEVENT_KERNEL_GROUP | UEVENT_UDEV_GROUP;
+    let bound = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if bound < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fs::File::from(socket))
+}
+
+/// Picks the disk a uevent is about out of its NUL-separated KEY=VALUE
+/// fields. The kernel puts an "ACTION@DEVPATH" line before them, udev a
+/// binary header. Partitions stand for the disk they are on; anything but a
+/// block device gives `None`.
+fn uevent_disk(message: &[u8]) -> Option<String> {
+    let properties = match message.strip_prefix(UDEV_PREFIX) {
+        Some(header) => {
+            let field = |offset: usize| -> Option<usize> {
+                Some(u32::from_ne_bytes(header.get(offset..offset + 4)?.try_into().ok()?) as usize)
+            };
+            if u32::from_be_bytes(header.get(0..4)?.try_into().ok()?) != UDEV_MAGIC {
+                return None;
+            }
+            let (offset, len) = (field(8)?, field(12)?);
+            message.get(offset..offset.checked_add(len)?)?
+        }
+        None => {
+            let end = message.iter().position(|&b| b == 0)?;
+            if !message[..end].contains(&b'@') {
+                return None;
+            }
+            &message[end + 1..]
+        }
+    };
+    let fields = properties.split(|&b| b == 0).map(String::from_utf8_lossy);
+
+    let mut subsystem = None;
+    let mut devpath = None;
+    let mut devtype = None;
+    for field in fields {
+        match field.split_once('=') {
+            Some(("SUBSYSTEM", value)) => subsystem = Some(value.to_string()),
+            Some(("DEVPATH", value)) => devpath = Some(value.to_string()),
+            Some(("DEVTYPE", value)) => devtype = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    if subsystem.as_deref() != Some("block") {
+        return None;
+    }
+
+    let devpath = PathBuf::from(devpath?);
+    let disk = match devtype.as_deref() {
+        Some("disk") => devpath.file_name()?,
+        Some("partition") => devpath.parent()?.file_name()?,
+        _ => return None,
+    };
+    Some(disk.to_string_lossy().to_string())
+}
+
+/// Reads a disk again and reports how it differs from what was known of it.
+fn update(known: &mut HashMap<String, BlockDevice>, name: &str, on_event: &dyn Fn(DeviceEvent)) {
+    match (known.remove(name), read_device(name)) {
+        (None, Some(device)) => {
+            known.insert(name.to_string(), device.clone());
+            on_event(DeviceEvent::Added(device));
+        }
+        // Gone from sysfs, so the last known state has to do
+        (Some(old), None) => on_event(DeviceEvent::Removed(old)),
+        (Some(old), Some(device)) => {
+            known.insert(name.to_string(), device.clone());
+            if old != device {
+                on_event(DeviceEvent::Changed(device));
+            }
+        }
+        (None, None) => {}
+    }
+}
+
+/// Reads every disk again, for when a change can't be pinned to one.
+fn sync(known: &mut HashMap<String, BlockDevice>, on_event: &dyn Fn(DeviceEvent)) {
+    let mut names: Vec<String> = known.keys().cloned().collect();
+    for name in disk_names().unwrap_or_default() {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    for name in names {
+        update(known, &name, on_event);
+    }
 }
 
 fn get_mount_points(device_path: &str) -> Vec<String> {
@@ -103,6 +103,19 @@ async fn get_disk_info(disk_id: &str) -> Result<BlockDevice, PlatformError> {
         Vec::new()
     };
 
+    let transport = match dict.get("BusProtocol").and_then(|v| v.as_string()) {
+        Some("USB") => Some("usb"),
+        Some("Secure Digital") => Some("mmc"),
+        Some("SATA") | Some("ATA") => Some("ata"),
+        Some("PCI-Express") | Some("NVMe") => Some("nvme"),
+        _ => None,
+    };
+
+    let logical_sector_size = dict
+        .get("DeviceBlockSize")
+        .and_then(|v| v.as_unsigned_integer())
+        .map(|v| v as u32);
+
     Ok(BlockDevice {
         path: device_path,
         name,
@@ -110,6 +123,9 @@ async fn get_disk_info(disk_id: &str) -> Result<BlockDevice, PlatformError> {
         size_human: format_size(size),
         removable,
         mount_points,
+        transport: transport.map(|s| s.to_string()),
+        logical_sector_size,
+        ..Default::default()
     })
 }
 
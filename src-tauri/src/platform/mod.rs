@@ -19,8 +19,6 @@ pub enum PlatformError {
     Parse(String),
     #[error("Permission denied: {0}")]
     Permission(String),
-    #[error("Device not found: {0}")]
-    DeviceNotFound(String),
 }
 
 pub async fn list_removable_devices() -> Result<Vec<BlockDevice>, PlatformError> {
@@ -37,6 +35,32 @@ pub async fn list_removable_devices() -> Result<Vec<BlockDevice>, PlatformError>
     Err(PlatformError::Command("Unsupported platform".to_string()))
 }
 
+/// A removable drive coming, going or changing, as the device watcher sees it.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub enum DeviceEvent {
+    Added(BlockDevice),
+    /// Carries the device as it was last seen
+    Removed(BlockDevice),
+    Changed(BlockDevice),
+    /// The watcher failed and sends nothing more; carries the error
+    Stopped(String),
+}
+
+/// Calls `on_event` from a background thread whenever a removable drive is
+/// plugged in, pulled out or changes. Returns `false` where there is no way
+/// to watch, and the device list has to be polled instead; so does a
+/// `Stopped` event.
+pub fn watch_devices(on_event: Box<dyn Fn(DeviceEvent) + Send>) -> Result<bool, PlatformError> {
+    #[cfg(target_os = "linux")]
+    return linux::watch_devices(on_event);
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = on_event;
+        Ok(false)
+    }
+}
+
 pub async fn unmount_device(device_path: &str) -> Result<(), PlatformError> {
     #[cfg(target_os = "linux")]
     return linux::unmount_device(device_path).await;
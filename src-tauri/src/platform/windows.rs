@@ -1,5 +1,5 @@
 use super::PlatformError;
-use crate::commands::devices::{format_size, BlockDevice};
+use crate::commands::devices::{format_size, BlockDevice, DevicePartition};
 use std::process::Command;
 use tokio::fs::File;
 
@@ -13,19 +13,30 @@ pub async fn list_removable_devices() -> Result<Vec<BlockDevice>, PlatformError>
                 $disk = $_
                 $partitions = Get-Partition -DiskNumber $disk.Number -ErrorAction SilentlyContinue
                 $mountPoints = @()
+                $parts = @()
                 foreach ($p in $partitions) {
                     $vol = Get-Volume -Partition $p -ErrorAction SilentlyContinue
                     if ($vol.DriveLetter) {
                         $mountPoints += "$($vol.DriveLetter):"
                     }
+                    $parts += [PSCustomObject]@{
+                        Number = $p.PartitionNumber
+                        Size = $p.Size
+                        FileSystem = "$($vol.FileSystem)"
+                        Label = "$($vol.FileSystemLabel)"
+                    }
                 }
                 [PSCustomObject]@{
                     Number = $disk.Number
                     FriendlyName = $disk.FriendlyName
                     Size = $disk.Size
                     MountPoints = $mountPoints -join ","
+                    SerialNumber = "$($disk.SerialNumber)".Trim()
+                    LogicalSectorSize = $disk.LogicalSectorSize
+                    PhysicalSectorSize = $disk.PhysicalSectorSize
+                    Partitions = @($parts)
                 }
-            } | ConvertTo-Json -Compress"#,
+            } | ConvertTo-Json -Compress -Depth 4"#,
         ])
         .output()?;
 
@@ -68,6 +79,23 @@ pub async fn list_removable_devices() -> Result<Vec<BlockDevice>, PlatformError>
                     .collect()
             };
 
+            let partitions = disk
+                .partitions
+                .into_iter()
+                .map(|partition| DevicePartition {
+                    path: format!(
+                        r"\\?\GLOBALROOT\Device\Harddisk{}\Partition{}",
+                        disk.number, partition.number
+                    ),
+                    number: partition.number,
+                    size: partition.size,
+                    size_human: format_size(partition.size),
+                    fs_type: Some(partition.file_system).filter(|s| !s.is_empty()),
+                    label: Some(partition.label).filter(|s| !s.is_empty()),
+                    uuid: None,
+                })
+                .collect();
+
             BlockDevice {
                 path: format!(r"\\.\PhysicalDrive{}", disk.number),
                 name: disk.friendly_name,
@@ -75,6 +103,13 @@ pub async fn list_removable_devices() -> Result<Vec<BlockDevice>, PlatformError>
                 size_human: format_size(disk.size),
                 removable: true,
                 mount_points,
+                serial: Some(disk.serial_number).filter(|s| !s.is_empty()),
+                // Only USB disks are listed
+                transport: Some("usb".to_string()),
+                logical_sector_size: disk.logical_sector_size,
+                physical_sector_size: disk.physical_sector_size,
+                partitions,
+                ..Default::default()
             }
         })
         .collect();
@@ -89,13 +124,28 @@ struct DiskInfo {
     friendly_name: String,
     size: u64,
     mount_points: String,
+    #[serde(default)]
+    serial_number: String,
+    logical_sector_size: Option<u32>,
+    physical_sector_size: Option<u32>,
+    #[serde(default)]
+    partitions: Vec<PartitionInfo>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PartitionInfo {
+    number: u32,
+    size: u64,
+    file_system: String,
+    label: String,
 }
 
 pub async fn unmount_device(device_path: &str) -> Result<(), PlatformError> {
     let disk_num = parse_disk_number(device_path)?;
 
     // Offline the disk to release all volumes
-    let output = Command::new("powershell")
+    Command::new("powershell")
         .args([
             "-NoProfile",
             "-Command",
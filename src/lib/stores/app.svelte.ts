@@ -1,4 +1,4 @@
-import type { BlockDevice, FileInfo, ImageValidation, WriteProgress, Theme, AppMode, WritePhase } from "../types";
+import type { ArchiveEntry, BlockDevice, FileInfo, ImageValidation, WriteProgress, Theme, AppMode, WritePhase } from "../types";
 
 function loadTheme(): Theme {
   if (typeof localStorage === "undefined") return "system";
@@ -51,6 +51,8 @@ function applyTheme(theme: Theme) {
 class AppState {
   // File and device selection
   selectedFile = $state<FileInfo | null>(null);
+  archiveEntries = $state<ArchiveEntry[]>([]);
+  selectedEntry = $state<string | null>(null);
   selectedDevice = $state<BlockDevice | null>(null);
   devices = $state<BlockDevice[]>([]);
   devicesLoading = $state(false);
@@ -66,6 +68,9 @@ class AppState {
   writeProgress = $state<WriteProgress | null>(null);
   writeError = $state<string | null>(null);
 
+  // A check of an image or drive, run on its own outside a write
+  checking = $state(false);
+
   // Settings
   verifyAfterWrite = $state(loadVerifyDefault());
   theme = $state<Theme>(loadTheme());
@@ -88,8 +93,10 @@ class AppState {
 
   canWrite = $derived(
     this.selectedFile !== null &&
+    (this.archiveEntries.length <= 1 || this.selectedEntry !== null) &&
     this.selectedDevice !== null &&
-    this.writePhase === "idle"
+    this.writePhase === "idle" &&
+    !this.checking
   );
 
   constructor() {
@@ -100,10 +107,22 @@ class AppState {
   // Actions
   setFile(file: FileInfo | null) {
     this.selectedFile = file;
+    this.archiveEntries = [];
+    this.selectedEntry = null;
     this.calculatedChecksum = null;
     this.imageValidation = null;
   }
 
+  setArchiveEntries(entries: ArchiveEntry[]) {
+    this.archiveEntries = entries;
+    this.selectedEntry = entries.length === 1 ? entries[0].name : null;
+  }
+
+  setSelectedEntry(entry: string | null) {
+    this.selectedEntry = entry;
+    this.imageValidation = null;
+  }
+
   setDevice(device: BlockDevice | null) {
     this.selectedDevice = device;
   }
@@ -197,6 +216,18 @@ class AppState {
     this.writeProgress = null;
     this.writeError = null;
   }
+
+  // Runs a check command, whose progress arrives on "write-progress" too
+  async runCheck<T>(check: () => Promise<T>): Promise<T> {
+    this.checking = true;
+    this.writeProgress = null;
+    try {
+      return await check();
+    } finally {
+      this.checking = false;
+      this.writeProgress = null;
+    }
+  }
 }
 
 export const appState = new AppState();
@@ -5,6 +5,24 @@ export interface BlockDevice {
   size_human: string;
   removable: boolean;
   mount_points: string[];
+  serial: string | null;
+  vendor_id: string | null;
+  product_id: string | null;
+  transport: string | null;
+  by_id_path: string | null;
+  logical_sector_size: number | null;
+  physical_sector_size: number | null;
+  partitions: DevicePartition[];
+}
+
+export interface DevicePartition {
+  path: string;
+  number: number;
+  size: number;
+  size_human: string;
+  fs_type: string | null;
+  label: string | null;
+  uuid: string | null;
 }
 
 export interface FileInfo {
@@ -12,17 +30,157 @@ export interface FileInfo {
   name: string;
   size: number;
   size_human: string;
+  parts: number;
+  image_size: number | null;
+  image_size_human: string | null;
+  image_size_exact: boolean;
+}
+
+export interface ArchiveEntry {
+  name: string;
+  size: number;
+  size_human: string;
+}
+
+export interface ImageFile {
+  name: string;
+  path: string;
+  is_dir: boolean;
+  size: number;
+  size_human: string;
+}
+
+export interface IsoInfo {
+  volume_label: string;
+  system_id: string | null;
+  publisher: string | null;
+  application_id: string | null;
+  creation_date: string | null;
+  volume_space_size: number;
+  logical_block_size: number;
+  joliet: boolean;
+  rock_ridge: boolean;
+  boot_entries: BootEntry[];
+  hybrid_mbr: boolean;
+  hybrid_gpt: boolean;
+  implanted_md5: ImplantedMd5 | null;
+}
+
+export interface ImplantedMd5 {
+  md5: string;
+  skip_sectors: number;
+  fragment_sums: string | null;
+  fragment_count: number;
+  length: number;
+}
+
+export interface SumsCheck {
+  manifest: string;
+  algorithm: string;
+  files_checked: number;
+  missing: string[];
+  corrupt: string[];
+  passed: boolean;
+}
+
+export interface Md5Check {
+  expected: string;
+  actual: string | null;
+  passed: boolean;
+  failed_fragment: number | null;
+  bytes_checked: number;
+}
+
+export interface UdfInfo {
+  revision: string;
+  volume_label: string | null;
+}
+
+export interface BootEntry {
+  platform: string;
+  bootable: boolean;
+  emulation: string;
+  load_rba: number;
+  sector_count: number;
+}
+
+export interface PartitionTable {
+  scheme: "GPT" | "MBR";
+  sector_size: number;
+  disk_guid: string | null;
+  mbr: "protective" | "hybrid" | "none" | null;
+  partitions: Partition[];
+  required_size: number;
+  errors: string[];
+  warnings: string[];
+}
+
+export interface Partition {
+  index: number;
+  type_id: string;
+  type_name: string | null;
+  start: number;
+  size: number;
+  name: string | null;
+  attributes: number;
+  guid: string | null;
+  filesystem: FsInfo | null;
+}
+
+export interface FsInfo {
+  fs_type: string;
+  label: string | null;
+  uuid: string | null;
+  size: number | null;
+}
+
+export interface OsInfo {
+  name: string;
+  version: string | null;
+  architecture: string | null;
+  source: string;
+  live: string | null;
+}
+
+export interface EfiBootloader {
+  path: string;
+  architecture: string | null;
+  signed: boolean;
+  signer: string | null;
+  issuer: string | null;
+  sbat: boolean;
+  shim: boolean;
+}
+
+export interface Platforms {
+  architectures: string[];
+  bios: boolean;
+  raspberry_pi: string[];
 }
 
 export interface ImageValidation {
   is_valid: boolean;
   format: string;
+  compression: string | null;
+  entry: string | null;
+  disk_format: string | null;
+  image_size: number | null;
+  image_size_exact: boolean;
+  bmap: string | null;
+  mapped_size: number | null;
+  mapped_size_human: string | null;
+  iso: IsoInfo | null;
+  udf: UdfInfo | null;
+  partitions: PartitionTable | null;
+  os: OsInfo | null;
+  bootloaders: EfiBootloader[];
+  platforms: Platforms;
   errors: string[];
   warnings: string[];
 }
 
 export interface WriteProgress {
-  phase: "writing" | "verifying";
+  phase: "writing" | "verifying" | "checking-md5" | "checking-files";
   bytes_written: number;
   total_bytes: number;
   speed_bps: number;